@@ -0,0 +1,463 @@
+//! Functional interface of the automatic differentiation. Unlike
+//! [`Tensor::backward`](crate::backend::tensor::Tensor::backward), these
+//! functions don't modify the gradients of the tensors of the graph: the
+//! derivatives are returned as new arrays. The graph is not freed either, so
+//! the inputs that are not leaves can still be backpropagated afterwards.
+
+use crate::backend::float::Float;
+use crate::backend::grad_mode::enable_grad;
+use crate::backend::ops::{add, constant, mul, shape_of, sum};
+use crate::backend::tensor::{BackwardOptions, RTensor, Tensor};
+use crate::error::{invalid_argument, unwrap_or_panic, Result, RustyGradError};
+use ndarray::prelude::*;
+
+/// Returns an error if any of the `inputs` doesn't require grad
+fn check_inputs<T: Float>(op: &'static str, inputs: &[RTensor<T>]) -> Result<()> {
+    match inputs.iter().position(|t| !t.borrow().requires_grad) {
+        Some(i) => invalid_argument(op, format!("input {} doesn't require grad", i)),
+        None => Ok(()),
+    }
+}
+
+/// Evaluates `f` recording its graph, even inside a `no_grad` scope
+fn eval<T: Float>(f: impl Fn(&[RTensor<T>]) -> RTensor<T>, inputs: &[RTensor<T>]) -> RTensor<T> {
+    let _guard = enable_grad();
+    f(inputs)
+}
+
+/// Returns an error if `out` is not a scalar
+fn check_scalar<T: Float>(out: &RTensor<T>) -> Result<()> {
+    if out.borrow().data.borrow().len() != 1 {
+        return Err(RustyGradError::NonScalarBackward {
+            shape: shape_of(out),
+        });
+    }
+    Ok(())
+}
+
+/// Backpropagates `seed`, the gradient of `out`, and returns the gradients of
+/// `inputs`. The inputs that are not reached get a zero gradient.
+fn grads_of<T: Float>(
+    out: &RTensor<T>,
+    seed: RTensor<T>,
+    inputs: &[RTensor<T>],
+    options: BackwardOptions,
+) -> Result<Vec<RTensor<T>>> {
+    // The backpropagation doesn't stop at the inputs, so freeing the graph
+    // would drop the history of the inputs that are not leaves and of the
+    // tensors captured by `f`
+    let options = BackwardOptions {
+        retain_graph: true,
+        ..options
+    };
+    let grads = if out.borrow().requires_grad {
+        Tensor::try_grad_of(out, seed, inputs, options)?
+    } else {
+        // `out` doesn't depend on any tensor that requires grad
+        vec![None; inputs.len()]
+    };
+    Ok(grads
+        .into_iter()
+        .zip(inputs)
+        .map(|(g, t)| {
+            g.unwrap_or_else(|| constant(ArrayD::zeros(t.borrow().data.borrow().raw_dim())))
+        })
+        .collect())
+}
+
+/// Returns the jacobians of `out` with respect to each of the `inputs`, with
+/// shape `out.shape ++ input.shape`. The graph of `out` is backpropagated once
+/// per element of `out`.
+fn jacobian_of<T: Float>(out: &RTensor<T>, inputs: &[RTensor<T>]) -> Result<Vec<ArrayD<T>>> {
+    let out_shape = shape_of(out);
+    let n = out.borrow().data.borrow().len();
+    let mut jacobians: Vec<Array2<T>> = inputs
+        .iter()
+        .map(|t| Array2::zeros((n, t.borrow().data.borrow().len())))
+        .collect();
+    for k in 0..n {
+        let mut seed = Array1::zeros(n);
+        seed[k] = T::one();
+        let seed = seed.into_shape(IxDyn(&out_shape)).unwrap();
+        let grads = grads_of(out, constant(seed), inputs, Default::default())?;
+        for (jacobian, g) in jacobians.iter_mut().zip(grads) {
+            jacobian
+                .row_mut(k)
+                .assign(&Array::from_iter(g.borrow().data.borrow().iter().cloned()));
+        }
+    }
+    Ok(jacobians
+        .into_iter()
+        .zip(inputs)
+        .map(|(jacobian, t)| {
+            let shape = [out_shape.as_slice(), t.borrow().data.borrow().shape()].concat();
+            jacobian.into_shape(IxDyn(&shape)).unwrap()
+        })
+        .collect())
+}
+
+/// Fallible version of [`grad`], returns an error if an input doesn't require
+/// grad or if the output of `f` is not a scalar
+pub fn try_grad<T: Float>(
+    f: impl Fn(&[RTensor<T>]) -> RTensor<T>,
+    inputs: &[RTensor<T>],
+) -> Result<Vec<ArrayD<T>>> {
+    check_inputs("Grad", inputs)?;
+    let out = eval(f, inputs);
+    check_scalar(&out)?;
+    let seed = constant(ArrayD::ones(out.borrow().data.borrow().raw_dim()));
+    let grads = grads_of(&out, seed, inputs, BackwardOptions::default())?;
+    Ok(grads
+        .iter()
+        .map(|g| g.borrow().data.borrow().clone())
+        .collect())
+}
+
+/// Returns the gradients of the scalar output of `f` with respect to each of
+/// the `inputs`. The gradients of the inputs are not modified.
+///
+/// ```
+/// use ndarray::prelude::*;
+/// use rusty_grad::backend::{autograd::grad, ops::*, tensor::Tensor};
+///
+/// let x = Tensor::new_ref(&array![1., 2., 3.].into_dyn());
+/// let grads = grad(|x| sum(&mul(&x[0], &x[0]), None, false), &[x.clone()]);
+/// assert_eq!(grads[0], array![2., 4., 6.].into_dyn());
+/// assert_eq!(x.borrow().grad, Array::zeros(3).into_dyn());
+/// ```
+pub fn grad<T: Float>(
+    f: impl Fn(&[RTensor<T>]) -> RTensor<T>,
+    inputs: &[RTensor<T>],
+) -> Vec<ArrayD<T>> {
+    unwrap_or_panic(try_grad(f, inputs))
+}
+
+/// Fallible version of [`vjp`], returns an error if an input doesn't require
+/// grad or if the shape of `v` is not the shape of the output of `f`
+pub fn try_vjp<T: Float>(
+    f: impl Fn(&[RTensor<T>]) -> RTensor<T>,
+    inputs: &[RTensor<T>],
+    v: &ArrayD<T>,
+) -> Result<(ArrayD<T>, Vec<ArrayD<T>>)> {
+    check_inputs("Vjp", inputs)?;
+    let out = eval(f, inputs);
+    if out.borrow().data.borrow().shape() != v.shape() {
+        return Err(RustyGradError::ShapeMismatch {
+            op: "Vjp",
+            lhs: shape_of(&out),
+            rhs: v.shape().to_vec(),
+        });
+    }
+    let grads = grads_of(
+        &out,
+        constant(v.clone()),
+        inputs,
+        BackwardOptions::default(),
+    )?;
+    let out = out.borrow().data.borrow().clone();
+    Ok((
+        out,
+        grads
+            .iter()
+            .map(|g| g.borrow().data.borrow().clone())
+            .collect(),
+    ))
+}
+
+/// Computes the vector-Jacobian product `vᵀ·J` of `f` at `inputs`. Returns
+/// the output of `f` and the product for each of the inputs, i.e. the
+/// gradients of the inputs when `v` is backpropagated from the output.
+pub fn vjp<T: Float>(
+    f: impl Fn(&[RTensor<T>]) -> RTensor<T>,
+    inputs: &[RTensor<T>],
+    v: &ArrayD<T>,
+) -> (ArrayD<T>, Vec<ArrayD<T>>) {
+    unwrap_or_panic(try_vjp(f, inputs, v))
+}
+
+/// Fallible version of [`jvp`], returns an error if an input doesn't require
+/// grad or if `v` doesn't have one array with the shape of each input
+pub fn try_jvp<T: Float>(
+    f: impl Fn(&[RTensor<T>]) -> RTensor<T>,
+    inputs: &[RTensor<T>],
+    v: &[ArrayD<T>],
+) -> Result<(ArrayD<T>, ArrayD<T>)> {
+    check_inputs("Jvp", inputs)?;
+    if v.len() != inputs.len() {
+        return invalid_argument(
+            "Jvp",
+            format!("expected {} tangents, got {}", inputs.len(), v.len()),
+        );
+    }
+    for (t, v) in inputs.iter().zip(v) {
+        if t.borrow().data.borrow().shape() != v.shape() {
+            return Err(RustyGradError::ShapeMismatch {
+                op: "Jvp",
+                lhs: shape_of(t),
+                rhs: v.shape().to_vec(),
+            });
+        }
+    }
+    let out = eval(f, inputs);
+    // The gradients of the inputs for a seed `u` are `Jᵀ·u`, which is linear
+    // in `u`. Backpropagating `v` through them gives `J·v`, whatever `u` is.
+    let u = Tensor::new_ref(&ArrayD::zeros(out.borrow().data.borrow().raw_dim()));
+    let options = BackwardOptions {
+        create_graph: true,
+        ..Default::default()
+    };
+    let grads = grads_of(&out, u.clone(), inputs, options)?;
+    let dot = {
+        let _guard = enable_grad();
+        grads
+            .iter()
+            .zip(v)
+            .map(|(g, v)| sum(&mul(g, &constant(v.clone())), None, false))
+            .reduce(|acc, s| add(&acc, &s))
+    };
+    let product = match dot {
+        Some(dot) => grads_of(
+            &dot,
+            constant(arr0(T::one()).into_dyn()),
+            &[u],
+            Default::default(),
+        )?
+        .remove(0),
+        None => constant(ArrayD::zeros(out.borrow().data.borrow().raw_dim())),
+    };
+    let out = out.borrow().data.borrow().clone();
+    let product = product.borrow().data.borrow().clone();
+    Ok((out, product))
+}
+
+/// Computes the Jacobian-vector product `J·v` of `f` at `inputs`, where `v`
+/// has one tangent array per input. Returns the output of `f` and the product,
+/// i.e. the directional derivative of the output along `v`.
+pub fn jvp<T: Float>(
+    f: impl Fn(&[RTensor<T>]) -> RTensor<T>,
+    inputs: &[RTensor<T>],
+    v: &[ArrayD<T>],
+) -> (ArrayD<T>, ArrayD<T>) {
+    unwrap_or_panic(try_jvp(f, inputs, v))
+}
+
+/// Fallible version of [`jacobian`], returns an error if an input doesn't
+/// require grad
+pub fn try_jacobian<T: Float>(
+    f: impl Fn(&[RTensor<T>]) -> RTensor<T>,
+    inputs: &[RTensor<T>],
+) -> Result<Vec<ArrayD<T>>> {
+    check_inputs("Jacobian", inputs)?;
+    let out = eval(f, inputs);
+    jacobian_of(&out, inputs)
+}
+
+/// Returns the jacobian of the output of `f` with respect to each of the
+/// `inputs`. The jacobian of an input has the shape of the output followed by
+/// the shape of the input.
+pub fn jacobian<T: Float>(
+    f: impl Fn(&[RTensor<T>]) -> RTensor<T>,
+    inputs: &[RTensor<T>],
+) -> Vec<ArrayD<T>> {
+    unwrap_or_panic(try_jacobian(f, inputs))
+}
+
+/// Fallible version of [`hessian`], returns an error if an input doesn't
+/// require grad or if the output of `f` is not a scalar
+pub fn try_hessian<T: Float>(
+    f: impl Fn(&[RTensor<T>]) -> RTensor<T>,
+    inputs: &[RTensor<T>],
+) -> Result<Vec<Vec<ArrayD<T>>>> {
+    check_inputs("Hessian", inputs)?;
+    let out = eval(f, inputs);
+    check_scalar(&out)?;
+    let seed = constant(ArrayD::ones(out.borrow().data.borrow().raw_dim()));
+    let options = BackwardOptions {
+        create_graph: true,
+        ..Default::default()
+    };
+    let grads = grads_of(&out, seed, inputs, options)?;
+    grads.iter().map(|g| jacobian_of(g, inputs)).collect()
+}
+
+/// Returns the second derivatives of the scalar output of `f`. The block
+/// `[i][j]` holds the derivatives with respect to the inputs `i` and `j`, with
+/// the shape of the input `i` followed by the shape of the input `j`.
+pub fn hessian<T: Float>(
+    f: impl Fn(&[RTensor<T>]) -> RTensor<T>,
+    inputs: &[RTensor<T>],
+) -> Vec<Vec<ArrayD<T>>> {
+    unwrap_or_panic(try_hessian(f, inputs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::grad_mode::no_grad;
+    use crate::backend::ops::*;
+    use crate::nn::activations::tanh;
+
+    fn assert_close(a: &ArrayD<f32>, b: &ArrayD<f32>) {
+        assert_eq!(a.shape(), b.shape());
+        assert!(
+            a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-5),
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn grad_ok() {
+        let x = Tensor::new_ref(&array![1., 2.].into_dyn());
+        let y = Tensor::new_ref(&array![3., 4.].into_dyn());
+        let grads = grad(
+            |x| sum(&mul(&x[0], &exp(&x[1])), None, false),
+            &[x.clone(), y.clone()],
+        );
+        assert_close(&grads[0], &y.borrow().data.borrow().mapv(f32::exp));
+        assert_close(
+            &grads[1],
+            &(&*x.borrow().data.borrow() * &y.borrow().data.borrow().mapv(f32::exp)),
+        );
+        // The leaves are not modified
+        assert_eq!(x.borrow().grad, Array::zeros(2).into_dyn());
+        assert_eq!(y.borrow().grad, Array::zeros(2).into_dyn());
+    }
+
+    #[test]
+    fn grad_keeps_graph_of_inputs() {
+        let a = Tensor::new_ref(&array![2., 3.].into_dyn());
+        let h = mul(&a, &a);
+        let grads = grad(
+            |i| sum(&mul(&i[0], &i[0]), None, false),
+            std::slice::from_ref(&h),
+        );
+        assert_close(&grads[0], &array![8., 18.].into_dyn());
+        // The history of the intermediate input is kept, so it can still be
+        // backpropagated: d(sum(a^3))/da = 3 * a^2
+        assert_eq!(h.borrow().prev.len(), 2);
+        let out = sum(&mul(&h, &a), None, false);
+        out.borrow_mut().try_backward().unwrap();
+        assert_close(&a.borrow().grad, &array![12., 27.].into_dyn());
+    }
+
+    #[test]
+    fn grad_unused_input_ok() {
+        let x = Tensor::new_ref(&array![1., 2.].into_dyn());
+        let y = Tensor::new_ref(&array![[3.]].into_dyn());
+        let grads = grad(|x| sum(&x[0], None, false), &[x, y]);
+        assert_eq!(grads[0], Array::ones(2).into_dyn());
+        assert_eq!(grads[1], Array::zeros((1, 1)).into_dyn());
+    }
+
+    #[test]
+    fn grad_no_grad_ok() {
+        let _guard = no_grad();
+        let x = Tensor::new_ref(&array![2.].into_dyn());
+        let grads = grad(|x| sum(&pow(&x[0], 3.0), None, false), &[x]);
+        assert_close(&grads[0], &array![12.].into_dyn());
+    }
+
+    #[test]
+    fn try_grad_err() {
+        let x = Tensor::new_ref(&array![1., 2.].into_dyn());
+        assert_eq!(
+            try_grad(|x| exp(&x[0]), std::slice::from_ref(&x)),
+            Err(RustyGradError::NonScalarBackward { shape: vec![2] })
+        );
+        let c = Tensor::new(&array![1.].into_dyn())
+            .with_requires_grad(false)
+            .to_ref();
+        assert!(matches!(
+            try_grad(|x| sum(&x[0], None, false), &[x, c]),
+            Err(RustyGradError::InvalidArgument { op: "Grad", .. })
+        ));
+    }
+
+    #[test]
+    fn vjp_ok() {
+        let a = Tensor::new_ref(&array![[1., 2.], [3., 4.]].into_dyn());
+        let x = Tensor::new_ref(&array![[1.], [-1.]].into_dyn());
+        let v = array![[1.], [2.]].into_dyn();
+        let (out, grads) = vjp(|x| matmul(&x[0], &x[1]), &[a.clone(), x.clone()], &v);
+        assert_eq!(out, array![[-1.], [-1.]].into_dyn());
+        // d(vᵀ·A·x)/dA = v·xᵀ and d(vᵀ·A·x)/dx = Aᵀ·v
+        assert_eq!(grads[0], array![[1., -1.], [2., -2.]].into_dyn());
+        assert_eq!(grads[1], array![[7.], [10.]].into_dyn());
+        assert_eq!(
+            try_vjp(
+                |x| matmul(&x[0], &x[1]),
+                &[a, x],
+                &Array::ones(2).into_dyn()
+            ),
+            Err(RustyGradError::ShapeMismatch {
+                op: "Vjp",
+                lhs: vec![2, 1],
+                rhs: vec![2]
+            })
+        );
+    }
+
+    #[test]
+    fn jvp_ok() {
+        let x = Tensor::new_ref(&array![1., 2., 3.].into_dyn());
+        let y = Tensor::new_ref(&array![0.5].into_dyn());
+        let f = |x: &[RTensor]| mul(&sin(&x[0]), &x[1]);
+        let v = [array![1., 0., -1.].into_dyn(), array![2.].into_dyn()];
+        let (out, product) = jvp(f, &[x.clone(), y.clone()], &v);
+        let x_data = x.borrow().data.borrow().clone();
+        assert_close(&out, &x_data.mapv(|x| 0.5 * x.sin()));
+        // J·v = cos(x)·y·v_x + sin(x)·v_y
+        let expected = &x_data.mapv(|x| 0.5 * x.cos()) * &v[0] + &x_data.mapv(|x| 2. * x.sin());
+        assert_close(&product, &expected);
+        assert_eq!(x.borrow().grad, Array::zeros(3).into_dyn());
+        assert!(matches!(
+            try_jvp(f, &[x, y], &v[..1]),
+            Err(RustyGradError::InvalidArgument { op: "Jvp", .. })
+        ));
+    }
+
+    #[test]
+    fn jacobian_ok() {
+        let a = Tensor::new_ref(&array![[1., 2., 3.], [4., 5., 6.]].into_dyn());
+        let x = Tensor::new_ref(&array![1., 1., 1.].into_dyn());
+        let jacobians = jacobian(
+            |x| squeeze(&matmul(&x[0], &unsqueeze(&x[1], 1)), None),
+            &[a.clone(), x],
+        );
+        // The jacobian of a linear map with respect to its input is the matrix
+        assert_eq!(a.borrow().data, jacobians[1]);
+        assert_eq!(jacobians[0].shape(), &[2, 2, 3]);
+        assert_eq!(
+            jacobians[0],
+            array![[[1., 1., 1.], [0., 0., 0.]], [[0., 0., 0.], [1., 1., 1.]]].into_dyn()
+        );
+    }
+
+    #[test]
+    fn jacobian_matches_vjp() {
+        let x = Tensor::new_ref(&array![[0.1, -0.2], [0.3, 0.4]].into_dyn());
+        let f = |x: &[RTensor]| tanh(sum(&x[0], Some(&[1]), false));
+        let jacobians = jacobian(f, std::slice::from_ref(&x));
+        let (_, grads) = vjp(f, &[x], &array![0., 1.].into_dyn());
+        assert_close(&jacobians[0].index_axis(Axis(0), 1).to_owned(), &grads[0]);
+    }
+
+    #[test]
+    fn hessian_ok() {
+        let x = Tensor::new_ref(&array![1., 2.].into_dyn());
+        let y = Tensor::new_ref(&array![3.].into_dyn());
+        // f = sum(x^2)·y
+        let hessians = hessian(
+            |x| mul(&sum(&pow(&x[0], 2.0), None, false), &x[1]),
+            &[x.clone(), y],
+        );
+        assert_close(&hessians[0][0], &array![[6., 0.], [0., 6.]].into_dyn());
+        assert_close(&hessians[0][1], &array![[2.], [4.]].into_dyn());
+        assert_close(&hessians[1][0], &array![[2., 4.]].into_dyn());
+        assert_close(&hessians[1][1], &array![[0.]].into_dyn());
+        assert_eq!(x.borrow().grad, Array::zeros(2).into_dyn());
+        assert!(x.borrow().grad_graph.is_none());
+    }
+}
@@ -0,0 +1,308 @@
+//! Constructors of leaf tensors. Every constructor takes `requires_grad`, and
+//! the random ones take an optional generator, so that they are reproducible
+//! with a seeded one (e.g. `StdRng::seed_from_u64`). Without a generator they
+//! sample from the generator of the library, see
+//! [`manual_seed`](crate::backend::random::manual_seed).
+
+use crate::backend::dtype::IntTensor;
+use crate::backend::float::Float;
+use crate::backend::random::with_rng;
+use crate::backend::tensor::{RTensor, Tensor};
+use crate::error::{invalid_argument, unwrap_or_panic, Result};
+use ndarray::prelude::*;
+use rand::{distributions::Uniform, prelude::Distribution, Rng, RngCore};
+
+/// Creates a leaf tensor from `data`
+fn leaf<T: Float>(data: ArrayD<T>, requires_grad: bool) -> RTensor<T> {
+    Tensor::new(&data)
+        .with_requires_grad(requires_grad)
+        .to_ref()
+}
+
+/// Creates an array with the given `shape` with the elements sampled by `f`
+/// from `rng`, or from the generator of the library if it is `None`
+pub(crate) fn sample<A>(
+    shape: &[usize],
+    rng: Option<&mut dyn RngCore>,
+    mut f: impl FnMut(&mut dyn RngCore) -> A,
+) -> ArrayD<A> {
+    match rng {
+        Some(rng) => Array::from_shape_simple_fn(IxDyn(shape), || f(&mut *rng)),
+        None => with_rng(|rng| Array::from_shape_simple_fn(IxDyn(shape), || f(&mut *rng))),
+    }
+}
+
+/// Creates a tensor of zeros with the given `shape`
+pub fn zeros<T: Float>(shape: &[usize], requires_grad: bool) -> RTensor<T> {
+    leaf(Array::zeros(IxDyn(shape)), requires_grad)
+}
+
+/// Creates a tensor of ones with the given `shape`
+pub fn ones<T: Float>(shape: &[usize], requires_grad: bool) -> RTensor<T> {
+    leaf(Array::ones(IxDyn(shape)), requires_grad)
+}
+
+/// Creates a tensor with the given `shape` filled with `value`
+pub fn full<T: Float>(shape: &[usize], value: T, requires_grad: bool) -> RTensor<T> {
+    leaf(Array::from_elem(IxDyn(shape), value), requires_grad)
+}
+
+/// Creates a tensor of zeros with the shape of `t`
+pub fn zeros_like<T: Float>(t: &RTensor<T>, requires_grad: bool) -> RTensor<T> {
+    leaf(
+        Array::zeros(t.borrow().data.borrow().raw_dim()),
+        requires_grad,
+    )
+}
+
+/// Fallible version of [`arange`], returns an error if `start`, `end` or
+/// `step` is not finite, `step` is 0 or it goes away from `end`
+pub fn try_arange<T: Float>(start: T, end: T, step: T, requires_grad: bool) -> Result<RTensor<T>> {
+    if !start.is_finite() || !end.is_finite() || !step.is_finite() {
+        return invalid_argument(
+            "Arange",
+            format!(
+                "the range from {} to {} with step {} is not finite",
+                start, end, step
+            ),
+        );
+    }
+    if step.is_zero() {
+        return invalid_argument("Arange", "the step can't be 0".to_string());
+    }
+    let len = ((end - start) / step).ceil();
+    if len < T::zero() {
+        return invalid_argument(
+            "Arange",
+            format!("the step {} doesn't go from {} to {}", step, start, end),
+        );
+    }
+    // The length is not finite or doesn't fit in a `usize` when the range is
+    // too large for the step
+    let Some(len) = len.to_usize().filter(|_| len.is_finite()) else {
+        return invalid_argument(
+            "Arange",
+            format!(
+                "the range from {} to {} has too many steps of {}",
+                start, end, step
+            ),
+        );
+    };
+    let data = (0..len)
+        .map(|i| start + T::from_usize(i).unwrap() * step)
+        .collect::<Array1<T>>();
+    Ok(leaf(data.into_dyn(), requires_grad))
+}
+
+/// Creates a 1-D tensor with the values from `start` (included) to `end`
+/// (excluded) spaced by `step`
+pub fn arange<T: Float>(start: T, end: T, step: T, requires_grad: bool) -> RTensor<T> {
+    unwrap_or_panic(try_arange(start, end, step, requires_grad))
+}
+
+/// Creates a 1-D tensor with `steps` values evenly spaced from `start` to `end`
+/// (both included)
+pub fn linspace<T: Float>(start: T, end: T, steps: usize, requires_grad: bool) -> RTensor<T> {
+    leaf(Array::linspace(start, end, steps).into_dyn(), requires_grad)
+}
+
+/// Creates the `n x n` identity matrix
+pub fn eye<T: Float>(n: usize, requires_grad: bool) -> RTensor<T> {
+    leaf(Array::eye(n).into_dyn(), requires_grad)
+}
+
+/// Fallible version of [`uniform`], returns an error if the bounds or their
+/// difference are not finite or `low` is not lower than `high`
+pub fn try_uniform<T: Float>(
+    shape: &[usize],
+    low: T,
+    high: T,
+    rng: Option<&mut dyn RngCore>,
+    requires_grad: bool,
+) -> Result<RTensor<T>> {
+    if !low.is_finite() || !high.is_finite() || !(high - low).is_finite() || low >= high {
+        return invalid_argument(
+            "Uniform",
+            format!("the range from {} to {} is not valid", low, high),
+        );
+    }
+    let uniform = Uniform::new(low, high);
+    let data = sample(shape, rng, |rng| uniform.sample(rng));
+    Ok(leaf(data, requires_grad))
+}
+
+/// Creates a tensor with the given `shape` sampled from the uniform
+/// distribution over `[low, high)`
+pub fn uniform<T: Float>(
+    shape: &[usize],
+    low: T,
+    high: T,
+    rng: Option<&mut dyn RngCore>,
+    requires_grad: bool,
+) -> RTensor<T> {
+    unwrap_or_panic(try_uniform(shape, low, high, rng, requires_grad))
+}
+
+/// Creates a tensor with the given `shape` sampled from the uniform
+/// distribution over `[0, 1)`
+pub fn rand<T: Float>(
+    shape: &[usize],
+    rng: Option<&mut dyn RngCore>,
+    requires_grad: bool,
+) -> RTensor<T> {
+    uniform(shape, T::zero(), T::one(), rng, requires_grad)
+}
+
+/// Creates a tensor with the given `shape` sampled from the standard normal
+/// distribution
+pub fn randn<T: Float>(
+    shape: &[usize],
+    rng: Option<&mut dyn RngCore>,
+    requires_grad: bool,
+) -> RTensor<T> {
+    let data = sample(shape, rng, |rng| T::cast(standard_normal(rng)));
+    leaf(data, requires_grad)
+}
+
+/// Samples a value from the standard normal distribution
+pub(crate) fn standard_normal(rng: &mut dyn RngCore) -> f64 {
+    // Box-Muller transform, `u1` is in (0, 1] so that its log is finite
+    let u1 = 1. - rng.gen::<f64>();
+    let u2 = rng.gen::<f64>();
+    (-2. * u1.ln()).sqrt() * (2. * std::f64::consts::PI * u2).cos()
+}
+
+/// Fallible version of [`randint`], returns an error if `low` is not lower
+/// than `high`
+pub fn try_randint(
+    shape: &[usize],
+    low: i64,
+    high: i64,
+    rng: Option<&mut dyn RngCore>,
+) -> Result<IntTensor> {
+    if low >= high {
+        return invalid_argument(
+            "Randint",
+            format!("low {} must be lower than high {}", low, high),
+        );
+    }
+    Ok(sample(shape, rng, |rng| rng.gen_range(low..high)))
+}
+
+/// Creates an integer tensor with the given `shape` sampled uniformly from
+/// `low` (included) to `high` (excluded), e.g. random class labels. Integer
+/// tensors are not part of the graph, so it doesn't take `requires_grad`.
+pub fn randint(shape: &[usize], low: i64, high: i64, rng: Option<&mut dyn RngCore>) -> IntTensor {
+    unwrap_or_panic(try_randint(shape, low, high, rng))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RustyGradError;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn constant_ctors_ok() {
+        let t = zeros::<f32>(&[2, 3], true);
+        assert_eq!(t.borrow().data, Array::zeros(IxDyn(&[2, 3])));
+        assert!(t.borrow().requires_grad);
+        let t = ones::<f64>(&[2], false);
+        assert_eq!(t.borrow().data, array![1., 1.].into_dyn());
+        assert!(!t.borrow().requires_grad);
+        let t = full(&[1, 2], 3f32, true);
+        assert_eq!(t.borrow().data, array![[3., 3.]].into_dyn());
+        assert_eq!(
+            zeros_like(&t, false).borrow().data,
+            array![[0., 0.]].into_dyn()
+        );
+        assert_eq!(
+            eye::<f32>(2, false).borrow().data,
+            array![[1., 0.], [0., 1.]].into_dyn()
+        );
+    }
+
+    #[test]
+    fn ranges_ok() {
+        let t = arange(0f32, 1., 0.25, false);
+        assert_eq!(t.borrow().data, array![0., 0.25, 0.5, 0.75].into_dyn());
+        let t = arange(3f32, 0., -1.5, false);
+        assert_eq!(t.borrow().data, array![3., 1.5].into_dyn());
+        let t = linspace(-1f32, 1., 5, true);
+        assert_eq!(t.borrow().data, array![-1., -0.5, 0., 0.5, 1.].into_dyn());
+    }
+
+    #[test]
+    fn arange_err() {
+        assert!(matches!(
+            try_arange(0f32, 1., 0., false),
+            Err(RustyGradError::InvalidArgument { op: "Arange", .. })
+        ));
+        assert!(try_arange(0f32, 1., -1., false).is_err());
+        // Non-finite arguments or lengths are errors instead of panics
+        for (start, end, step) in [
+            (f32::NAN, 1., 1.),
+            (0., f32::INFINITY, 1.),
+            (0., 1., f32::NEG_INFINITY),
+            (-f32::MAX, f32::MAX, 1.),
+            (0., 1., 1e-40),
+        ] {
+            assert!(matches!(
+                try_arange(start, end, step, false),
+                Err(RustyGradError::InvalidArgument { op: "Arange", .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn random_ctors_seeded_ok() {
+        let mut rng1 = StdRng::seed_from_u64(0);
+        let mut rng2 = StdRng::seed_from_u64(0);
+        let t1 = rand::<f32>(&[3, 4], Some(&mut rng1), true);
+        let t2 = rand::<f32>(&[3, 4], Some(&mut rng2), true);
+        assert_eq!(t1.borrow().data, t2.borrow().data);
+        assert!(t1
+            .borrow()
+            .data
+            .borrow()
+            .iter()
+            .all(|&x| (0. ..1.).contains(&x)));
+
+        let t = uniform(&[100], -2f64, -1., Some(&mut rng1), false);
+        assert!(t
+            .borrow()
+            .data
+            .borrow()
+            .iter()
+            .all(|&x| (-2. ..-1.).contains(&x)));
+        assert!(try_uniform(&[1], 1f32, 1., None, false).is_err());
+        assert!(try_uniform(&[1], 0f32, f32::INFINITY, None, false).is_err());
+        assert!(try_uniform(&[1], -f32::MAX, f32::MAX, None, false).is_err());
+
+        let labels = randint(&[50], -1, 2, Some(&mut rng1));
+        assert!(labels.iter().all(|x| (-1..2).contains(x)));
+        assert!(labels.iter().any(|&x| x == -1) && labels.iter().any(|&x| x == 1));
+        assert!(try_randint(&[1], 2, 2, None).is_err());
+    }
+
+    #[test]
+    fn randn_ok() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let t = randn::<f64>(&[10_000], Some(&mut rng), false);
+        let t = t.borrow();
+        let data = t.data.borrow();
+        let mean = data.mean().unwrap();
+        let std = data.std(0.);
+        assert!(mean.abs() < 0.05, "mean {}", mean);
+        assert!((std - 1.).abs() < 0.05, "std {}", std);
+        // Without a generator they use the generator of the library
+        assert_eq!(
+            randn::<f32>(&[2, 2], None, true)
+                .borrow()
+                .data
+                .borrow()
+                .shape(),
+            &[2, 2]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Non-differentiable integer and boolean tensors, for classification labels,
+//! indices and masks. They are plain `ndarray` arrays since they are never part
+//! of the graph: the comparison ops in [`ops`](crate::backend::ops) return
+//! [`BoolTensor`]s, and the casts of this module convert between them and the
+//! float tensors.
+
+use crate::backend::float::{indicator, Float};
+use crate::backend::ops::constant;
+use crate::backend::tensor::RTensor;
+use crate::error::{invalid_argument, unwrap_or_panic, Result};
+use ndarray::prelude::*;
+
+/// Tensor of integers, e.g. class labels or gather indices
+pub type IntTensor = ArrayD<i64>;
+
+/// Tensor of booleans, e.g. the result of a comparison or an attention mask
+pub type BoolTensor = ArrayD<bool>;
+
+/// Fallible version of [`to_int`], returns an error if an element is NaN or
+/// doesn't fit in an `i64`
+pub fn try_to_int<T: Float>(t: &RTensor<T>) -> Result<IntTensor> {
+    let t = t.borrow();
+    let data = t.data.borrow();
+    if let Some(x) = data.iter().find(|x| x.to_i64().is_none()) {
+        return invalid_argument("ToInt", format!("{} can't be cast to an integer", x));
+    }
+    Ok(data.mapv(|x| x.to_i64().unwrap()))
+}
+
+/// Casts the elements of `t` to integers, truncating them towards zero
+pub fn to_int<T: Float>(t: &RTensor<T>) -> IntTensor {
+    unwrap_or_panic(try_to_int(t))
+}
+
+/// Casts the elements of `t` to booleans, that are true for the non-zero
+/// elements
+pub fn to_bool<T: Float>(t: &RTensor<T>) -> BoolTensor {
+    t.borrow().data.borrow().mapv(|x| !x.is_zero())
+}
+
+/// Casts the integers of `t` to a float tensor. The result doesn't require
+/// grad, since the gradient can't flow back to `t`.
+pub fn from_int<T: Float>(t: &IntTensor) -> RTensor<T> {
+    constant(t.mapv(|x| T::from_i64(x).unwrap()))
+}
+
+/// Casts the booleans of `t` to a float tensor of ones and zeros. The result
+/// doesn't require grad, since the gradient can't flow back to `t`.
+pub fn from_bool<T: Float>(t: &BoolTensor) -> RTensor<T> {
+    constant(t.mapv(indicator))
+}
+
+/// Casts the integers of `t` to booleans, that are true for the non-zero
+/// elements
+pub fn int_to_bool(t: &IntTensor) -> BoolTensor {
+    t.mapv(|x| x != 0)
+}
+
+/// Casts the booleans of `t` to ones and zeros
+pub fn bool_to_int(t: &BoolTensor) -> IntTensor {
+    t.mapv(i64::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::tensor::Tensor;
+    use crate::error::RustyGradError;
+
+    #[test]
+    fn casts_ok() {
+        let t = Tensor::new_ref(&array![-1.7f32, 0., 2.5].into_dyn());
+        let ints = to_int(&t);
+        assert_eq!(ints, array![-1, 0, 2].into_dyn());
+        let bools = to_bool(&t);
+        assert_eq!(bools, array![true, false, true].into_dyn());
+        assert_eq!(int_to_bool(&ints), bools);
+        assert_eq!(bool_to_int(&bools), array![1, 0, 1].into_dyn());
+
+        let floats = from_int::<f64>(&ints);
+        assert_eq!(floats.borrow().data, array![-1., 0., 2.].into_dyn());
+        assert!(!floats.borrow().requires_grad);
+        let mask = from_bool::<f32>(&bools);
+        assert_eq!(mask.borrow().data, array![1., 0., 1.].into_dyn());
+    }
+
+    #[test]
+    fn to_int_err() {
+        let t = Tensor::new_ref(&array![1., f32::NAN].into_dyn());
+        assert!(matches!(
+            try_to_int(&t),
+            Err(RustyGradError::InvalidArgument { op: "ToInt", .. })
+        ));
+        let t = Tensor::new_ref(&array![1e30f32].into_dyn());
+        assert!(try_to_int(&t).is_err());
+    }
+}
@@ -0,0 +1,953 @@
+//! Forward-mode automatic differentiation. A [`DualTensor`] carries the value
+//! of a tensor (the primal) and its derivative along a direction (the tangent),
+//! and each op computes both, so the directional derivative of a function is
+//! obtained with a single evaluation, without recording a graph. It's cheaper
+//! than the reverse mode when there are few inputs and many outputs.
+//!
+//! The ops mirror the ones of [`ops`](crate::backend::ops) and
+//! [`activations`](crate::nn::activations), with the same errors.
+
+use crate::backend::float::{indicator, Float};
+use crate::backend::ops::{self, constant, prod_rest};
+use crate::backend::tensor::RTensor;
+use crate::error::{unwrap_or_panic, Result, RustyGradError};
+use crate::nn::activations::{sigmoid_value, GELU_COEF, GELU_SCALE};
+use ndarray::{prelude::*, Slice, Zip};
+
+/// Tensor of the forward mode, with its value and its tangent
+#[derive(Debug, Clone, PartialEq)]
+pub struct DualTensor<T: Float = f32> {
+    pub primal: ArrayD<T>,
+    pub tangent: ArrayD<T>,
+}
+
+impl<T: Float> DualTensor<T> {
+    /// Fallible version of [`DualTensor::new`], returns an error if `primal`
+    /// and `tangent` don't have the same shape
+    pub fn try_new(primal: ArrayD<T>, tangent: ArrayD<T>) -> Result<Self> {
+        if primal.shape() != tangent.shape() {
+            return Err(RustyGradError::ShapeMismatch {
+                op: "Dual",
+                lhs: primal.shape().to_vec(),
+                rhs: tangent.shape().to_vec(),
+            });
+        }
+        Ok(DualTensor { primal, tangent })
+    }
+
+    /// Creates a dual tensor with value `primal` and derivative `tangent`
+    pub fn new(primal: ArrayD<T>, tangent: ArrayD<T>) -> Self {
+        unwrap_or_panic(Self::try_new(primal, tangent))
+    }
+
+    /// Creates a dual tensor with a zero tangent, for the values that don't
+    /// depend on the direction of the derivative
+    pub fn constant(primal: ArrayD<T>) -> Self {
+        let tangent = ArrayD::zeros(primal.raw_dim());
+        DualTensor { primal, tangent }
+    }
+}
+
+/// Applies the op `f` to `data` wrapped in a constant tensor and returns the
+/// data of the result
+fn apply<T: Float>(
+    data: &ArrayD<T>,
+    f: impl FnOnce(&RTensor<T>) -> Result<RTensor<T>>,
+) -> Result<ArrayD<T>> {
+    let res = f(&constant(data.clone()))?;
+    let data = res.borrow().data.borrow().clone();
+    Ok(data)
+}
+
+/// Applies the binary op `f` to `a` and `b` wrapped in constant tensors and
+/// returns the data of the result
+fn apply2<T: Float>(
+    a: &ArrayD<T>,
+    b: &ArrayD<T>,
+    f: impl FnOnce(&RTensor<T>, &RTensor<T>) -> Result<RTensor<T>>,
+) -> Result<ArrayD<T>> {
+    apply(a, |a| f(a, &constant(b.clone())))
+}
+
+/// Applies the linear op `f`, whose derivative is the op itself, to `x`
+fn linear<T: Float>(
+    x: &DualTensor<T>,
+    f: impl Fn(&RTensor<T>) -> Result<RTensor<T>>,
+) -> Result<DualTensor<T>> {
+    Ok(DualTensor {
+        primal: apply(&x.primal, &f)?,
+        tangent: apply(&x.tangent, &f)?,
+    })
+}
+
+/// Applies the linear op `f` of several tensors to `xs`
+fn linear_many<T: Float>(
+    xs: &[DualTensor<T>],
+    f: impl Fn(&[RTensor<T>]) -> Result<RTensor<T>>,
+) -> Result<DualTensor<T>> {
+    let apply_many = |arrays: Vec<&ArrayD<T>>| -> Result<ArrayD<T>> {
+        let ts: Vec<RTensor<T>> = arrays.into_iter().map(|a| constant(a.clone())).collect();
+        let res = f(&ts)?;
+        let data = res.borrow().data.borrow().clone();
+        Ok(data)
+    };
+    Ok(DualTensor {
+        primal: apply_many(xs.iter().map(|x| &x.primal).collect())?,
+        tangent: apply_many(xs.iter().map(|x| &x.tangent).collect())?,
+    })
+}
+
+/// Applies the linear op `f` that splits a tensor in several ones to `x`
+fn linear_split<T: Float>(
+    x: &DualTensor<T>,
+    f: impl Fn(&RTensor<T>) -> Result<Vec<RTensor<T>>>,
+) -> Result<Vec<DualTensor<T>>> {
+    let primals = f(&constant(x.primal.clone()))?;
+    let tangents = f(&constant(x.tangent.clone()))?;
+    Ok(primals
+        .iter()
+        .zip(&tangents)
+        .map(|(p, t)| DualTensor {
+            primal: p.borrow().data.borrow().clone(),
+            tangent: t.borrow().data.borrow().clone(),
+        })
+        .collect())
+}
+
+/// Computes an elementwise unary op, where `derivative` returns the local
+/// derivative of an element from the input and output values
+fn unary<T: Float>(
+    x: &DualTensor<T>,
+    f: impl Fn(T) -> T,
+    derivative: impl Fn(T, T) -> T,
+) -> DualTensor<T> {
+    let primal = x.primal.mapv(f);
+    let tangent = Zip::from(&x.tangent)
+        .and(&x.primal)
+        .and(&primal)
+        .map_collect(|&t, &x, &y| t * derivative(x, y));
+    DualTensor { primal, tangent }
+}
+
+/// Fallible version of [`add`], returns an error if the shapes of `a` and `b`
+/// can't be broadcasted together
+pub fn try_add<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> Result<DualTensor<T>> {
+    Ok(DualTensor {
+        primal: apply2(&a.primal, &b.primal, ops::try_add)?,
+        tangent: apply2(&a.tangent, &b.tangent, ops::try_add)?,
+    })
+}
+
+/// Elementwise sum of `a` and `b`, with broadcasting
+pub fn add<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> DualTensor<T> {
+    unwrap_or_panic(try_add(a, b))
+}
+
+/// Fallible version of [`diff`], returns an error if the shapes of `a` and `b`
+/// can't be broadcasted together
+pub fn try_diff<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> Result<DualTensor<T>> {
+    Ok(DualTensor {
+        primal: apply2(&a.primal, &b.primal, ops::try_diff)?,
+        tangent: apply2(&a.tangent, &b.tangent, ops::try_diff)?,
+    })
+}
+
+/// Elementwise difference of `a` and `b`, with broadcasting
+pub fn diff<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> DualTensor<T> {
+    unwrap_or_panic(try_diff(a, b))
+}
+
+/// Fallible version of [`mul`], returns an error if the shapes of `a` and `b`
+/// can't be broadcasted together
+pub fn try_mul<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> Result<DualTensor<T>> {
+    let primal = apply2(&a.primal, &b.primal, ops::try_mul)?;
+    // d(a * b) = da * b + a * db
+    let tangent = &apply2(&a.tangent, &b.primal, ops::try_mul)?
+        + &apply2(&a.primal, &b.tangent, ops::try_mul)?;
+    Ok(DualTensor { primal, tangent })
+}
+
+/// Elementwise product of `a` and `b`, with broadcasting
+pub fn mul<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> DualTensor<T> {
+    unwrap_or_panic(try_mul(a, b))
+}
+
+/// Fallible version of [`div`], returns an error if the shapes of `a` and `b`
+/// can't be broadcasted together
+pub fn try_div<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> Result<DualTensor<T>> {
+    let primal = apply2(&a.primal, &b.primal, ops::try_div)?;
+    // d(a / b) = (da - a / b * db) / b
+    let numerator = apply2(
+        &a.tangent,
+        &apply2(&primal, &b.tangent, ops::try_mul)?,
+        ops::try_diff,
+    )?;
+    let tangent = apply2(&numerator, &b.primal, ops::try_div)?;
+    Ok(DualTensor { primal, tangent })
+}
+
+/// Elementwise division of `a` by `b`, with broadcasting
+pub fn div<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> DualTensor<T> {
+    unwrap_or_panic(try_div(a, b))
+}
+
+/// Elementwise power of `x` to `power`
+pub fn pow<T: Float>(x: &DualTensor<T>, power: T) -> DualTensor<T> {
+    unary(
+        x,
+        |x| x.powf(power),
+        |x, _| power * x.powf(power - T::one()),
+    )
+}
+
+/// Elementwise exponential of `x`
+pub fn exp<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    unary(x, T::exp, |_, y| y)
+}
+
+/// Elementwise natural logarithm of `x`
+pub fn log<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    unary(x, T::ln, |x, _| x.recip())
+}
+
+/// Elementwise `ln(1 + x)` of `x`
+pub fn log1p<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    unary(x, T::ln_1p, |x, _| (T::one() + x).recip())
+}
+
+/// Elementwise square root of `x`
+pub fn sqrt<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    unary(x, T::sqrt, |_, y| T::cast(0.5) / y)
+}
+
+/// Elementwise reciprocal of the square root of `x`
+pub fn rsqrt<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    unary(x, |x| x.sqrt().recip(), |x, y| T::cast(-0.5) * y / x)
+}
+
+/// Elementwise absolute value of `x`
+pub fn abs<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    let sign = sign(x).primal;
+    DualTensor {
+        primal: x.primal.mapv(T::abs),
+        tangent: &x.tangent * &sign,
+    }
+}
+
+/// Elementwise negation of `x`
+pub fn neg<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    DualTensor {
+        primal: x.primal.mapv(T::neg),
+        tangent: x.tangent.mapv(T::neg),
+    }
+}
+
+/// Elementwise sign of `x` (-1, 0 or 1), with a zero derivative
+pub fn sign<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    let primal = apply(&x.primal, |t| Ok(ops::sign(t))).unwrap();
+    DualTensor::constant(primal)
+}
+
+/// Elementwise sine of `x`
+pub fn sin<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    unary(x, T::sin, |x, _| x.cos())
+}
+
+/// Elementwise cosine of `x`
+pub fn cos<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    unary(x, T::cos, |x, _| -x.sin())
+}
+
+/// Fallible version of [`clamp`], returns an error if a bound is NaN or `min`
+/// is greater than `max`
+pub fn try_clamp<T: Float>(x: &DualTensor<T>, min: T, max: T) -> Result<DualTensor<T>> {
+    let primal = apply(&x.primal, |t| ops::try_clamp(t, min, max))?;
+    let tangent = Zip::from(&x.tangent).and(&x.primal).map_collect(|&t, &x| {
+        if min <= x && x <= max {
+            t
+        } else {
+            T::zero()
+        }
+    });
+    Ok(DualTensor { primal, tangent })
+}
+
+/// Elementwise clamp of `x` to the range `[min, max]`
+pub fn clamp<T: Float>(x: &DualTensor<T>, min: T, max: T) -> DualTensor<T> {
+    unwrap_or_panic(try_clamp(x, min, max))
+}
+
+/// Computes an elementwise op that selects one of `a` and `b`. The tangent is
+/// the one of the selected input, and the mean of both when they are equal.
+fn select<T: Float>(
+    a: &DualTensor<T>,
+    b: &DualTensor<T>,
+    f: impl Fn(&RTensor<T>, &RTensor<T>) -> Result<RTensor<T>>,
+    select_first: fn(T, T) -> bool,
+) -> Result<DualTensor<T>> {
+    let primal = apply2(&a.primal, &b.primal, f)?;
+    let dim = primal.raw_dim();
+    let tangent = Zip::from(&a.primal.broadcast(dim.clone()).unwrap())
+        .and(&b.primal.broadcast(dim.clone()).unwrap())
+        .and(&a.tangent.broadcast(dim.clone()).unwrap())
+        .and(&b.tangent.broadcast(dim).unwrap())
+        .map_collect(|&x1, &x2, &t1, &t2| {
+            if x1 == x2 {
+                T::cast(0.5) * (t1 + t2)
+            } else if select_first(x1, x2) {
+                t1
+            } else {
+                t2
+            }
+        });
+    Ok(DualTensor { primal, tangent })
+}
+
+/// Fallible version of [`minimum`], returns an error if the shapes of `a` and
+/// `b` can't be broadcasted together
+pub fn try_minimum<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> Result<DualTensor<T>> {
+    select(a, b, ops::try_minimum, |x1, x2| x1 < x2)
+}
+
+/// Elementwise minimum of `a` and `b`, with broadcasting
+pub fn minimum<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> DualTensor<T> {
+    unwrap_or_panic(try_minimum(a, b))
+}
+
+/// Fallible version of [`maximum`], returns an error if the shapes of `a` and
+/// `b` can't be broadcasted together
+pub fn try_maximum<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> Result<DualTensor<T>> {
+    select(a, b, ops::try_maximum, |x1, x2| x1 > x2)
+}
+
+/// Elementwise maximum of `a` and `b`, with broadcasting
+pub fn maximum<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> DualTensor<T> {
+    unwrap_or_panic(try_maximum(a, b))
+}
+
+/// Fallible version of [`matmul`], returns an error if the shapes of `a` and
+/// `b` are not compatible
+pub fn try_matmul<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> Result<DualTensor<T>> {
+    let primal = apply2(&a.primal, &b.primal, ops::try_matmul)?;
+    let tangent = &apply2(&a.tangent, &b.primal, ops::try_matmul)?
+        + &apply2(&a.primal, &b.tangent, ops::try_matmul)?;
+    Ok(DualTensor { primal, tangent })
+}
+
+/// Matrix product of `a` and `b`, see [`ops::matmul`]
+pub fn matmul<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> DualTensor<T> {
+    unwrap_or_panic(try_matmul(a, b))
+}
+
+/// Fallible version of [`dot`], returns an error if `a` and `b` are not
+/// vectors of the same length
+pub fn try_dot<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> Result<DualTensor<T>> {
+    let primal = apply2(&a.primal, &b.primal, ops::try_dot)?;
+    let tangent = &apply2(&a.tangent, &b.primal, ops::try_dot)?
+        + &apply2(&a.primal, &b.tangent, ops::try_dot)?;
+    Ok(DualTensor { primal, tangent })
+}
+
+/// Dot product of the vectors `a` and `b`
+pub fn dot<T: Float>(a: &DualTensor<T>, b: &DualTensor<T>) -> DualTensor<T> {
+    unwrap_or_panic(try_dot(a, b))
+}
+
+/// Fallible version of [`sum`], returns an error if any of the axes is not
+/// valid
+pub fn try_sum<T: Float>(
+    x: &DualTensor<T>,
+    axes: Option<&[usize]>,
+    keepdims: bool,
+) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_sum(t, axes, keepdims))
+}
+
+/// Sum of the elements of `x` over the given `axes` (all of them if `None`)
+pub fn sum<T: Float>(x: &DualTensor<T>, axes: Option<&[usize]>, keepdims: bool) -> DualTensor<T> {
+    unwrap_or_panic(try_sum(x, axes, keepdims))
+}
+
+/// Fallible version of [`sum_to`], returns an error if `shape` can't be
+/// broadcasted to the shape of `x`
+pub fn try_sum_to<T: Float>(x: &DualTensor<T>, shape: &[usize]) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_sum_to(t, shape))
+}
+
+/// Sums `x` to `shape`, see [`ops::sum_to`]
+pub fn sum_to<T: Float>(x: &DualTensor<T>, shape: &[usize]) -> DualTensor<T> {
+    unwrap_or_panic(try_sum_to(x, shape))
+}
+
+/// Fallible version of [`mean`], returns an error if any of the axes is not
+/// valid
+pub fn try_mean<T: Float>(
+    x: &DualTensor<T>,
+    axes: Option<&[usize]>,
+    keepdims: bool,
+) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_mean(t, axes, keepdims))
+}
+
+/// Mean of the elements of `x` over the given `axes` (all of them if `None`)
+pub fn mean<T: Float>(x: &DualTensor<T>, axes: Option<&[usize]>, keepdims: bool) -> DualTensor<T> {
+    unwrap_or_panic(try_mean(x, axes, keepdims))
+}
+
+/// Computes the extremum of `x` over `axes` with the op `f`. The tangent is
+/// the one of the selected element, and the mean of the tied ones.
+fn extremum<T: Float>(
+    x: &DualTensor<T>,
+    axes: Option<&[usize]>,
+    keepdims: bool,
+    f: impl Fn(&RTensor<T>, Option<&[usize]>, bool) -> Result<RTensor<T>>,
+) -> Result<DualTensor<T>> {
+    let kept = apply(&x.primal, |t| f(t, axes, true))?;
+    let mask = Zip::from(&x.primal)
+        .and_broadcast(&kept)
+        .map_collect(|&x, &selected| indicator(x == selected));
+    let n_ties = apply(&mask, |t| ops::try_sum(t, axes, keepdims))?;
+    let tangent = apply(&(&x.tangent * &mask), |t| ops::try_sum(t, axes, keepdims))? / n_ties;
+    let primal = apply(&x.primal, |t| f(t, axes, keepdims))?;
+    Ok(DualTensor { primal, tangent })
+}
+
+/// Fallible version of [`max`], returns an error if any of the axes is not
+/// valid
+pub fn try_max<T: Float>(
+    x: &DualTensor<T>,
+    axes: Option<&[usize]>,
+    keepdims: bool,
+) -> Result<DualTensor<T>> {
+    extremum(x, axes, keepdims, ops::try_max)
+}
+
+/// Maximum of the elements of `x` over the given `axes` (all of them if `None`)
+pub fn max<T: Float>(x: &DualTensor<T>, axes: Option<&[usize]>, keepdims: bool) -> DualTensor<T> {
+    unwrap_or_panic(try_max(x, axes, keepdims))
+}
+
+/// Fallible version of [`min`], returns an error if any of the axes is not
+/// valid
+pub fn try_min<T: Float>(
+    x: &DualTensor<T>,
+    axes: Option<&[usize]>,
+    keepdims: bool,
+) -> Result<DualTensor<T>> {
+    extremum(x, axes, keepdims, ops::try_min)
+}
+
+/// Minimum of the elements of `x` over the given `axes` (all of them if `None`)
+pub fn min<T: Float>(x: &DualTensor<T>, axes: Option<&[usize]>, keepdims: bool) -> DualTensor<T> {
+    unwrap_or_panic(try_min(x, axes, keepdims))
+}
+
+/// Fallible version of [`prod`], returns an error if any of the axes is not
+/// valid
+pub fn try_prod<T: Float>(
+    x: &DualTensor<T>,
+    axes: Option<&[usize]>,
+    keepdims: bool,
+) -> Result<DualTensor<T>> {
+    let primal = apply(&x.primal, |t| ops::try_prod(t, axes, keepdims))?;
+    let all_axes: Vec<usize> = match axes {
+        Some(axes) => axes.to_vec(),
+        None => (0..x.primal.ndim()).collect(),
+    };
+    // d(prod(x)) = sum(dx * prod of the rest of elements)
+    let rest = prod_rest(&x.primal, &all_axes);
+    let tangent = apply(&(&x.tangent * &rest), |t| ops::try_sum(t, axes, keepdims))?;
+    Ok(DualTensor { primal, tangent })
+}
+
+/// Product of the elements of `x` over the given `axes` (all of them if `None`)
+pub fn prod<T: Float>(x: &DualTensor<T>, axes: Option<&[usize]>, keepdims: bool) -> DualTensor<T> {
+    unwrap_or_panic(try_prod(x, axes, keepdims))
+}
+
+/// Fallible version of [`reshape`], returns an error if the number of
+/// elements of `shape` is not the same as in `x`
+pub fn try_reshape<T: Float>(x: &DualTensor<T>, shape: &[usize]) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_reshape(t, shape))
+}
+
+/// Reshapes `x` to `shape`
+pub fn reshape<T: Float>(x: &DualTensor<T>, shape: &[usize]) -> DualTensor<T> {
+    unwrap_or_panic(try_reshape(x, shape))
+}
+
+/// Fallible version of [`transpose`], returns an error if any of the axes is
+/// not valid
+pub fn try_transpose<T: Float>(
+    x: &DualTensor<T>,
+    axis1: usize,
+    axis2: usize,
+) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_transpose(t, axis1, axis2))
+}
+
+/// Swaps the axes `axis1` and `axis2` of `x`
+pub fn transpose<T: Float>(x: &DualTensor<T>, axis1: usize, axis2: usize) -> DualTensor<T> {
+    unwrap_or_panic(try_transpose(x, axis1, axis2))
+}
+
+/// Fallible version of [`permute`], returns an error if `axes` is not a
+/// permutation of the axes of `x`
+pub fn try_permute<T: Float>(x: &DualTensor<T>, axes: &[usize]) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_permute(t, axes))
+}
+
+/// Permutes the axes of `x`
+pub fn permute<T: Float>(x: &DualTensor<T>, axes: &[usize]) -> DualTensor<T> {
+    unwrap_or_panic(try_permute(x, axes))
+}
+
+/// Fallible version of [`squeeze`], returns an error if any of the axes is not
+/// valid or doesn't have size 1
+pub fn try_squeeze<T: Float>(x: &DualTensor<T>, axes: Option<&[usize]>) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_squeeze(t, axes))
+}
+
+/// Removes the given `axes` of size 1 of `x` (all of them if `None`)
+pub fn squeeze<T: Float>(x: &DualTensor<T>, axes: Option<&[usize]>) -> DualTensor<T> {
+    unwrap_or_panic(try_squeeze(x, axes))
+}
+
+/// Fallible version of [`unsqueeze`], returns an error if `axis` is greater
+/// than the number of dimensions of `x`
+pub fn try_unsqueeze<T: Float>(x: &DualTensor<T>, axis: usize) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_unsqueeze(t, axis))
+}
+
+/// Inserts an axis of size 1 at position `axis` of `x`
+pub fn unsqueeze<T: Float>(x: &DualTensor<T>, axis: usize) -> DualTensor<T> {
+    unwrap_or_panic(try_unsqueeze(x, axis))
+}
+
+/// Fallible version of [`flatten`], returns an error if any of the axes is not
+/// valid or `start_axis` is greater than `end_axis`
+pub fn try_flatten<T: Float>(
+    x: &DualTensor<T>,
+    start_axis: usize,
+    end_axis: usize,
+) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_flatten(t, start_axis, end_axis))
+}
+
+/// Merges the axes of `x` from `start_axis` to `end_axis` (both included)
+pub fn flatten<T: Float>(x: &DualTensor<T>, start_axis: usize, end_axis: usize) -> DualTensor<T> {
+    unwrap_or_panic(try_flatten(x, start_axis, end_axis))
+}
+
+/// Fallible version of [`expand`], returns an error if `x` can't be
+/// broadcasted to `shape`
+pub fn try_expand<T: Float>(x: &DualTensor<T>, shape: &[usize]) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_expand(t, shape))
+}
+
+/// Broadcasts `x` to `shape`
+pub fn expand<T: Float>(x: &DualTensor<T>, shape: &[usize]) -> DualTensor<T> {
+    unwrap_or_panic(try_expand(x, shape))
+}
+
+/// Fallible version of [`slice`], returns an error if there are more slices
+/// than axes, a step is 0 or a bound is out of the axis
+pub fn try_slice<T: Float>(x: &DualTensor<T>, slices: &[Slice]) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_slice(t, slices))
+}
+
+/// Selects a region of `x`, see [`ops::slice`]
+pub fn slice<T: Float>(x: &DualTensor<T>, slices: &[Slice]) -> DualTensor<T> {
+    unwrap_or_panic(try_slice(x, slices))
+}
+
+/// Fallible version of [`index_select`], returns an error if `axis` is not
+/// valid or any of the indices is out of bounds
+pub fn try_index_select<T: Float>(
+    x: &DualTensor<T>,
+    axis: usize,
+    indices: &[usize],
+) -> Result<DualTensor<T>> {
+    linear(x, |t| ops::try_index_select(t, axis, indices))
+}
+
+/// Selects the elements of `x` at `indices` along `axis`
+pub fn index_select<T: Float>(x: &DualTensor<T>, axis: usize, indices: &[usize]) -> DualTensor<T> {
+    unwrap_or_panic(try_index_select(x, axis, indices))
+}
+
+/// Fallible version of [`concat`], returns an error if `xs` is empty, `axis`
+/// is not valid or the shapes of the tensors are not compatible
+pub fn try_concat<T: Float>(xs: &[DualTensor<T>], axis: usize) -> Result<DualTensor<T>> {
+    linear_many(xs, |ts| ops::try_concat(ts, axis))
+}
+
+/// Concatenates the tensors `xs` along `axis`
+pub fn concat<T: Float>(xs: &[DualTensor<T>], axis: usize) -> DualTensor<T> {
+    unwrap_or_panic(try_concat(xs, axis))
+}
+
+/// Fallible version of [`stack`], returns an error if `xs` is empty, `axis`
+/// is not valid or the tensors don't have the same shape
+pub fn try_stack<T: Float>(xs: &[DualTensor<T>], axis: usize) -> Result<DualTensor<T>> {
+    linear_many(xs, |ts| ops::try_stack(ts, axis))
+}
+
+/// Stacks the tensors `xs` along a new axis at position `axis`
+pub fn stack<T: Float>(xs: &[DualTensor<T>], axis: usize) -> DualTensor<T> {
+    unwrap_or_panic(try_stack(xs, axis))
+}
+
+/// Fallible version of [`split`], returns an error if `axis` is not valid or
+/// the sizes don't add up to the size of the axis
+pub fn try_split<T: Float>(
+    x: &DualTensor<T>,
+    sizes: &[usize],
+    axis: usize,
+) -> Result<Vec<DualTensor<T>>> {
+    linear_split(x, |t| ops::try_split(t, sizes, axis))
+}
+
+/// Splits `x` along `axis` in tensors with the given `sizes`
+pub fn split<T: Float>(x: &DualTensor<T>, sizes: &[usize], axis: usize) -> Vec<DualTensor<T>> {
+    unwrap_or_panic(try_split(x, sizes, axis))
+}
+
+/// Fallible version of [`chunk`], returns an error if `n_chunks` is 0 or
+/// `axis` is not valid
+pub fn try_chunk<T: Float>(
+    x: &DualTensor<T>,
+    n_chunks: usize,
+    axis: usize,
+) -> Result<Vec<DualTensor<T>>> {
+    linear_split(x, |t| ops::try_chunk(t, n_chunks, axis))
+}
+
+/// Splits `x` along `axis` in `n_chunks` tensors of (almost) equal size
+pub fn chunk<T: Float>(x: &DualTensor<T>, n_chunks: usize, axis: usize) -> Vec<DualTensor<T>> {
+    unwrap_or_panic(try_chunk(x, n_chunks, axis))
+}
+
+/// Elementwise rectified linear unit of `x`
+pub fn relu<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    unary(x, |x| x.max(T::zero()), |x, _| indicator(x > T::zero()))
+}
+
+/// Elementwise hyperbolic tangent of `x`
+pub fn tanh<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    unary(x, T::tanh, |_, y| T::one() - y * y)
+}
+
+/// Elementwise logistic function of `x`
+pub fn sigmoid<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    unary(x, sigmoid_value, |_, y| y * (T::one() - y))
+}
+
+/// Elementwise GELU of `x`, with the same tanh approximation as
+/// [`gelu`](crate::nn::activations::gelu)
+pub fn gelu<T: Float>(x: &DualTensor<T>) -> DualTensor<T> {
+    let (scale, coef, half) = (T::cast(GELU_SCALE), T::cast(GELU_COEF), T::cast(0.5));
+    let th = move |x: T| (scale * (x + coef * x.powi(3))).tanh();
+    unary(
+        x,
+        move |x| half * x * (T::one() + th(x)),
+        move |x, _| {
+            let th = th(x);
+            let du = scale * (T::one() + T::cast(3.) * coef * x * x);
+            half * (T::one() + th) + half * x * (T::one() - th * th) * du
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::autograd::jvp;
+    use crate::backend::tensor::Tensor;
+    use crate::nn::activations;
+    use rand::{distributions::Uniform, prelude::Distribution, rngs::StdRng, SeedableRng};
+
+    fn rand_array(shape: &[usize], seed: u64) -> ArrayD<f32> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let uniform = Uniform::new_inclusive(0.5, 2.0);
+        Array::from_shape_simple_fn(IxDyn(shape), || uniform.sample(&mut rng))
+    }
+
+    fn assert_close(a: &ArrayD<f32>, b: &ArrayD<f32>) {
+        assert_eq!(a.shape(), b.shape());
+        assert!(
+            a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-4),
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    /// Checks that the forward mode `f_dual` computes the same values and
+    /// directional derivatives as the reverse mode `f` for the given `primals`
+    fn check_primals(
+        f_dual: impl Fn(&[DualTensor]) -> DualTensor,
+        f: impl Fn(&[RTensor]) -> RTensor,
+        primals: Vec<ArrayD<f32>>,
+    ) {
+        let tangents: Vec<ArrayD<f32>> = primals
+            .iter()
+            .enumerate()
+            .map(|(i, p)| rand_array(p.shape(), 100 + i as u64) - 1.)
+            .collect();
+        let duals: Vec<DualTensor> = primals
+            .iter()
+            .zip(&tangents)
+            .map(|(p, t)| DualTensor::new(p.clone(), t.clone()))
+            .collect();
+        let inputs: Vec<RTensor> = primals.iter().map(Tensor::new_ref).collect();
+        let res = f_dual(&duals);
+        let (out, product) = jvp(f, &inputs, &tangents);
+        assert_close(&res.primal, &out);
+        assert_close(&res.tangent, &product);
+    }
+
+    fn check(
+        f_dual: impl Fn(&[DualTensor]) -> DualTensor,
+        f: impl Fn(&[RTensor]) -> RTensor,
+        shapes: &[&[usize]],
+    ) {
+        let primals = shapes
+            .iter()
+            .enumerate()
+            .map(|(i, s)| rand_array(s, i as u64))
+            .collect();
+        check_primals(f_dual, f, primals);
+    }
+
+    #[test]
+    fn dual_new_err() {
+        let res =
+            DualTensor::<f32>::try_new(Array::zeros(3).into_dyn(), Array::zeros(2).into_dyn());
+        assert_eq!(
+            res,
+            Err(RustyGradError::ShapeMismatch {
+                op: "Dual",
+                lhs: vec![3],
+                rhs: vec![2]
+            })
+        );
+    }
+
+    #[test]
+    fn dual_binary_ops_ok() {
+        check(
+            |x| add(&x[0], &x[1]),
+            |x| ops::add(&x[0], &x[1]),
+            &[&[2, 3], &[3]],
+        );
+        check(
+            |x| diff(&x[0], &x[1]),
+            |x| ops::diff(&x[0], &x[1]),
+            &[&[2, 1], &[2, 3]],
+        );
+        check(
+            |x| mul(&x[0], &x[1]),
+            |x| ops::mul(&x[0], &x[1]),
+            &[&[2, 3], &[2, 1]],
+        );
+        check(
+            |x| div(&x[0], &x[1]),
+            |x| ops::div(&x[0], &x[1]),
+            &[&[3], &[2, 3]],
+        );
+        check(|x| pow(&x[0], 3.), |x| ops::pow(&x[0], 3.), &[&[2, 2]]);
+        check(
+            |x| matmul(&x[0], &x[1]),
+            |x| ops::matmul(&x[0], &x[1]),
+            &[&[2, 2, 3], &[3, 4]],
+        );
+        check(
+            |x| dot(&x[0], &x[1]),
+            |x| ops::dot(&x[0], &x[1]),
+            &[&[3], &[3]],
+        );
+        check(
+            |x| maximum(&x[0], &x[1]),
+            |x| ops::maximum(&x[0], &x[1]),
+            &[&[2, 3], &[3]],
+        );
+        check(
+            |x| minimum(&x[0], &x[1]),
+            |x| ops::minimum(&x[0], &x[1]),
+            &[&[2, 3], &[2, 1]],
+        );
+        // Ties split the tangent evenly, like the gradient
+        check_primals(
+            |x| maximum(&x[0], &x[1]),
+            |x| ops::maximum(&x[0], &x[1]),
+            vec![array![1., 2.].into_dyn(), array![1., 3.].into_dyn()],
+        );
+        assert!(try_add(
+            &DualTensor::<f32>::constant(Array::zeros(2).into_dyn()),
+            &DualTensor::constant(Array::zeros(3).into_dyn())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn dual_math_ops_ok() {
+        check(|x| exp(&x[0]), |x| ops::exp(&x[0]), &[&[4]]);
+        check(|x| log(&x[0]), |x| ops::log(&x[0]), &[&[4]]);
+        check(|x| log1p(&x[0]), |x| ops::log1p(&x[0]), &[&[4]]);
+        check(|x| sqrt(&x[0]), |x| ops::sqrt(&x[0]), &[&[4]]);
+        check(|x| rsqrt(&x[0]), |x| ops::rsqrt(&x[0]), &[&[4]]);
+        check(
+            |x| sin(&cos(&x[0])),
+            |x| ops::sin(&ops::cos(&x[0])),
+            &[&[4]],
+        );
+        check(
+            |x| clamp(&x[0], 1., 1.5),
+            |x| ops::clamp(&x[0], 1., 1.5),
+            &[&[4]],
+        );
+        let signed = vec![array![-1.5, 0., 2.].into_dyn()];
+        check_primals(|x| abs(&x[0]), |x| ops::abs(&x[0]), signed.clone());
+        check_primals(|x| neg(&x[0]), |x| ops::neg(&x[0]), signed.clone());
+        check_primals(|x| sign(&x[0]), |x| ops::sign(&x[0]), signed.clone());
+        check_primals(
+            |x| relu(&x[0]),
+            |x| activations::relu(x[0].clone()),
+            signed.clone(),
+        );
+        check_primals(
+            |x| tanh(&x[0]),
+            |x| activations::tanh(x[0].clone()),
+            signed.clone(),
+        );
+        check_primals(
+            |x| sigmoid(&x[0]),
+            |x| activations::sigmoid(x[0].clone()),
+            signed.clone(),
+        );
+        check_primals(|x| gelu(&x[0]), |x| activations::gelu(x[0].clone()), signed);
+    }
+
+    #[test]
+    fn dual_reduce_ops_ok() {
+        check(
+            |x| sum(&x[0], Some(&[1]), false),
+            |x| ops::sum(&x[0], Some(&[1]), false),
+            &[&[2, 3]],
+        );
+        check(
+            |x| sum_to(&x[0], &[1, 3]),
+            |x| ops::sum_to(&x[0], &[1, 3]),
+            &[&[2, 3]],
+        );
+        check(
+            |x| mean(&x[0], None, true),
+            |x| ops::mean(&x[0], None, true),
+            &[&[2, 3]],
+        );
+        check(
+            |x| prod(&x[0], Some(&[0]), false),
+            |x| ops::prod(&x[0], Some(&[0]), false),
+            &[&[2, 3]],
+        );
+        check(
+            |x| max(&x[0], Some(&[1]), true),
+            |x| ops::max(&x[0], Some(&[1]), true),
+            &[&[2, 3]],
+        );
+        check(
+            |x| min(&x[0], None, false),
+            |x| ops::min(&x[0], None, false),
+            &[&[2, 3]],
+        );
+        // Zeros in the product and ties in the extremum
+        let special = vec![array![[0., 2., 2.], [0., 0., 1.]].into_dyn()];
+        check_primals(
+            |x| prod(&x[0], Some(&[1]), false),
+            |x| ops::prod(&x[0], Some(&[1]), false),
+            special.clone(),
+        );
+        check_primals(
+            |x| max(&x[0], Some(&[1]), false),
+            |x| ops::max(&x[0], Some(&[1]), false),
+            special,
+        );
+    }
+
+    #[test]
+    fn dual_shape_ops_ok() {
+        check(
+            |x| reshape(&x[0], &[3, 2]),
+            |x| ops::reshape(&x[0], &[3, 2]),
+            &[&[2, 3]],
+        );
+        check(
+            |x| transpose(&x[0], 0, 2),
+            |x| ops::transpose(&x[0], 0, 2),
+            &[&[2, 3, 4]],
+        );
+        check(
+            |x| permute(&x[0], &[2, 0, 1]),
+            |x| ops::permute(&x[0], &[2, 0, 1]),
+            &[&[2, 3, 4]],
+        );
+        check(
+            |x| squeeze(&x[0], None),
+            |x| ops::squeeze(&x[0], None),
+            &[&[2, 1, 3]],
+        );
+        check(
+            |x| unsqueeze(&x[0], 1),
+            |x| ops::unsqueeze(&x[0], 1),
+            &[&[2, 3]],
+        );
+        check(
+            |x| flatten(&x[0], 0, 1),
+            |x| ops::flatten(&x[0], 0, 1),
+            &[&[2, 3, 4]],
+        );
+        check(
+            |x| expand(&x[0], &[2, 3]),
+            |x| ops::expand(&x[0], &[2, 3]),
+            &[&[3]],
+        );
+    }
+
+    #[test]
+    fn dual_index_ops_ok() {
+        let slices = [Slice::from(1..), Slice::new(0, None, 2)];
+        check(
+            |x| slice(&x[0], &slices),
+            |x| ops::slice(&x[0], &slices),
+            &[&[3, 4]],
+        );
+        check(
+            |x| index_select(&x[0], 1, &[2, 0, 2]),
+            |x| ops::index_select(&x[0], 1, &[2, 0, 2]),
+            &[&[2, 3]],
+        );
+        check(|x| concat(x, 1), |x| ops::concat(x, 1), &[&[2, 1], &[2, 3]]);
+        check(|x| stack(x, 0), |x| ops::stack(x, 0), &[&[2, 3], &[2, 3]]);
+        check(
+            |x| mul(&split(&x[0], &[1, 2], 1)[1], &chunk(&x[0], 3, 1)[0]),
+            |x| {
+                ops::mul(
+                    &ops::split(&x[0], &[1, 2], 1)[1],
+                    &ops::chunk(&x[0], 3, 1)[0],
+                )
+            },
+            &[&[2, 3]],
+        );
+    }
+
+    #[test]
+    fn dual_composite_ok() {
+        // A small layer: tanh(x·W + b)
+        check(
+            |x| tanh(&add(&matmul(&x[0], &x[1]), &x[2])),
+            |x| activations::tanh(ops::add(&ops::matmul(&x[0], &x[1]), &x[2])),
+            &[&[4, 3], &[3, 2], &[2]],
+        );
+    }
+}
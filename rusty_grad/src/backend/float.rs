@@ -0,0 +1,39 @@
+use core::fmt;
+use ndarray::{LinalgScalar, ScalarOperand};
+use num_traits::{FromPrimitive, NumAssign};
+use rand::distributions::uniform::SampleUniform;
+
+/// Element type of the tensors. It is implemented for `f32`, the default
+/// element type, and `f64`.
+pub trait Float:
+    num_traits::Float
+    + NumAssign
+    + FromPrimitive
+    + ScalarOperand
+    + LinalgScalar
+    + SampleUniform
+    + Default
+    + fmt::Debug
+    + fmt::Display
+    + Send
+    + Sync
+    + 'static
+{
+    /// Converts the constant `x` to the element type, rounding it if needed
+    fn cast(x: f64) -> Self {
+        <Self as FromPrimitive>::from_f64(x).unwrap()
+    }
+}
+
+impl Float for f32 {}
+
+impl Float for f64 {}
+
+/// Returns 1 if `cond` is true and 0 otherwise, for masks and indicators
+pub(crate) fn indicator<T: Float>(cond: bool) -> T {
+    if cond {
+        T::one()
+    } else {
+        T::zero()
+    }
+}
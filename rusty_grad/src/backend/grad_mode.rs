@@ -0,0 +1,80 @@
+use std::cell::Cell;
+
+thread_local! {
+    /// Whether the ops of the current thread record the graph for backpropagation
+    static GRAD_ENABLED: Cell<bool> = const { Cell::new(true) };
+}
+
+/// Returns true if the ops of the current thread record their graph
+pub fn is_grad_enabled() -> bool {
+    GRAD_ENABLED.with(Cell::get)
+}
+
+/// Guard returned by [`no_grad`], [`enable_grad`] and [`set_grad_enabled`].
+/// Restores the previous grad mode when dropped.
+#[must_use = "the previous grad mode is restored as soon as the guard is dropped"]
+pub struct GradModeGuard {
+    prev: bool,
+}
+
+impl Drop for GradModeGuard {
+    fn drop(&mut self) {
+        GRAD_ENABLED.with(|enabled| enabled.set(self.prev));
+    }
+}
+
+/// Disables the recording of the graph in the current thread until the
+/// returned guard is dropped. The ops computed in the meantime return tensors
+/// that don't require grad, without `prev` nor backward function.
+///
+/// ```
+/// use rusty_grad::backend::grad_mode::{is_grad_enabled, no_grad};
+///
+/// {
+///     let _guard = no_grad();
+///     assert!(!is_grad_enabled());
+/// }
+/// assert!(is_grad_enabled());
+/// ```
+pub fn no_grad() -> GradModeGuard {
+    set_grad_enabled(false)
+}
+
+/// Enables the recording of the graph in the current thread until the
+/// returned guard is dropped, even inside a [`no_grad`] scope
+pub fn enable_grad() -> GradModeGuard {
+    set_grad_enabled(true)
+}
+
+/// Sets whether the ops of the current thread record their graph until the
+/// returned guard is dropped
+pub fn set_grad_enabled(enabled: bool) -> GradModeGuard {
+    GradModeGuard {
+        prev: GRAD_ENABLED.with(|cell| cell.replace(enabled)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_grad_nested_ok() {
+        assert!(is_grad_enabled());
+        {
+            let _outer = no_grad();
+            {
+                let _inner = no_grad();
+                assert!(!is_grad_enabled());
+            }
+            // Dropping the inner guard restores the mode set by the outer one
+            assert!(!is_grad_enabled());
+            {
+                let _enabled = enable_grad();
+                assert!(is_grad_enabled());
+            }
+            assert!(!is_grad_enabled());
+        }
+        assert!(is_grad_enabled());
+    }
+}
@@ -1,2 +1,11 @@
+pub mod autograd;
+pub mod creation;
+pub mod dtype;
+pub mod dual;
+pub mod float;
+pub mod grad_mode;
 pub mod ops;
+pub mod random;
+pub mod shared;
 pub mod tensor;
+pub mod var;
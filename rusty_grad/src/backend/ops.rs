@@ -1,140 +1,272 @@
-use crate::backend::tensor::{RTensor, Tensor};
+use crate::backend::float::Float;
+use crate::backend::tensor::{RTensor, Tensor, TensorData};
+use crate::error::{unwrap_or_panic, Result, RustyGradError};
+use by_address::ByAddress;
 use ndarray::prelude::*;
+use std::collections::HashMap;
 
-pub fn add(t1: &RTensor, t2: &RTensor) -> RTensor {
-    Tensor {
-        data: &t1.borrow().data + &t2.borrow().data,
-        grad: Array::zeros(t1.borrow().grad.raw_dim()),
-        prev: vec![t1.clone(), t2.clone()],
-        backward_fn: Box::new(add_backward),
+mod compare;
+mod index;
+mod linalg;
+mod math;
+mod reduce;
+mod shape;
+
+pub use compare::{
+    eq, ge, gt, le, logical_and, logical_not, logical_or, logical_xor, lt, ne, try_eq, try_ge,
+    try_gt, try_le, try_logical_and, try_logical_or, try_logical_xor, try_lt, try_ne, try_where_,
+    where_,
+};
+pub use index::{
+    chunk, concat, index_select, slice, split, stack, try_chunk, try_concat, try_index_select,
+    try_slice, try_split, try_stack,
+};
+pub use linalg::{dot, matmul, try_dot, try_matmul};
+pub use math::{
+    abs, clamp, cos, exp, log, log1p, maximum, minimum, neg, rsqrt, sign, sin, sqrt, try_clamp,
+    try_maximum, try_minimum,
+};
+pub(crate) use reduce::prod_rest;
+pub use reduce::{
+    max, mean, min, prod, sum, sum_to, try_max, try_mean, try_min, try_prod, try_sum, try_sum_to,
+};
+pub use shape::{
+    expand, flatten, permute, reshape, squeeze, transpose, try_expand, try_flatten, try_permute,
+    try_reshape, try_squeeze, try_transpose, try_unsqueeze, unsqueeze,
+};
+
+/// Computes the shape resulting from broadcasting `s1` with `s2` following the
+/// NumPy rules: shapes are aligned from the trailing axis and each pair of
+/// dimensions must be equal or one of them must be 1.
+///
+/// Returns `None` if the shapes can't be broadcasted together.
+pub fn broadcast_shape(s1: &[usize], s2: &[usize]) -> Option<Vec<usize>> {
+    let ndim = s1.len().max(s2.len());
+    let mut shape = vec![0; ndim];
+    for i in 0..ndim {
+        // Missing leading dimensions are treated as 1
+        let d1 = if i < ndim - s1.len() {
+            1
+        } else {
+            s1[i - (ndim - s1.len())]
+        };
+        let d2 = if i < ndim - s2.len() {
+            1
+        } else {
+            s2[i - (ndim - s2.len())]
+        };
+        shape[i] = match (d1, d2) {
+            (d1, d2) if d1 == d2 => d1,
+            (1, d) | (d, 1) => d,
+            _ => return None,
+        };
     }
-    .to_ref()
+    Some(shape)
 }
 
-fn add_backward(t: &Tensor) {
-    for child in t.prev.iter() {
-        child.borrow_mut().grad += &t.grad;
+/// Reduces a gradient computed for a broadcasted result back to `shape`, the
+/// shape of the operand before broadcasting. The gradient is summed over the
+/// leading axes added by the broadcasting and over the axes that were expanded
+/// from size 1.
+pub fn unbroadcast<T: Float>(grad: &ArrayD<T>, shape: &[usize]) -> ArrayD<T> {
+    if grad.shape() == shape {
+        return grad.clone();
+    }
+    let mut res = grad.clone();
+    // Sum over the leading axes that are not in the original shape
+    while res.ndim() > shape.len() {
+        res = res.sum_axis(Axis(0));
+    }
+    // Sum over the axes that were expanded from size 1 (keeping the axis)
+    for (i, &dim) in shape.iter().enumerate() {
+        if dim == 1 && res.shape()[i] != 1 {
+            res = res.sum_axis(Axis(i)).insert_axis(Axis(i));
+        }
     }
+    res
 }
 
-pub fn diff(t1: &RTensor, t2: &RTensor) -> RTensor {
-    add(
-        t1,
-        &mul(
-            t2,
-            &Tensor::new_ref(&Array::from_elem(t1.borrow().grad.raw_dim(), -1.0)),
-        ),
-    )
+/// Returns an error if `axis` is not a valid axis for a tensor with `ndim`
+/// dimensions
+fn check_axis(op: &'static str, axis: usize, ndim: usize) -> Result<()> {
+    if axis >= ndim {
+        return Err(RustyGradError::InvalidAxis { op, axis, ndim });
+    }
+    Ok(())
 }
 
-pub fn mul(t1: &RTensor, t2: &RTensor) -> RTensor {
-    Tensor {
-        data: &t1.borrow().data * &t2.borrow().data,
-        grad: Array::zeros(t1.borrow().grad.raw_dim()),
-        prev: vec![t1.clone(), t2.clone()],
-        backward_fn: Box::new(mul_backward),
-    }
-    .to_ref()
+/// Returns the shape of the output of a broadcasting binary op, or an error if
+/// the shapes of the operands are not compatible
+fn binary_out_shape<T: Float>(
+    op: &'static str,
+    d1: &ArrayD<T>,
+    d2: &ArrayD<T>,
+) -> Result<Vec<usize>> {
+    broadcast_shape(d1.shape(), d2.shape()).ok_or_else(|| RustyGradError::ShapeMismatch {
+        op,
+        lhs: d1.shape().to_vec(),
+        rhs: d2.shape().to_vec(),
+    })
 }
 
-fn mul_backward(t: &Tensor) {
-    match &t.prev[..] {
-        [t1, t2] => {
-            let mut t1 = t1.borrow_mut();
-            let mut t2 = t2.borrow_mut();
-            t1.grad += &(&t.grad * &t2.data);
-            t2.grad += &(&t.grad * &t1.data);
-        }
-        _ => panic!(
-            "[Error] The number of children in Mul op must be 2, but is {})!",
-            t.prev.len()
-        ),
-    }
+/// Calls `f` with the data of the tensors `ts` borrowed, in the same order.
+/// A storage that appears several times (e.g. in `mul(&w, &w)`, or with a
+/// tensor and its detached version) is borrowed only once: with the `sync`
+/// feature a thread that takes the same read lock twice deadlocks if another
+/// thread starts waiting to write it in between.
+#[allow(clippy::mutable_key_type)] // ByAddress only hashes the pointer
+pub(crate) fn with_data<T: Float, R>(ts: &[&RTensor<T>], f: impl FnOnce(&[&ArrayD<T>]) -> R) -> R {
+    let storages: Vec<TensorData<T>> = ts.iter().map(|t| t.borrow().data.share()).collect();
+    // Position in `ts` of the first occurrence of each storage
+    let mut first: HashMap<ByAddress<_>, usize> = HashMap::new();
+    let positions: Vec<usize> = storages
+        .iter()
+        .enumerate()
+        .map(|(i, s)| *first.entry(ByAddress(s.0.clone())).or_insert(i))
+        .collect();
+    let guards: Vec<_> = storages
+        .iter()
+        .zip(&positions)
+        .enumerate()
+        .map(|(i, (s, &pos))| (pos == i).then(|| s.borrow()))
+        .collect();
+    let arrays: Vec<&ArrayD<T>> = positions
+        .iter()
+        .map(|&pos| guards[pos].as_deref().unwrap())
+        .collect();
+    f(&arrays)
 }
 
-pub fn dot(t1: &RTensor, t2: &RTensor) -> RTensor {
-    // Compute the dot product of t1 and t2.
-    // Note: They must be converted to a 2D array view (Ix2) to be able to call .dot()
-    let out_data = t1
-        .borrow()
-        .data
-        .view()
-        .into_dimensionality::<Ix2>()
-        .unwrap() // TODO Handle the error
-        .dot(
-            &t2.borrow()
-                .data
-                .view()
-                .into_dimensionality::<Ix2>()
-                .unwrap(), // TODO Handle the error
-        )
-        .into_dyn(); // Convert back to dynamic array to create the output Tensor
-    let out_dim = out_data.raw_dim();
-    Tensor {
-        data: out_data,
-        grad: Array::zeros(out_dim),
-        prev: vec![t1.clone(), t2.clone()],
-        backward_fn: Box::new(dot_backward),
-    }
-    .to_ref()
+/// Calls `f` with the data of `t1` and `t2` borrowed, borrowing it once if
+/// they share it. See [`with_data`].
+pub(crate) fn with_data_pair<T: Float, R>(
+    t1: &RTensor<T>,
+    t2: &RTensor<T>,
+    f: impl FnOnce(&ArrayD<T>, &ArrayD<T>) -> R,
+) -> R {
+    with_data(&[t1, t2], |ds| f(ds[0], ds[1]))
 }
 
-fn dot_backward(t: &Tensor) {
-    match &t.prev[..] {
-        [t1, t2] => {
-            let mut t1 = t1.borrow_mut();
-            let mut t2 = t2.borrow_mut();
-            t1.grad += &(t
-                .grad
-                .view()
-                .into_dimensionality::<Ix2>()
-                .unwrap()
-                .dot(&t2.data.view().into_dimensionality::<Ix2>().unwrap().t()));
-            t2.grad += &(t1
-                .data
-                .view()
-                .into_dimensionality::<Ix2>()
-                .unwrap()
-                .t()
-                .dot(&t.grad.view().into_dimensionality::<Ix2>().unwrap()));
-        }
-        _ => panic!(
-            "[Error] The number of children in Dot op must be 2, but is {})!",
-            t.prev.len()
-        ),
-    }
+/// Returns the shape of the tensor `t`
+pub(crate) fn shape_of<T: Float>(t: &RTensor<T>) -> Vec<usize> {
+    t.borrow().data.borrow().shape().to_vec()
 }
 
-pub fn div(t1: &RTensor, t2: &RTensor) -> RTensor {
-    mul(t1, &pow(t2, -1.0))
+/// Creates a tensor that doesn't require grad, used for the constant factors
+/// of the backward functions
+pub(crate) fn constant<T: Float>(data: ArrayD<T>) -> RTensor<T> {
+    Tensor::new(&data).with_requires_grad(false).to_ref()
 }
 
-pub fn pow(t1: &RTensor, power: f32) -> RTensor {
-    Tensor {
-        data: t1.borrow().data.mapv(|x| x.powf(power)),
-        grad: Array::zeros(t1.borrow().grad.raw_dim()),
-        prev: vec![t1.clone()],
-        backward_fn: Box::new(move |x| pow_backward(x, power)),
-    }
-    .to_ref()
+/// Multiplies `t` by the scalar `factor`
+pub(crate) fn scale<T: Float>(t: &RTensor<T>, factor: T) -> RTensor<T> {
+    mul(t, &constant(arr0(factor).into_dyn()))
 }
 
-fn pow_backward(t: &Tensor, power: f32) {
-    match &t.prev[..] {
-        [prev] => {
-            let dprev = prev.borrow().data.mapv(|x| x.powf(power - 1.0)) * power;
-            prev.borrow_mut().grad += &(&t.grad * &dprev);
-        }
-        _ => panic!(
-            "[Error] The number of children in Pow op must be 1, but is {})!",
-            t.prev.len()
-        ),
+/// Returns an error if the node `t` doesn't have `expected` children
+pub(crate) fn check_children<T: Float>(
+    op: &'static str,
+    t: &Tensor<T>,
+    expected: usize,
+) -> Result<()> {
+    if t.prev.len() != expected {
+        return Err(RustyGradError::InvalidChildren {
+            op,
+            expected,
+            got: t.prev.len(),
+        });
     }
+    Ok(())
+}
+
+/// Fallible version of [`add`], returns an error if the shapes of `t1` and
+/// `t2` can't be broadcasted together
+pub fn try_add<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<RTensor<T>> {
+    let data = with_data_pair(t1, t2, |d1, d2| {
+        binary_out_shape("Add", d1, d2).map(|_| d1 + d2)
+    })?;
+    Ok(Tensor::from_op(
+        data,
+        vec![t1.clone(), t2.clone()],
+        add_backward,
+    ))
+}
+
+pub fn add<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> RTensor<T> {
+    unwrap_or_panic(try_add(t1, t2))
+}
+
+fn add_backward<T: Float>(t: &Tensor<T>, grad: &RTensor<T>) -> Result<Vec<RTensor<T>>> {
+    check_children("Add", t, 2)?;
+    Ok(t.prev
+        .iter()
+        .map(|child| sum_to(grad, &shape_of(child)))
+        .collect())
+}
+
+/// Fallible version of [`diff`], returns an error if the shapes of `t1` and
+/// `t2` can't be broadcasted together
+pub fn try_diff<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<RTensor<T>> {
+    try_add(t1, &neg(t2))
+}
+
+pub fn diff<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> RTensor<T> {
+    unwrap_or_panic(try_diff(t1, t2))
+}
+
+/// Fallible version of [`mul`], returns an error if the shapes of `t1` and
+/// `t2` can't be broadcasted together
+pub fn try_mul<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<RTensor<T>> {
+    let data = with_data_pair(t1, t2, |d1, d2| {
+        binary_out_shape("Mul", d1, d2).map(|_| d1 * d2)
+    })?;
+    Ok(Tensor::from_op(
+        data,
+        vec![t1.clone(), t2.clone()],
+        mul_backward,
+    ))
+}
+
+pub fn mul<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> RTensor<T> {
+    unwrap_or_panic(try_mul(t1, t2))
+}
+
+fn mul_backward<T: Float>(t: &Tensor<T>, grad: &RTensor<T>) -> Result<Vec<RTensor<T>>> {
+    check_children("Mul", t, 2)?;
+    let (t1, t2) = (&t.prev[0], &t.prev[1]);
+    Ok(vec![
+        sum_to(&mul(grad, t2), &shape_of(t1)),
+        sum_to(&mul(grad, t1), &shape_of(t2)),
+    ])
+}
+
+/// Fallible version of [`div`], returns an error if the shapes of `t1` and
+/// `t2` can't be broadcasted together
+pub fn try_div<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<RTensor<T>> {
+    try_mul(t1, &pow(t2, -T::one()))
+}
+
+pub fn div<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> RTensor<T> {
+    unwrap_or_panic(try_div(t1, t2))
+}
+
+pub fn pow<T: Float>(t1: &RTensor<T>, power: T) -> RTensor<T> {
+    let data = t1.borrow().data.borrow().mapv(|x| x.powf(power));
+    Tensor::from_op(data, vec![t1.clone()], move |t, grad| {
+        pow_backward(t, grad, power)
+    })
+}
+
+fn pow_backward<T: Float>(t: &Tensor<T>, grad: &RTensor<T>, power: T) -> Result<Vec<RTensor<T>>> {
+    check_children("Pow", t, 1)?;
+    let dprev = scale(&pow(&t.prev[0], power - T::one()), power);
+    Ok(vec![mul(grad, &dprev)])
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::grad_mode::no_grad;
 
     #[test]
     fn add_ok() {
@@ -161,12 +293,174 @@ mod tests {
             ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![-5., 7., 1., 7., 3., 7.]).unwrap()
         );
 
-        t3.borrow_mut().backward();
+        let ones = Array::ones(t3.borrow().data.borrow().raw_dim());
+        t3.borrow_mut().backward_with(ones);
         let target_grad = Array::<f32, _>::ones(t1.borrow().grad.raw_dim());
         assert_eq!(t1.borrow().grad, target_grad);
         assert_eq!(t2.borrow().grad, target_grad);
     }
 
+    #[test]
+    fn broadcast_shape_ok() {
+        assert_eq!(broadcast_shape(&[2, 3], &[2, 3]), Some(vec![2, 3]));
+        assert_eq!(broadcast_shape(&[2, 3], &[]), Some(vec![2, 3]));
+        assert_eq!(broadcast_shape(&[2, 3], &[3]), Some(vec![2, 3]));
+        assert_eq!(broadcast_shape(&[2, 1], &[1, 3]), Some(vec![2, 3]));
+        assert_eq!(broadcast_shape(&[4, 1, 3], &[2, 1]), Some(vec![4, 2, 3]));
+        assert_eq!(broadcast_shape(&[2, 3], &[2]), None);
+        assert_eq!(broadcast_shape(&[2, 3], &[3, 3]), None);
+    }
+
+    #[test]
+    fn unbroadcast_ok() {
+        let grad = ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![1., 2., 3., 4., 5., 6.]).unwrap();
+        assert_eq!(unbroadcast(&grad, &[2, 3]), grad);
+        assert_eq!(unbroadcast(&grad, &[]), arr0(21.).into_dyn());
+        assert_eq!(
+            unbroadcast(&grad, &[3]),
+            ArrayD::from_shape_vec(IxDyn(&[3]), vec![5., 7., 9.]).unwrap()
+        );
+        assert_eq!(
+            unbroadcast(&grad, &[1, 3]),
+            ArrayD::from_shape_vec(IxDyn(&[1, 3]), vec![5., 7., 9.]).unwrap()
+        );
+        assert_eq!(
+            unbroadcast(&grad, &[2, 1]),
+            ArrayD::from_shape_vec(IxDyn(&[2, 1]), vec![6., 15.]).unwrap()
+        );
+    }
+
+    #[test]
+    fn add_broadcast_scalar_backward_ok() {
+        let arr1 = ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![1., 2., 3., 4., 5., 6.]).unwrap();
+        let t1 = Tensor::new_ref(&arr1);
+        let t2 = Tensor::new_ref(&arr0(10.).into_dyn());
+        let t3 = add(&t1, &t2);
+        assert_eq!(
+            t3.borrow().data,
+            ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![11., 12., 13., 14., 15., 16.]).unwrap()
+        );
+
+        let ones = Array::ones(t3.borrow().data.borrow().raw_dim());
+        t3.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, Array::ones(IxDyn(&[2, 3])));
+        assert_eq!(t2.borrow().grad, arr0(6.).into_dyn());
+    }
+
+    #[test]
+    fn add_broadcast_row_backward_ok() {
+        let arr1 = ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![1., 2., 3., 4., 5., 6.]).unwrap();
+        let arr2 = ArrayD::from_shape_vec(IxDyn(&[1, 3]), vec![1., -1., 2.]).unwrap();
+        let t1 = Tensor::new_ref(&arr1);
+        let t2 = Tensor::new_ref(&arr2);
+        let t3 = add(&t1, &t2);
+        assert_eq!(
+            t3.borrow().data,
+            ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![2., 1., 5., 5., 4., 8.]).unwrap()
+        );
+
+        let ones = Array::ones(t3.borrow().data.borrow().raw_dim());
+        t3.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, Array::ones(IxDyn(&[2, 3])));
+        assert_eq!(t2.borrow().grad, Array::from_elem(IxDyn(&[1, 3]), 2.));
+    }
+
+    #[test]
+    fn add_broadcast_column_backward_ok() {
+        let arr1 = ArrayD::from_shape_vec(IxDyn(&[2, 1]), vec![1., 2.]).unwrap();
+        let arr2 = ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![1., 2., 3., 4., 5., 6.]).unwrap();
+        let t1 = Tensor::new_ref(&arr1);
+        let t2 = Tensor::new_ref(&arr2);
+        let t3 = add(&t1, &t2);
+        assert_eq!(
+            t3.borrow().data,
+            ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![2., 3., 4., 6., 7., 8.]).unwrap()
+        );
+
+        let ones = Array::ones(t3.borrow().data.borrow().raw_dim());
+        t3.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, Array::from_elem(IxDyn(&[2, 1]), 3.));
+        assert_eq!(t2.borrow().grad, Array::ones(IxDyn(&[2, 3])));
+    }
+
+    #[test]
+    fn add_broadcast_rank_mismatch_backward_ok() {
+        // Bias-like case: [batch, n_out] + [n_out]
+        let arr1 = ArrayD::from_shape_vec(IxDyn(&[3, 2]), vec![1., 2., 3., 4., 5., 6.]).unwrap();
+        let arr2 = ArrayD::from_shape_vec(IxDyn(&[2]), vec![0.5, -0.5]).unwrap();
+        let t1 = Tensor::new_ref(&arr1);
+        let t2 = Tensor::new_ref(&arr2);
+        let t3 = add(&t1, &t2);
+        assert_eq!(
+            t3.borrow().data,
+            ArrayD::from_shape_vec(IxDyn(&[3, 2]), vec![1.5, 1.5, 3.5, 3.5, 5.5, 5.5]).unwrap()
+        );
+
+        let ones = Array::ones(t3.borrow().data.borrow().raw_dim());
+        t3.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, Array::ones(IxDyn(&[3, 2])));
+        assert_eq!(t2.borrow().grad, Array::from_elem(IxDyn(&[2]), 3.));
+    }
+
+    #[test]
+    #[should_panic(expected = "Incompatible shapes [2, 3] and [2] in Add op")]
+    fn add_broadcast_incompatible_panics() {
+        let t1 = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[2, 3])));
+        let t2 = Tensor::new_ref(&Array::zeros(IxDyn(&[2])));
+        add(&t1, &t2);
+    }
+
+    #[test]
+    fn try_binary_ops_err() {
+        let t1 = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[2, 3])));
+        let t2 = Tensor::new_ref(&Array::zeros(IxDyn(&[2])));
+        let expected = |op| RustyGradError::ShapeMismatch {
+            op,
+            lhs: vec![2, 3],
+            rhs: vec![2],
+        };
+        assert_eq!(try_add(&t1, &t2).err(), Some(expected("Add")));
+        assert_eq!(try_mul(&t1, &t2).err(), Some(expected("Mul")));
+        assert_eq!(try_diff(&t1, &t2).err(), Some(expected("Add")));
+        assert_eq!(try_div(&t1, &t2).err(), Some(expected("Mul")));
+        assert!(try_add(&t1, &t1).is_ok());
+    }
+
+    #[test]
+    fn backward_invalid_children_err() {
+        // A node built by hand with a wrong number of children
+        let t = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[2])));
+        let mut res = Tensor::new(&Array::zeros(IxDyn(&[2])));
+        res.prev = vec![t];
+        res.backward_fn = Box::new(mul_backward);
+        assert_eq!(
+            res.try_backward_with(Array::ones(IxDyn(&[2]))).err(),
+            Some(RustyGradError::InvalidChildren {
+                op: "Mul",
+                expected: 2,
+                got: 1
+            })
+        );
+    }
+
+    #[test]
+    fn diff_broadcast_backward_ok() {
+        let arr1 = ArrayD::from_shape_vec(IxDyn(&[2]), vec![1., 2.]).unwrap();
+        let arr2 = ArrayD::from_shape_vec(IxDyn(&[3, 2]), vec![1., 2., 3., 4., 5., 6.]).unwrap();
+        let t1 = Tensor::new_ref(&arr1);
+        let t2 = Tensor::new_ref(&arr2);
+        let t3 = diff(&t1, &t2);
+        assert_eq!(
+            t3.borrow().data,
+            ArrayD::from_shape_vec(IxDyn(&[3, 2]), vec![0., 0., -2., -2., -4., -4.]).unwrap()
+        );
+
+        let ones = Array::ones(t3.borrow().data.borrow().raw_dim());
+        t3.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, Array::from_elem(IxDyn(&[2]), 3.));
+        assert_eq!(t2.borrow().grad, Array::from_elem(IxDyn(&[3, 2]), -1.));
+    }
+
     #[test]
     fn diff_ok() {
         let arr1 = ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![1., 2., 3., 4., 5., 6.]).unwrap();
@@ -192,7 +486,8 @@ mod tests {
             ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![7., -3., -7., 1., 7., 5.]).unwrap()
         );
 
-        t3.borrow_mut().backward();
+        let ones = Array::ones(t3.borrow().data.borrow().raw_dim());
+        t3.borrow_mut().backward_with(ones);
         let target_grad = Array::<f32, _>::ones(t1.borrow().grad.raw_dim());
         assert_eq!(t1.borrow().grad, target_grad);
         assert_eq!(t2.borrow().grad, -target_grad);
@@ -223,11 +518,47 @@ mod tests {
             ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![-6., 10., -12., 12., -10., 6.]).unwrap()
         );
 
-        t3.borrow_mut().backward();
+        let ones = Array::ones(t3.borrow().data.borrow().raw_dim());
+        t3.borrow_mut().backward_with(ones);
         assert_eq!(t1.borrow().grad, arr2);
         assert_eq!(t2.borrow().grad, arr1);
     }
 
+    #[test]
+    fn mul_broadcast_backward_ok() {
+        let arr1 = ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![1., 2., 3., 4., 5., 6.]).unwrap();
+        let arr2 = ArrayD::from_shape_vec(IxDyn(&[2, 1]), vec![2., -1.]).unwrap();
+        let t1 = Tensor::new_ref(&arr1);
+        let t2 = Tensor::new_ref(&arr2);
+        let t3 = mul(&t1, &t2);
+        assert_eq!(
+            t3.borrow().data,
+            ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![2., 4., 6., -4., -5., -6.]).unwrap()
+        );
+
+        let ones = Array::ones(t3.borrow().data.borrow().raw_dim());
+        t3.borrow_mut().backward_with(ones);
+        assert_eq!(
+            t1.borrow().grad,
+            ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![2., 2., 2., -1., -1., -1.]).unwrap()
+        );
+        assert_eq!(
+            t2.borrow().grad,
+            ArrayD::from_shape_vec(IxDyn(&[2, 1]), vec![6., 15.]).unwrap()
+        );
+    }
+
+    #[test]
+    fn mul_same_tensor_backward_ok() {
+        let arr = ArrayD::from_shape_vec(IxDyn(&[3]), vec![1., -2., 3.]).unwrap();
+        let t = Tensor::new_ref(&arr);
+        let res = mul(&t, &t);
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, &arr * 2.);
+    }
+
     #[test]
     fn div_ok() {
         let arr1 = ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![1., 2., 9., 10., 1., 6.]).unwrap();
@@ -247,15 +578,16 @@ mod tests {
             ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![0.3, -0.6, 1.2, -0.6, -0.4, 2.2]).unwrap();
         let arr2 =
             ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![0.9, 0.2, -0.4, -0.3, -0.2, 2.2]).unwrap();
-        let t1 = Tensor::new_ref(&arr1);
-        let t2 = Tensor::new_ref(&arr2);
+        let t1 = Tensor::<f32>::new_ref(&arr1);
+        let t2 = Tensor::<f32>::new_ref(&arr2);
         let t3 = div(&t1, &t2);
         assert_eq!(
             t3.borrow().data,
             ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![0.33333337, -3., -3., 2., 2., 1.]).unwrap()
         );
 
-        t3.borrow_mut().backward();
+        let ones = Array::ones(t3.borrow().data.borrow().raw_dim());
+        t3.borrow_mut().backward_with(ones);
         assert_eq!(
             t1.borrow().grad,
             ArrayD::from_shape_vec(
@@ -302,10 +634,58 @@ mod tests {
             ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![1., 4., 16., 9., 25., 36.]).unwrap()
         );
 
-        res.borrow_mut().backward();
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
         assert_eq!(
             t.borrow().grad,
             ArrayD::from_shape_vec(IxDyn(&[2, 3]), vec![2., 4., 8., 6., 10., 12.]).unwrap()
         );
     }
+
+    #[test]
+    fn requires_grad_false_input_backward_ok() {
+        let x = Tensor::new_ref(&ArrayD::from_shape_vec(IxDyn(&[2]), vec![1., 2.]).unwrap());
+        let c = Tensor::new(&ArrayD::from_shape_vec(IxDyn(&[2]), vec![3., 4.]).unwrap())
+            .with_requires_grad(false)
+            .to_ref();
+        let res = mul(&x, &c);
+        assert!(res.borrow().requires_grad);
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(
+            x.borrow().grad,
+            ArrayD::from_shape_vec(IxDyn(&[2]), vec![3., 4.]).unwrap()
+        );
+        // The constant has no gradient buffer
+        assert_eq!(c.borrow().grad.len(), 0);
+    }
+
+    #[test]
+    fn requires_grad_false_inputs_no_graph() {
+        let c1 = Tensor::<f32>::new(&Array::ones(IxDyn(&[2]))).with_requires_grad(false);
+        let c2 = Tensor::new(&Array::ones(IxDyn(&[2]))).with_requires_grad(false);
+        let res = add(&c1.to_ref(), &c2.to_ref());
+        assert!(!res.borrow().requires_grad);
+        assert!(res.borrow().prev.is_empty());
+        assert!(res.borrow_mut().try_backward().is_err());
+    }
+
+    #[test]
+    fn no_grad_ok() {
+        let x = Tensor::new_ref(&Array::ones(IxDyn(&[2])));
+        let res = {
+            let _guard = no_grad();
+            pow(&add(&x, &x), 2.0)
+        };
+        assert_eq!(res.borrow().data, Array::from_elem(IxDyn(&[2]), 4.));
+        assert!(!res.borrow().requires_grad);
+        assert!(res.borrow().prev.is_empty());
+        assert_eq!(res.borrow().grad.len(), 0);
+
+        // The graph is recorded again once the guard is dropped
+        let res = add(&x, &x);
+        assert!(res.borrow().requires_grad);
+        assert_eq!(res.borrow().prev.len(), 2);
+    }
 }
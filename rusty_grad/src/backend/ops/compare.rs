@@ -0,0 +1,276 @@
+use crate::backend::dtype::BoolTensor;
+use crate::backend::float::{indicator, Float};
+use crate::backend::ops::{
+    binary_out_shape, broadcast_shape, check_children, constant, mul, shape_of, sum_to,
+    with_data_pair,
+};
+use crate::backend::tensor::{RTensor, Tensor};
+use crate::error::{unwrap_or_panic, Result, RustyGradError};
+use ndarray::{prelude::*, Zip};
+
+/// Computes `f` for each pair of elements of `a` and `b` broadcasted together
+fn broadcast_map<A: Copy, B: Copy, R>(
+    op: &'static str,
+    a: &ArrayD<A>,
+    b: &ArrayD<B>,
+    f: impl Fn(A, B) -> R,
+) -> Result<ArrayD<R>> {
+    let shape =
+        broadcast_shape(a.shape(), b.shape()).ok_or_else(|| RustyGradError::ShapeMismatch {
+            op,
+            lhs: a.shape().to_vec(),
+            rhs: b.shape().to_vec(),
+        })?;
+    Ok(Zip::from(&a.broadcast(IxDyn(&shape)).unwrap())
+        .and_broadcast(b)
+        .map_collect(|&x, &y| f(x, y)))
+}
+
+/// Compares the elements of `t1` and `t2` with broadcasting. The result is not
+/// part of the graph.
+fn compare_op<T: Float>(
+    op: &'static str,
+    t1: &RTensor<T>,
+    t2: &RTensor<T>,
+    f: fn(T, T) -> bool,
+) -> Result<BoolTensor> {
+    with_data_pair(t1, t2, |d1, d2| broadcast_map(op, d1, d2, f))
+}
+
+/// Fallible version of [`eq`], returns an error if the shapes of `t1` and
+/// `t2` can't be broadcasted together
+pub fn try_eq<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<BoolTensor> {
+    compare_op("Eq", t1, t2, |x1, x2| x1 == x2)
+}
+
+/// Elementwise `t1 == t2`, with broadcasting
+pub fn eq<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> BoolTensor {
+    unwrap_or_panic(try_eq(t1, t2))
+}
+
+/// Fallible version of [`ne`], returns an error if the shapes of `t1` and
+/// `t2` can't be broadcasted together
+pub fn try_ne<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<BoolTensor> {
+    compare_op("Ne", t1, t2, |x1, x2| x1 != x2)
+}
+
+/// Elementwise `t1 != t2`, with broadcasting
+pub fn ne<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> BoolTensor {
+    unwrap_or_panic(try_ne(t1, t2))
+}
+
+/// Fallible version of [`lt`], returns an error if the shapes of `t1` and
+/// `t2` can't be broadcasted together
+pub fn try_lt<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<BoolTensor> {
+    compare_op("Lt", t1, t2, |x1, x2| x1 < x2)
+}
+
+/// Elementwise `t1 < t2`, with broadcasting
+pub fn lt<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> BoolTensor {
+    unwrap_or_panic(try_lt(t1, t2))
+}
+
+/// Fallible version of [`le`], returns an error if the shapes of `t1` and
+/// `t2` can't be broadcasted together
+pub fn try_le<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<BoolTensor> {
+    compare_op("Le", t1, t2, |x1, x2| x1 <= x2)
+}
+
+/// Elementwise `t1 <= t2`, with broadcasting
+pub fn le<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> BoolTensor {
+    unwrap_or_panic(try_le(t1, t2))
+}
+
+/// Fallible version of [`gt`], returns an error if the shapes of `t1` and
+/// `t2` can't be broadcasted together
+pub fn try_gt<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<BoolTensor> {
+    compare_op("Gt", t1, t2, |x1, x2| x1 > x2)
+}
+
+/// Elementwise `t1 > t2`, with broadcasting
+pub fn gt<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> BoolTensor {
+    unwrap_or_panic(try_gt(t1, t2))
+}
+
+/// Fallible version of [`ge`], returns an error if the shapes of `t1` and
+/// `t2` can't be broadcasted together
+pub fn try_ge<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<BoolTensor> {
+    compare_op("Ge", t1, t2, |x1, x2| x1 >= x2)
+}
+
+/// Elementwise `t1 >= t2`, with broadcasting
+pub fn ge<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> BoolTensor {
+    unwrap_or_panic(try_ge(t1, t2))
+}
+
+/// Fallible version of [`logical_and`], returns an error if the shapes of
+/// `m1` and `m2` can't be broadcasted together
+pub fn try_logical_and(m1: &BoolTensor, m2: &BoolTensor) -> Result<BoolTensor> {
+    broadcast_map("LogicalAnd", m1, m2, |x1, x2| x1 && x2)
+}
+
+/// Elementwise `m1 && m2`, with broadcasting
+pub fn logical_and(m1: &BoolTensor, m2: &BoolTensor) -> BoolTensor {
+    unwrap_or_panic(try_logical_and(m1, m2))
+}
+
+/// Fallible version of [`logical_or`], returns an error if the shapes of `m1`
+/// and `m2` can't be broadcasted together
+pub fn try_logical_or(m1: &BoolTensor, m2: &BoolTensor) -> Result<BoolTensor> {
+    broadcast_map("LogicalOr", m1, m2, |x1, x2| x1 || x2)
+}
+
+/// Elementwise `m1 || m2`, with broadcasting
+pub fn logical_or(m1: &BoolTensor, m2: &BoolTensor) -> BoolTensor {
+    unwrap_or_panic(try_logical_or(m1, m2))
+}
+
+/// Fallible version of [`logical_xor`], returns an error if the shapes of
+/// `m1` and `m2` can't be broadcasted together
+pub fn try_logical_xor(m1: &BoolTensor, m2: &BoolTensor) -> Result<BoolTensor> {
+    broadcast_map("LogicalXor", m1, m2, |x1, x2| x1 != x2)
+}
+
+/// Elementwise `m1 ^ m2`, with broadcasting
+pub fn logical_xor(m1: &BoolTensor, m2: &BoolTensor) -> BoolTensor {
+    unwrap_or_panic(try_logical_xor(m1, m2))
+}
+
+/// Elementwise `!m`
+pub fn logical_not(m: &BoolTensor) -> BoolTensor {
+    m.mapv(|x| !x)
+}
+
+/// Fallible version of [`where_`], returns an error if the shapes of `mask`,
+/// `t1` and `t2` can't be broadcasted together
+pub fn try_where_<T: Float>(
+    mask: &BoolTensor,
+    t1: &RTensor<T>,
+    t2: &RTensor<T>,
+) -> Result<RTensor<T>> {
+    let shape = with_data_pair(t1, t2, |d1, d2| binary_out_shape("Where", d1, d2))?;
+    let out_shape =
+        broadcast_shape(mask.shape(), &shape).ok_or_else(|| RustyGradError::ShapeMismatch {
+            op: "Where",
+            lhs: mask.shape().to_vec(),
+            rhs: shape,
+        })?;
+    let mask = mask.broadcast(IxDyn(&out_shape)).unwrap();
+    let data = with_data_pair(t1, t2, |d1, d2| {
+        Zip::from(&mask)
+            .and_broadcast(d1)
+            .and_broadcast(d2)
+            .map_collect(|&m, &x1, &x2| if m { x1 } else { x2 })
+    });
+    // Weights of the gradient that goes to `t1` and `t2`
+    let w1 = mask.mapv(indicator::<T>);
+    let w2 = mask.mapv(|m| indicator::<T>(!m));
+    Ok(Tensor::from_op(
+        data,
+        vec![t1.clone(), t2.clone()],
+        move |t, grad| {
+            check_children("Where", t, 2)?;
+            let (t1, t2) = (&t.prev[0], &t.prev[1]);
+            Ok(vec![
+                sum_to(&mul(grad, &constant(w1.clone())), &shape_of(t1)),
+                sum_to(&mul(grad, &constant(w2.clone())), &shape_of(t2)),
+            ])
+        },
+    ))
+}
+
+/// Selects the elements of `t1` where `mask` is true and the elements of `t2`
+/// elsewhere, with broadcasting. It is differentiable in `t1` and `t2`: each
+/// of them gets the upstream gradient of the elements selected from it.
+pub fn where_<T: Float>(mask: &BoolTensor, t1: &RTensor<T>, t2: &RTensor<T>) -> RTensor<T> {
+    unwrap_or_panic(try_where_(mask, t1, t2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparisons_ok() {
+        let t1 = Tensor::new_ref(&array![[1., 2., 3.], [3., 2., 1.]].into_dyn());
+        let t2 = Tensor::new_ref(&array![2., 2., 2.].into_dyn());
+        let m = |rows: [[bool; 3]; 2]| arr2(&rows).into_dyn();
+        assert_eq!(
+            eq(&t1, &t2),
+            m([[false, true, false], [false, true, false]])
+        );
+        assert_eq!(ne(&t1, &t2), m([[true, false, true], [true, false, true]]));
+        assert_eq!(
+            lt(&t1, &t2),
+            m([[true, false, false], [false, false, true]])
+        );
+        assert_eq!(le(&t1, &t2), m([[true, true, false], [false, true, true]]));
+        assert_eq!(
+            gt(&t1, &t2),
+            m([[false, false, true], [true, false, false]])
+        );
+        assert_eq!(ge(&t1, &t2), m([[false, true, true], [true, true, false]]));
+    }
+
+    #[test]
+    fn comparison_shape_err() {
+        let t1 = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[2, 3])));
+        let t2 = Tensor::new_ref(&Array::zeros(IxDyn(&[2])));
+        assert_eq!(
+            try_lt(&t1, &t2),
+            Err(RustyGradError::ShapeMismatch {
+                op: "Lt",
+                lhs: vec![2, 3],
+                rhs: vec![2],
+            })
+        );
+    }
+
+    #[test]
+    fn logical_ops_ok() {
+        let m1 = array![[true, false], [true, false]].into_dyn();
+        let m2 = array![true, true].into_dyn();
+        assert_eq!(
+            logical_and(&m1, &m2),
+            array![[true, false], [true, false]].into_dyn()
+        );
+        assert_eq!(
+            logical_or(&m1, &logical_not(&m2)),
+            array![[true, false], [true, false]].into_dyn()
+        );
+        assert_eq!(
+            logical_xor(&m1, &m2),
+            array![[false, true], [false, true]].into_dyn()
+        );
+        assert!(try_logical_or(&m1, &array![true, true, false].into_dyn()).is_err());
+    }
+
+    #[test]
+    fn where_backward_ok() {
+        let x = Tensor::new_ref(&array![[-1., 2.], [3., -4.]].into_dyn());
+        let zero = Tensor::new_ref(&arr0(0.).into_dyn());
+        // `relu` written with a mask, the scalar is broadcasted
+        let mask = gt(&x, &zero);
+        let res = where_(&mask, &x, &zero);
+        assert_eq!(res.borrow().data, array![[0., 2.], [3., 0.]].into_dyn());
+
+        res.borrow_mut()
+            .backward_with(array![[1., 2.], [3., 4.]].into_dyn());
+        assert_eq!(x.borrow().grad, array![[0., 2.], [3., 0.]].into_dyn());
+        assert_eq!(zero.borrow().grad, arr0(5.).into_dyn());
+    }
+
+    #[test]
+    fn where_shape_err() {
+        let t = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[2, 3])));
+        let mask = Array::from_elem(IxDyn(&[2]), true);
+        assert_eq!(
+            try_where_(&mask, &t, &t).err(),
+            Some(RustyGradError::ShapeMismatch {
+                op: "Where",
+                lhs: vec![2],
+                rhs: vec![2, 3],
+            })
+        );
+    }
+}
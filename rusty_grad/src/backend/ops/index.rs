@@ -0,0 +1,485 @@
+use crate::backend::float::Float;
+use crate::backend::ops::{check_axis, check_children, squeeze, with_data};
+use crate::backend::tensor::{RTensor, Tensor};
+use crate::error::{invalid_argument, unwrap_or_panic, Result, RustyGradError};
+use ndarray::{concatenate, prelude::*, stack as stack_arrays, Slice};
+
+/// Returns an error if the possibly negative `index` (counting from the end)
+/// is not a position in `0..=len`
+fn check_index(op: &'static str, index: isize, len: usize) -> Result<()> {
+    let abs = if index < 0 {
+        len as isize + index
+    } else {
+        index
+    };
+    if abs < 0 || abs > len as isize {
+        return Err(RustyGradError::IndexOutOfBounds {
+            op,
+            index,
+            size: len,
+        });
+    }
+    Ok(())
+}
+
+/// Fallible version of [`slice`], returns an error if there are more slices
+/// than axes, a step is 0 or a bound is out of the axis
+pub fn try_slice<T: Float>(t: &RTensor<T>, slices: &[Slice]) -> Result<RTensor<T>> {
+    let in_shape = t.borrow().data.borrow().shape().to_vec();
+    if slices.len() > in_shape.len() {
+        return Err(RustyGradError::RankMismatch {
+            op: "Slice",
+            expected: slices.len(),
+            got: in_shape.len(),
+        });
+    }
+    let mut full_slices = vec![Slice::from(..); in_shape.len()];
+    for (i, s) in slices.iter().enumerate() {
+        if s.step == 0 {
+            return invalid_argument(
+                "Slice",
+                format!("the step of the slice for axis {} can't be 0", i),
+            );
+        }
+        // Validate the bounds to get an error instead of an ndarray panic
+        check_index("Slice", s.start, in_shape[i])?;
+        if let Some(end) = s.end {
+            check_index("Slice", end, in_shape[i])?;
+        }
+        full_slices[i] = *s;
+    }
+    let data = t
+        .borrow()
+        .data
+        .borrow()
+        .slice_each_axis(|ax| full_slices[ax.axis.index()])
+        .to_owned();
+    Ok(Tensor::from_op(data, vec![t.clone()], move |t, grad| {
+        check_children("Slice", t, 1)?;
+        Ok(vec![slice_scatter(grad, &in_shape, &full_slices)])
+    }))
+}
+
+/// Selects a region of `t`. Each element of `slices` is the `Slice` (start,
+/// end and step, with negative indices counting from the end) applied to the
+/// corresponding axis. The axes without a slice are fully selected.
+pub fn slice<T: Float>(t: &RTensor<T>, slices: &[Slice]) -> RTensor<T> {
+    unwrap_or_panic(try_slice(t, slices))
+}
+
+/// Fallible version of [`index_select`], returns an error if `axis` is not
+/// valid or any of the indices is out of bounds
+pub fn try_index_select<T: Float>(
+    t: &RTensor<T>,
+    axis: usize,
+    indices: &[usize],
+) -> Result<RTensor<T>> {
+    let in_shape = t.borrow().data.borrow().shape().to_vec();
+    check_axis("IndexSelect", axis, in_shape.len())?;
+    if let Some(&index) = indices.iter().find(|&&i| i >= in_shape[axis]) {
+        return Err(RustyGradError::IndexOutOfBounds {
+            op: "IndexSelect",
+            index: index as isize,
+            size: in_shape[axis],
+        });
+    }
+    let data = t.borrow().data.borrow().select(Axis(axis), indices);
+    let indices = indices.to_vec();
+    Ok(Tensor::from_op(data, vec![t.clone()], move |t, grad| {
+        check_children("IndexSelect", t, 1)?;
+        Ok(vec![index_add(grad, &in_shape, axis, &indices)])
+    }))
+}
+
+/// Scatters `t` into the region selected by `slices` of a tensor of zeros with
+/// the given `shape`. It is the backward of `slice`, and its backward is
+/// `slice` again.
+fn slice_scatter<T: Float>(t: &RTensor<T>, shape: &[usize], slices: &[Slice]) -> RTensor<T> {
+    let mut data = Array::zeros(IxDyn(shape));
+    data.slice_each_axis_mut(|ax| slices[ax.axis.index()])
+        .assign(&t.borrow().data.borrow());
+    let slices = slices.to_vec();
+    Tensor::from_op(data, vec![t.clone()], move |t, grad| {
+        check_children("SliceScatter", t, 1)?;
+        Ok(vec![slice(grad, &slices)])
+    })
+}
+
+/// Adds each slice `i` of `t` along `axis` to the slice `indices[i]` of a
+/// tensor of zeros with the given `shape`. It is the backward of
+/// `index_select`, and its backward is `index_select` again.
+fn index_add<T: Float>(
+    t: &RTensor<T>,
+    shape: &[usize],
+    axis: usize,
+    indices: &[usize],
+) -> RTensor<T> {
+    let mut data = Array::zeros(IxDyn(shape));
+    for (i, &index) in indices.iter().enumerate() {
+        let mut dst = data.index_axis_mut(Axis(axis), index);
+        dst += &t.borrow().data.borrow().index_axis(Axis(axis), i);
+    }
+    let indices = indices.to_vec();
+    Tensor::from_op(data, vec![t.clone()], move |t, grad| {
+        check_children("IndexAdd", t, 1)?;
+        Ok(vec![index_select(grad, axis, &indices)])
+    })
+}
+
+/// Selects the elements of `t` at `indices` along `axis`. Indices can be
+/// repeated, in that case the gradients of the repeated elements are added.
+pub fn index_select<T: Float>(t: &RTensor<T>, axis: usize, indices: &[usize]) -> RTensor<T> {
+    unwrap_or_panic(try_index_select(t, axis, indices))
+}
+
+/// Fallible version of [`concat`], returns an error if `ts` is empty, `axis`
+/// is not valid or the shapes of the tensors are not compatible
+pub fn try_concat<T: Float>(ts: &[RTensor<T>], axis: usize) -> Result<RTensor<T>> {
+    if ts.is_empty() {
+        return invalid_argument("Concat", "at least one tensor is needed".to_string());
+    }
+    let first_shape = ts[0].borrow().data.borrow().shape().to_vec();
+    check_axis("Concat", axis, first_shape.len())?;
+    let mut sizes = vec![];
+    for t in ts {
+        let shape = t.borrow().data.borrow().shape().to_vec();
+        let compatible = shape.len() == first_shape.len()
+            && (0..shape.len()).all(|i| i == axis || shape[i] == first_shape[i]);
+        if !compatible {
+            return Err(RustyGradError::ShapeMismatch {
+                op: "Concat",
+                lhs: first_shape,
+                rhs: shape,
+            });
+        }
+        sizes.push(shape[axis]);
+    }
+    let data = with_data(&ts.iter().collect::<Vec<_>>(), |ds| {
+        let views: Vec<_> = ds.iter().map(|d| d.view()).collect();
+        concatenate(Axis(axis), &views).unwrap()
+    });
+    Ok(Tensor::from_op(data, ts.to_vec(), move |t, grad| {
+        check_children("Concat", t, sizes.len())?;
+        // Each child gets its own region of the gradient along `axis`
+        let mut offset = 0;
+        let mut grads = vec![];
+        for &size in sizes.iter() {
+            let mut slices = vec![Slice::from(..); axis + 1];
+            slices[axis] = Slice::from(offset..offset + size);
+            grads.push(slice(grad, &slices));
+            offset += size;
+        }
+        Ok(grads)
+    }))
+}
+
+/// Joins the tensors `ts` along the existing `axis`. All the tensors must have
+/// the same shape except in the concatenation axis.
+pub fn concat<T: Float>(ts: &[RTensor<T>], axis: usize) -> RTensor<T> {
+    unwrap_or_panic(try_concat(ts, axis))
+}
+
+/// Fallible version of [`stack`], returns an error if `ts` is empty, `axis`
+/// is not valid or the tensors don't have the same shape
+pub fn try_stack<T: Float>(ts: &[RTensor<T>], axis: usize) -> Result<RTensor<T>> {
+    if ts.is_empty() {
+        return invalid_argument("Stack", "at least one tensor is needed".to_string());
+    }
+    let first_shape = ts[0].borrow().data.borrow().shape().to_vec();
+    check_axis("Stack", axis, first_shape.len() + 1)?;
+    for t in ts {
+        if t.borrow().data.borrow().shape() != first_shape {
+            return Err(RustyGradError::ShapeMismatch {
+                op: "Stack",
+                lhs: first_shape,
+                rhs: t.borrow().data.borrow().shape().to_vec(),
+            });
+        }
+    }
+    let data = with_data(&ts.iter().collect::<Vec<_>>(), |ds| {
+        let views: Vec<_> = ds.iter().map(|d| d.view()).collect();
+        stack_arrays(Axis(axis), &views).unwrap()
+    });
+    let n_inputs = ts.len();
+    Ok(Tensor::from_op(data, ts.to_vec(), move |t, grad| {
+        check_children("Stack", t, n_inputs)?;
+        Ok((0..n_inputs)
+            .map(|i| squeeze(&index_select(grad, axis, &[i]), Some(&[axis])))
+            .collect())
+    }))
+}
+
+/// Joins the tensors `ts` along a new axis inserted at position `axis`. All
+/// the tensors must have the same shape.
+pub fn stack<T: Float>(ts: &[RTensor<T>], axis: usize) -> RTensor<T> {
+    unwrap_or_panic(try_stack(ts, axis))
+}
+
+/// Fallible version of [`split`], returns an error if `axis` is not valid or
+/// the sizes don't add up to the size of the axis
+pub fn try_split<T: Float>(
+    t: &RTensor<T>,
+    sizes: &[usize],
+    axis: usize,
+) -> Result<Vec<RTensor<T>>> {
+    let ndim = t.borrow().data.borrow().ndim();
+    check_axis("Split", axis, ndim)?;
+    let axis_size = t.borrow().data.borrow().shape()[axis];
+    if sizes.iter().sum::<usize>() != axis_size {
+        return invalid_argument(
+            "Split",
+            format!(
+                "the sizes {:?} don't add up to the size {} of axis {}",
+                sizes, axis_size, axis
+            ),
+        );
+    }
+    let mut offset = 0;
+    sizes
+        .iter()
+        .map(|&size| {
+            let mut slices = vec![Slice::from(..); axis + 1];
+            slices[axis] = Slice::from(offset..offset + size);
+            offset += size;
+            try_slice(t, &slices)
+        })
+        .collect()
+}
+
+/// Splits `t` along `axis` in consecutive parts with the given `sizes`, that
+/// must add up to the size of the axis
+pub fn split<T: Float>(t: &RTensor<T>, sizes: &[usize], axis: usize) -> Vec<RTensor<T>> {
+    unwrap_or_panic(try_split(t, sizes, axis))
+}
+
+/// Fallible version of [`chunk`], returns an error if `n_chunks` is 0 or
+/// `axis` is not valid
+pub fn try_chunk<T: Float>(
+    t: &RTensor<T>,
+    n_chunks: usize,
+    axis: usize,
+) -> Result<Vec<RTensor<T>>> {
+    if n_chunks == 0 {
+        return invalid_argument("Chunk", "the number of chunks can't be 0".to_string());
+    }
+    let ndim = t.borrow().data.borrow().ndim();
+    check_axis("Chunk", axis, ndim)?;
+    let axis_size = t.borrow().data.borrow().shape()[axis];
+    let chunk_size = axis_size.div_ceil(n_chunks);
+    let mut sizes = vec![chunk_size; axis_size / chunk_size.max(1)];
+    let remainder = axis_size % chunk_size.max(1);
+    if remainder > 0 {
+        sizes.push(remainder);
+    }
+    try_split(t, &sizes, axis)
+}
+
+/// Splits `t` along `axis` in `n_chunks` parts of the same size. If the size
+/// of the axis is not divisible by `n_chunks` the last chunk is smaller, and
+/// fewer chunks may be returned (as in `torch.chunk`).
+pub fn chunk<T: Float>(t: &RTensor<T>, n_chunks: usize, axis: usize) -> Vec<RTensor<T>> {
+    unwrap_or_panic(try_chunk(t, n_chunks, axis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ops::mul;
+
+    fn arr(shape: &[usize], data: Vec<f32>) -> ArrayD<f32> {
+        ArrayD::from_shape_vec(IxDyn(shape), data).unwrap()
+    }
+
+    fn range_tensor(shape: &[usize]) -> RTensor {
+        let n = shape.iter().product::<usize>();
+        Tensor::new_ref(&arr(shape, (0..n).map(|x| x as f32).collect()))
+    }
+
+    #[test]
+    fn slice_ok() {
+        let t = range_tensor(&[3, 4]);
+        let res = slice(&t, &[Slice::from(1..), Slice::from(..).step_by(2)]);
+        assert_eq!(res.borrow().data, arr(&[2, 2], vec![4., 6., 8., 10.]));
+
+        let res = slice(&t, &[Slice::from(-1..)]);
+        assert_eq!(res.borrow().data, arr(&[1, 4], vec![8., 9., 10., 11.]));
+
+        let res = slice(&t, &[Slice::from(..), Slice::new(-1, None, -1)]);
+        assert_eq!(res.borrow().data, arr(&[3, 1], vec![3., 7., 11.]));
+    }
+
+    #[test]
+    fn slice_backward_ok() {
+        let t = range_tensor(&[3, 4]);
+        let res = slice(&t, &[Slice::from(0..2), Slice::new(1, None, 2)]);
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(
+            t.borrow().grad,
+            arr(
+                &[3, 4],
+                vec![0., 1., 0., 1., 0., 1., 0., 1., 0., 0., 0., 0.]
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Index 5 is out of bounds for an axis of size 4 in Slice op")]
+    fn slice_out_of_bounds_panics() {
+        let t = range_tensor(&[3, 4]);
+        slice(&t, &[Slice::from(..), Slice::from(0..5)]);
+    }
+
+    #[test]
+    fn index_select_backward_ok() {
+        let t = range_tensor(&[3, 2]);
+        let res = index_select(&t, 0, &[2, 0, 2]);
+        assert_eq!(
+            res.borrow().data,
+            arr(&[3, 2], vec![4., 5., 0., 1., 4., 5.])
+        );
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr(&[3, 2], vec![1., 1., 0., 0., 2., 2.]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Index 3 is out of bounds for an axis of size 2 in IndexSelect op")]
+    fn index_select_out_of_bounds_panics() {
+        let t = range_tensor(&[3, 2]);
+        index_select(&t, 1, &[3]);
+    }
+
+    #[test]
+    fn concat_backward_ok() {
+        let t1 = range_tensor(&[2, 1]);
+        let t2 = range_tensor(&[2, 2]);
+        let res = concat(&[t1.clone(), t2.clone()], 1);
+        assert_eq!(
+            res.borrow().data,
+            arr(&[2, 3], vec![0., 0., 1., 1., 2., 3.])
+        );
+
+        let weights = Tensor::new_ref(&arr(&[2, 3], vec![1., 2., 3., 4., 5., 6.]));
+        let out = mul(&res, &weights);
+        let ones = Array::ones(out.borrow().data.borrow().raw_dim());
+        out.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, arr(&[2, 1], vec![1., 4.]));
+        assert_eq!(t2.borrow().grad, arr(&[2, 2], vec![2., 3., 5., 6.]));
+    }
+
+    #[test]
+    fn concat_same_tensor_backward_ok() {
+        let t = range_tensor(&[2]);
+        let res = concat(&[t.clone(), t.clone()], 0);
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr(&[2], vec![2., 2.]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Incompatible shapes [2, 1] and [3, 1] in Concat op")]
+    fn concat_invalid_shapes_panics() {
+        concat(&[range_tensor(&[2, 1]), range_tensor(&[3, 1])], 1);
+    }
+
+    #[test]
+    fn stack_backward_ok() {
+        let t1 = range_tensor(&[2]);
+        let t2 = range_tensor(&[2]);
+        let res = stack(&[t1.clone(), t2.clone()], 1);
+        assert_eq!(res.borrow().data, arr(&[2, 2], vec![0., 0., 1., 1.]));
+
+        let weights = Tensor::new_ref(&arr(&[2, 2], vec![1., 2., 3., 4.]));
+        let out = mul(&res, &weights);
+        let ones = Array::ones(out.borrow().data.borrow().raw_dim());
+        out.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, arr(&[2], vec![1., 3.]));
+        assert_eq!(t2.borrow().grad, arr(&[2], vec![2., 4.]));
+    }
+
+    #[test]
+    fn split_backward_ok() {
+        let t = range_tensor(&[5, 2]);
+        let parts = split(&t, &[1, 4], 0);
+        assert_eq!(parts[0].borrow().data, arr(&[1, 2], vec![0., 1.]));
+        assert_eq!(parts[1].borrow().data.borrow().shape(), &[4, 2]);
+
+        let ones = Array::ones(parts[0].borrow().data.borrow().raw_dim());
+        parts[0].borrow_mut().backward_with(ones);
+        assert_eq!(
+            t.borrow().grad,
+            arr(&[5, 2], vec![1., 1., 0., 0., 0., 0., 0., 0., 0., 0.])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "don't add up to the size 5")]
+    fn split_invalid_sizes_panics() {
+        split(&range_tensor(&[5, 2]), &[1, 2], 0);
+    }
+
+    #[test]
+    fn chunk_ok() {
+        let t = range_tensor(&[2, 5]);
+        let sizes = |chunks: Vec<RTensor>| -> Vec<usize> {
+            chunks
+                .iter()
+                .map(|c| c.borrow().data.borrow().shape()[1])
+                .collect()
+        };
+        assert_eq!(sizes(chunk(&t, 2, 1)), vec![3, 2]);
+        assert_eq!(sizes(chunk(&t, 5, 1)), vec![1, 1, 1, 1, 1]);
+        assert_eq!(sizes(chunk(&t, 4, 1)), vec![2, 2, 1]);
+        assert_eq!(sizes(chunk(&t, 1, 1)), vec![5]);
+    }
+
+    #[test]
+    fn try_index_ops_err() {
+        let t = range_tensor(&[3, 4]);
+        assert_eq!(
+            try_slice(&t, &[Slice::from(..); 3]).err(),
+            Some(RustyGradError::RankMismatch {
+                op: "Slice",
+                expected: 3,
+                got: 2
+            })
+        );
+        assert!(matches!(
+            try_slice(
+                &t,
+                &[Slice {
+                    start: 0,
+                    end: None,
+                    step: 0
+                }]
+            ),
+            Err(RustyGradError::InvalidArgument { op: "Slice", .. })
+        ));
+        assert_eq!(
+            try_slice(&t, &[Slice::from(-4..)]).err(),
+            Some(RustyGradError::IndexOutOfBounds {
+                op: "Slice",
+                index: -4,
+                size: 3
+            })
+        );
+        assert!(matches!(
+            try_concat::<f32>(&[], 0),
+            Err(RustyGradError::InvalidArgument { op: "Concat", .. })
+        ));
+        assert!(matches!(
+            try_stack(&[t.clone(), range_tensor(&[4, 3])], 0),
+            Err(RustyGradError::ShapeMismatch { op: "Stack", .. })
+        ));
+        assert!(matches!(
+            try_chunk(&t, 0, 0),
+            Err(RustyGradError::InvalidArgument { op: "Chunk", .. })
+        ));
+        assert!(matches!(
+            try_split(&t, &[2], 2),
+            Err(RustyGradError::InvalidAxis { op: "Split", .. })
+        ));
+    }
+}
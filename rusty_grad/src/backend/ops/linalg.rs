@@ -0,0 +1,295 @@
+use crate::backend::float::Float;
+use crate::backend::ops::{
+    broadcast_shape, check_children, reshape, shape_of, sum_to, transpose, unsqueeze,
+};
+use crate::backend::tensor::{RTensor, Tensor};
+use crate::error::{unwrap_or_panic, Result, RustyGradError};
+use ndarray::{linalg::general_mat_mul, prelude::*};
+
+/// Matrix product of two arrays with at least 2 dimensions. The last two axes
+/// are the matrices and the leading axes are batch axes, that are broadcasted.
+/// Returns `None` if the shapes are not compatible.
+fn batched_matmul<T: Float>(a: &ArrayD<T>, b: &ArrayD<T>) -> Option<ArrayD<T>> {
+    let (a_batch, a_mat) = a.shape().split_at(a.ndim() - 2);
+    let (b_batch, b_mat) = b.shape().split_at(b.ndim() - 2);
+    let (n, k, m) = (a_mat[0], a_mat[1], b_mat[1]);
+    if k != b_mat[0] {
+        return None;
+    }
+    let batch = broadcast_shape(a_batch, b_batch)?;
+    let batch_size = batch.iter().product::<usize>();
+
+    // Broadcast the batch axes and flatten them into a single one
+    let flatten = |x: &ArrayD<T>, rows: usize, cols: usize| -> Array3<T> {
+        let shape = [batch.as_slice(), &[rows, cols]].concat();
+        x.broadcast(IxDyn(&shape))
+            .unwrap()
+            .as_standard_layout()
+            .into_owned()
+            .into_shape((batch_size, rows, cols))
+            .unwrap()
+    };
+    let a3 = flatten(a, n, k);
+    let b3 = flatten(b, k, m);
+
+    let mut out = Array3::<T>::zeros((batch_size, n, m));
+    for (i, mut out_mat) in out.outer_iter_mut().enumerate() {
+        general_mat_mul(
+            T::one(),
+            &a3.index_axis(Axis(0), i),
+            &b3.index_axis(Axis(0), i),
+            T::zero(),
+            &mut out_mat,
+        );
+    }
+    Some(
+        out.into_shape(IxDyn(&[batch.as_slice(), &[n, m]].concat()))
+            .unwrap(),
+    )
+}
+
+/// Fallible version of [`matmul`], returns an error if any of the tensors is
+/// 0-D or their shapes are not compatible
+pub fn try_matmul<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<RTensor<T>> {
+    let (a_shape, b_shape) = (shape_of(t1), shape_of(t2));
+    if a_shape.is_empty() || b_shape.is_empty() {
+        return Err(RustyGradError::RankMismatch {
+            op: "MatMul",
+            expected: 1,
+            got: 0,
+        });
+    }
+    // Promote the 1-D operands to matrices
+    let a = promote(&t1.borrow().data.borrow(), 0);
+    let b = promote(&t2.borrow().data.borrow(), 1);
+    let out = batched_matmul(&a, &b).ok_or(RustyGradError::ShapeMismatch {
+        op: "MatMul",
+        lhs: a_shape.clone(),
+        rhs: b_shape.clone(),
+    })?;
+    // Remove the axes added to the 1-D operands
+    let mut out_shape = out.shape().to_vec();
+    if b_shape.len() == 1 {
+        out_shape.remove(out_shape.len() - 1);
+    }
+    if a_shape.len() == 1 {
+        out_shape.remove(out_shape.len() - 1 - (b_shape.len() != 1) as usize);
+    }
+    let promoted_out_shape = out.shape().to_vec();
+    let data = out.into_shape(IxDyn(&out_shape)).unwrap();
+    Ok(Tensor::from_op(
+        data,
+        vec![t1.clone(), t2.clone()],
+        move |t, grad| {
+            check_children("MatMul", t, 2)?;
+            let (t1, t2) = (&t.prev[0], &t.prev[1]);
+            // Work with the promoted operands, so both are batches of matrices
+            let a = promote_tensor(t1, 0);
+            let b = promote_tensor(t2, 1);
+            let grad = reshape(grad, &promoted_out_shape);
+            let da = matmul(&grad, &transpose_last(&b));
+            let db = matmul(&transpose_last(&a), &grad);
+            // Sum over the broadcasted batch axes and remove the promoted axes
+            Ok(vec![
+                reshape(&sum_to(&da, &shape_of(&a)), &shape_of(t1)),
+                reshape(&sum_to(&db, &shape_of(&b)), &shape_of(t2)),
+            ])
+        },
+    ))
+}
+
+/// Matrix product of `t1` and `t2` following the semantics of NumPy's `matmul`:
+///
+/// - If both tensors are 2-D it is the regular matrix product.
+/// - If a tensor is 1-D it is promoted to a matrix by adding an axis of size 1
+///   (prepended for `t1`, appended for `t2`) that is removed from the result.
+/// - If a tensor has more than 2 dimensions it is treated as a batch of
+///   matrices in the last two axes, and the batch axes are broadcasted.
+pub fn matmul<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> RTensor<T> {
+    unwrap_or_panic(try_matmul(t1, t2))
+}
+
+/// Returns `a` as an array of at least 2 dimensions, inserting an axis of size
+/// 1 at `axis` if it is 1-D
+fn promote<T: Float>(a: &ArrayD<T>, axis: usize) -> ArrayD<T> {
+    if a.ndim() == 1 {
+        a.clone().insert_axis(Axis(axis))
+    } else {
+        a.clone()
+    }
+}
+
+/// Returns `t` with at least 2 dimensions, inserting an axis of size 1 at
+/// `axis` if it is 1-D
+fn promote_tensor<T: Float>(t: &RTensor<T>, axis: usize) -> RTensor<T> {
+    if t.borrow().data.borrow().ndim() == 1 {
+        unsqueeze(t, axis)
+    } else {
+        t.clone()
+    }
+}
+
+/// Swaps the last two axes of `t`
+fn transpose_last<T: Float>(t: &RTensor<T>) -> RTensor<T> {
+    let ndim = t.borrow().data.borrow().ndim();
+    transpose(t, ndim - 2, ndim - 1)
+}
+
+/// Fallible version of [`dot`], returns an error if any of the tensors is 0-D
+/// or their shapes are not compatible
+pub fn try_dot<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<RTensor<T>> {
+    try_matmul(t1, t2)
+}
+
+/// Dot product of `t1` and `t2`. It is an alias of `matmul`, so it is the
+/// matrix product for 2-D tensors and the inner product for 1-D tensors.
+pub fn dot<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> RTensor<T> {
+    matmul(t1, t2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arr(shape: &[usize], data: Vec<f32>) -> ArrayD<f32> {
+        ArrayD::from_shape_vec(IxDyn(shape), data).unwrap()
+    }
+
+    fn range_tensor(shape: &[usize]) -> RTensor {
+        let n = shape.iter().product::<usize>();
+        Tensor::new_ref(&arr(shape, (1..=n).map(|x| x as f32).collect()))
+    }
+
+    #[test]
+    fn matmul_2d_backward_ok() {
+        let t1 = range_tensor(&[2, 3]);
+        let t2 = range_tensor(&[3, 2]);
+        let res = matmul(&t1, &t2);
+        assert_eq!(res.borrow().data, arr(&[2, 2], vec![22., 28., 49., 64.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(
+            t1.borrow().grad,
+            arr(&[2, 3], vec![3., 7., 11., 3., 7., 11.])
+        );
+        assert_eq!(t2.borrow().grad, arr(&[3, 2], vec![5., 5., 7., 7., 9., 9.]));
+    }
+
+    #[test]
+    fn dot_1d_backward_ok() {
+        let t1 = Tensor::new_ref(&arr(&[3], vec![1., 2., 3.]));
+        let t2 = Tensor::new_ref(&arr(&[3], vec![4., 5., 6.]));
+        let res = dot(&t1, &t2);
+        assert_eq!(res.borrow().data, arr0(32.).into_dyn());
+
+        res.borrow_mut().backward();
+        assert_eq!(t1.borrow().grad, arr(&[3], vec![4., 5., 6.]));
+        assert_eq!(t2.borrow().grad, arr(&[3], vec![1., 2., 3.]));
+    }
+
+    #[test]
+    fn matmul_vector_matrix_backward_ok() {
+        let t1 = Tensor::new_ref(&arr(&[3], vec![1., 2., 3.]));
+        let t2 = range_tensor(&[3, 2]);
+        let res = matmul(&t1, &t2);
+        assert_eq!(res.borrow().data, arr(&[2], vec![22., 28.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, arr(&[3], vec![3., 7., 11.]));
+        assert_eq!(t2.borrow().grad, arr(&[3, 2], vec![1., 1., 2., 2., 3., 3.]));
+    }
+
+    #[test]
+    fn matmul_matrix_vector_backward_ok() {
+        let t1 = range_tensor(&[2, 3]);
+        let t2 = Tensor::new_ref(&arr(&[3], vec![1., 1., 1.]));
+        let res = matmul(&t1, &t2);
+        assert_eq!(res.borrow().data, arr(&[2], vec![6., 15.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, Array::ones(IxDyn(&[2, 3])));
+        assert_eq!(t2.borrow().grad, arr(&[3], vec![5., 7., 9.]));
+    }
+
+    #[test]
+    fn matmul_batched_backward_ok() {
+        let t1 = range_tensor(&[2, 2, 3]);
+        let t2 = range_tensor(&[3, 2]);
+        let res = matmul(&t1, &t2);
+        assert_eq!(
+            res.borrow().data,
+            arr(&[2, 2, 2], vec![22., 28., 49., 64., 76., 100., 103., 136.])
+        );
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(
+            t1.borrow().grad,
+            arr(
+                &[2, 2, 3],
+                vec![3., 7., 11., 3., 7., 11., 3., 7., 11., 3., 7., 11.]
+            )
+        );
+        // The gradient of the shared matrix is summed over the batch
+        assert_eq!(
+            t2.borrow().grad,
+            arr(&[3, 2], vec![22., 22., 26., 26., 30., 30.])
+        );
+    }
+
+    #[test]
+    fn matmul_batch_broadcast_backward_ok() {
+        let t1 = range_tensor(&[2, 1, 1, 2]);
+        let t2 = range_tensor(&[3, 2, 1]);
+        let res = matmul(&t1, &t2);
+        assert_eq!(res.borrow().data.borrow().shape(), &[2, 3, 1, 1]);
+        assert_eq!(
+            res.borrow().data,
+            arr(&[2, 3, 1, 1], vec![5., 11., 17., 11., 25., 39.])
+        );
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, arr(&[2, 1, 1, 2], vec![9., 12., 9., 12.]));
+        assert_eq!(
+            t2.borrow().grad,
+            arr(&[3, 2, 1], vec![4., 6., 4., 6., 4., 6.])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Incompatible shapes [2, 3] and [2, 3] in MatMul op")]
+    fn matmul_invalid_shapes_panics() {
+        matmul(&range_tensor(&[2, 3]), &range_tensor(&[2, 3]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Incompatible shapes [2, 2, 3] and [3, 3, 2] in MatMul op")]
+    fn matmul_invalid_batch_panics() {
+        matmul(&range_tensor(&[2, 2, 3]), &range_tensor(&[3, 3, 2]));
+    }
+
+    #[test]
+    fn try_matmul_err() {
+        let scalar = Tensor::new_ref(&arr0(1.).into_dyn());
+        assert_eq!(
+            try_matmul(&scalar, &range_tensor(&[2])).err(),
+            Some(RustyGradError::RankMismatch {
+                op: "MatMul",
+                expected: 1,
+                got: 0
+            })
+        );
+        assert_eq!(
+            try_dot(&range_tensor(&[3]), &range_tensor(&[2, 2])).err(),
+            Some(RustyGradError::ShapeMismatch {
+                op: "MatMul",
+                lhs: vec![3],
+                rhs: vec![2, 2]
+            })
+        );
+    }
+}
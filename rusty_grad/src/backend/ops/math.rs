@@ -0,0 +1,395 @@
+use crate::backend::float::{indicator, Float};
+use crate::backend::ops::{
+    add, binary_out_shape, check_children, constant, mul, pow, scale, shape_of, sum_to,
+    with_data_pair,
+};
+use crate::backend::shared::MaybeSendSync;
+use crate::backend::tensor::{RTensor, Tensor};
+use crate::error::{invalid_argument, unwrap_or_panic, Result};
+use ndarray::{prelude::*, Zip};
+
+/// Creates the output tensor of an elementwise unary op. The `derivative`
+/// function receives the input tensor and returns the local derivative of each
+/// element, that is multiplied by the upstream gradient. It must be computed
+/// with the autograd ops from the input, so that it can be differentiated.
+fn unary_op<T: Float>(
+    op: &'static str,
+    t: &RTensor<T>,
+    f: impl Fn(T) -> T,
+    derivative: impl Fn(&RTensor<T>) -> RTensor<T> + MaybeSendSync + 'static,
+) -> RTensor<T> {
+    let data = t.borrow().data.borrow().mapv(f);
+    Tensor::from_op(data, vec![t.clone()], move |t, grad| {
+        check_children(op, t, 1)?;
+        Ok(vec![mul(grad, &derivative(&t.prev[0]))])
+    })
+}
+
+/// Creates a constant tensor with the shape of `t` computing each element from
+/// the element of `t` with `f`
+fn constant_map<T: Float>(t: &RTensor<T>, f: impl Fn(T) -> T) -> RTensor<T> {
+    constant(t.borrow().data.borrow().mapv(f))
+}
+
+/// Elementwise exponential of `t`
+pub fn exp<T: Float>(t: &RTensor<T>) -> RTensor<T> {
+    unary_op("Exp", t, T::exp, exp)
+}
+
+/// Elementwise natural logarithm of `t`
+pub fn log<T: Float>(t: &RTensor<T>) -> RTensor<T> {
+    unary_op("Log", t, T::ln, |prev| pow(prev, -T::one()))
+}
+
+/// Elementwise `ln(1 + x)` of `t`, more accurate than `log` for small values
+pub fn log1p<T: Float>(t: &RTensor<T>) -> RTensor<T> {
+    unary_op("Log1p", t, T::ln_1p, |prev| {
+        pow(&add(prev, &constant(arr0(T::one()).into_dyn())), -T::one())
+    })
+}
+
+/// Elementwise square root of `t`
+pub fn sqrt<T: Float>(t: &RTensor<T>) -> RTensor<T> {
+    // d(sqrt(x))/dx = 1 / (2 * sqrt(x))
+    unary_op("Sqrt", t, T::sqrt, |prev| scale(&rsqrt(prev), T::cast(0.5)))
+}
+
+/// Elementwise reciprocal of the square root of `t`
+pub fn rsqrt<T: Float>(t: &RTensor<T>) -> RTensor<T> {
+    // d(x^-1/2)/dx = -1/2 * x^-3/2
+    unary_op(
+        "Rsqrt",
+        t,
+        |x| x.sqrt().recip(),
+        |prev| scale(&pow(prev, T::cast(-1.5)), T::cast(-0.5)),
+    )
+}
+
+/// Elementwise absolute value of `t`. The gradient at 0 is 0.
+pub fn abs<T: Float>(t: &RTensor<T>) -> RTensor<T> {
+    unary_op("Abs", t, T::abs, sign)
+}
+
+/// Elementwise negation of `t`
+pub fn neg<T: Float>(t: &RTensor<T>) -> RTensor<T> {
+    unary_op("Neg", t, |x| -x, |prev| constant_map(prev, |_| -T::one()))
+}
+
+/// Returns -1, 0 or 1 depending on the sign of `x` (unlike `Float::signum`, 0
+/// for zeros)
+fn sign_value<T: Float>(x: T) -> T {
+    if x > T::zero() {
+        T::one()
+    } else if x < T::zero() {
+        -T::one()
+    } else {
+        T::zero()
+    }
+}
+
+/// Elementwise sign of `t` (-1, 0 or 1). Its gradient is 0 everywhere.
+pub fn sign<T: Float>(t: &RTensor<T>) -> RTensor<T> {
+    unary_op("Sign", t, sign_value, |prev| {
+        constant_map(prev, |_| T::zero())
+    })
+}
+
+/// Elementwise sine of `t`
+pub fn sin<T: Float>(t: &RTensor<T>) -> RTensor<T> {
+    unary_op("Sin", t, T::sin, cos)
+}
+
+/// Elementwise cosine of `t`
+pub fn cos<T: Float>(t: &RTensor<T>) -> RTensor<T> {
+    unary_op("Cos", t, T::cos, |prev| neg(&sin(prev)))
+}
+
+/// Limits the values of `t` to the range `[min, max]`. The gradient flows
+/// only through the elements inside the range (bounds included).
+pub fn clamp<T: Float>(t: &RTensor<T>, min: T, max: T) -> RTensor<T> {
+    unwrap_or_panic(try_clamp(t, min, max))
+}
+
+/// Fallible version of [`clamp`], returns an error if a bound is NaN or `min`
+/// is greater than `max`
+pub fn try_clamp<T: Float>(t: &RTensor<T>, min: T, max: T) -> Result<RTensor<T>> {
+    if min.is_nan() || max.is_nan() {
+        return invalid_argument(
+            "Clamp",
+            format!("the bounds {} and {} can't be NaN", min, max),
+        );
+    }
+    if min > max {
+        return invalid_argument(
+            "Clamp",
+            format!(
+                "the min value {} can't be greater than the max value {}",
+                min, max
+            ),
+        );
+    }
+    Ok(unary_op(
+        "Clamp",
+        t,
+        move |x| x.clamp(min, max),
+        move |prev| constant_map(prev, |x| indicator(min <= x && x <= max)),
+    ))
+}
+
+/// Creates the output tensor of an elementwise binary op that selects one of
+/// its inputs (`minimum` and `maximum`). The gradient goes to the selected
+/// input, and it is split evenly between both inputs when they are equal.
+fn select_op<T: Float>(
+    op: &'static str,
+    t1: &RTensor<T>,
+    t2: &RTensor<T>,
+    select_first: fn(T, T) -> bool,
+) -> Result<RTensor<T>> {
+    let data = with_data_pair(t1, t2, |d1, d2| {
+        let out_shape = binary_out_shape(op, d1, d2)?;
+        Ok(Zip::from(&d1.broadcast(IxDyn(&out_shape)).unwrap())
+            .and_broadcast(d2)
+            .map_collect(|&x1, &x2| if select_first(x1, x2) { x1 } else { x2 }))
+    })?;
+    Ok(Tensor::from_op(
+        data,
+        vec![t1.clone(), t2.clone()],
+        move |t, grad| {
+            check_children(op, t, 2)?;
+            let (t1, t2) = (&t.prev[0], &t.prev[1]);
+            // Weight of the gradient that goes to `t1`, `t2` gets the rest
+            let out_dim = t.data.borrow().raw_dim();
+            let w1 = with_data_pair(t1, t2, |d1, d2| {
+                Zip::from(&d1.broadcast(out_dim).unwrap())
+                    .and_broadcast(d2)
+                    .map_collect(|&x1, &x2| {
+                        if x1 == x2 {
+                            T::cast(0.5)
+                        } else {
+                            indicator(select_first(x1, x2))
+                        }
+                    })
+            });
+            let w2 = w1.mapv(|w| T::one() - w);
+            Ok(vec![
+                sum_to(&mul(grad, &constant(w1)), &shape_of(t1)),
+                sum_to(&mul(grad, &constant(w2)), &shape_of(t2)),
+            ])
+        },
+    ))
+}
+
+/// Fallible version of [`minimum`], returns an error if the shapes of `t1`
+/// and `t2` can't be broadcasted together
+pub fn try_minimum<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<RTensor<T>> {
+    select_op("Minimum", t1, t2, |x1, x2| x1 < x2)
+}
+
+/// Elementwise minimum of `t1` and `t2`, with broadcasting
+pub fn minimum<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> RTensor<T> {
+    unwrap_or_panic(try_minimum(t1, t2))
+}
+
+/// Fallible version of [`maximum`], returns an error if the shapes of `t1`
+/// and `t2` can't be broadcasted together
+pub fn try_maximum<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> Result<RTensor<T>> {
+    select_op("Maximum", t1, t2, |x1, x2| x1 > x2)
+}
+
+/// Elementwise maximum of `t1` and `t2`, with broadcasting
+pub fn maximum<T: Float>(t1: &RTensor<T>, t2: &RTensor<T>) -> RTensor<T> {
+    unwrap_or_panic(try_maximum(t1, t2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RustyGradError;
+
+    fn arr(shape: &[usize], data: Vec<f32>) -> ArrayD<f32> {
+        ArrayD::from_shape_vec(IxDyn(shape), data).unwrap()
+    }
+
+    #[test]
+    fn exp_backward_ok() {
+        let arr = arr(&[2, 2], vec![0., 1., -2., 0.5]);
+        let t = Tensor::new_ref(&arr);
+        let res = exp(&t);
+        assert_eq!(res.borrow().data, arr.mapv(f32::exp));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr.mapv(f32::exp));
+    }
+
+    #[test]
+    fn log_backward_ok() {
+        let arr = arr(&[2, 2], vec![1., 2., 4., 0.5]);
+        let t = Tensor::new_ref(&arr);
+        let res = log(&t);
+        assert_eq!(res.borrow().data, arr.mapv(f32::ln));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr.mapv(|x| 1. / x));
+    }
+
+    #[test]
+    fn log1p_backward_ok() {
+        let arr = arr(&[2, 2], vec![0., 1e-8, 3., -0.5]);
+        let t = Tensor::new_ref(&arr);
+        let res = log1p(&t);
+        assert_eq!(res.borrow().data, arr.mapv(f32::ln_1p));
+        // `log(1 + x)` would round the small value to 0
+        assert!(res.borrow().data.borrow()[[0, 1]] > 0.);
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr.mapv(|x| 1. / (1. + x)));
+    }
+
+    #[test]
+    fn sqrt_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 2], vec![1., 4., 16., 0.25]));
+        let res = sqrt(&t);
+        assert_eq!(res.borrow().data, arr(&[2, 2], vec![1., 2., 4., 0.5]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr(&[2, 2], vec![0.5, 0.25, 0.125, 1.]));
+    }
+
+    #[test]
+    fn rsqrt_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 2], vec![1., 4., 16., 0.25]));
+        let res = rsqrt(&t);
+        assert_eq!(res.borrow().data, arr(&[2, 2], vec![1., 0.5, 0.25, 2.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(
+            t.borrow().grad,
+            arr(&[2, 2], vec![-0.5, -0.0625, -0.0078125, -4.])
+        );
+    }
+
+    #[test]
+    fn abs_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 2], vec![-1.5, 2., 0., -0.]));
+        let res = abs(&t);
+        assert_eq!(res.borrow().data, arr(&[2, 2], vec![1.5, 2., 0., 0.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr(&[2, 2], vec![-1., 1., 0., 0.]));
+    }
+
+    #[test]
+    fn neg_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 2], vec![-1.5, 2., 0., 3.]));
+        let res = neg(&t);
+        assert_eq!(res.borrow().data, arr(&[2, 2], vec![1.5, -2., 0., -3.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, Array::from_elem(IxDyn(&[2, 2]), -1.));
+    }
+
+    #[test]
+    fn sign_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 2], vec![-1.5, 2., 0., 3.]));
+        let res = sign(&t);
+        assert_eq!(res.borrow().data, arr(&[2, 2], vec![-1., 1., 0., 1.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, Array::zeros(IxDyn(&[2, 2])));
+    }
+
+    #[test]
+    fn sin_cos_backward_ok() {
+        let arr = arr(&[2, 2], vec![0., 0.5, -1., 3.]);
+        let t1 = Tensor::new_ref(&arr);
+        let t2 = Tensor::new_ref(&arr);
+        let res_sin = sin(&t1);
+        let res_cos = cos(&t2);
+        assert_eq!(res_sin.borrow().data, arr.mapv(f32::sin));
+        assert_eq!(res_cos.borrow().data, arr.mapv(f32::cos));
+
+        let ones = Array::ones(res_sin.borrow().data.borrow().raw_dim());
+        res_sin.borrow_mut().backward_with(ones);
+        let ones = Array::ones(res_cos.borrow().data.borrow().raw_dim());
+        res_cos.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, arr.mapv(f32::cos));
+        assert_eq!(t2.borrow().grad, arr.mapv(|x| -x.sin()));
+    }
+
+    #[test]
+    fn clamp_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![-2., -1., 0., 0.5, 1., 3.]));
+        let res = clamp(&t, -1., 1.);
+        assert_eq!(
+            res.borrow().data,
+            arr(&[2, 3], vec![-1., -1., 0., 0.5, 1., 1.])
+        );
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr(&[2, 3], vec![0., 1., 1., 1., 1., 0.]));
+    }
+
+    #[test]
+    #[should_panic(expected = "the min value 1 can't be greater than the max value -1")]
+    fn clamp_invalid_range_panics() {
+        clamp(&Tensor::new_ref(&Array::zeros(IxDyn(&[2]))), 1., -1.);
+    }
+
+    #[test]
+    fn minimum_backward_ok() {
+        let t1 = Tensor::new_ref(&arr(&[2, 2], vec![1., 5., 3., 0.]));
+        let t2 = Tensor::new_ref(&arr(&[2], vec![2., 3.]));
+        let res = minimum(&t1, &t2);
+        assert_eq!(res.borrow().data, arr(&[2, 2], vec![1., 3., 2., 0.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, arr(&[2, 2], vec![1., 0., 0., 1.]));
+        assert_eq!(t2.borrow().grad, arr(&[2], vec![1., 1.]));
+    }
+
+    #[test]
+    fn maximum_backward_ties_ok() {
+        let t1 = Tensor::new_ref(&arr(&[2, 2], vec![1., 5., 2., 0.]));
+        let t2 = Tensor::new_ref(&arr(&[2], vec![2., 3.]));
+        let res = maximum(&t1, &t2);
+        assert_eq!(res.borrow().data, arr(&[2, 2], vec![2., 5., 2., 3.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t1.borrow().grad, arr(&[2, 2], vec![0., 1., 0.5, 0.]));
+        assert_eq!(t2.borrow().grad, arr(&[2], vec![1.5, 1.]));
+    }
+
+    #[test]
+    fn try_math_ops_err() {
+        let t1 = Tensor::new_ref(&Array::zeros(IxDyn(&[2, 3])));
+        let t2 = Tensor::new_ref(&Array::zeros(IxDyn(&[3, 2])));
+        assert!(matches!(
+            try_minimum(&t1, &t2),
+            Err(RustyGradError::ShapeMismatch { op: "Minimum", .. })
+        ));
+        assert!(matches!(
+            try_maximum(&t1, &t2),
+            Err(RustyGradError::ShapeMismatch { op: "Maximum", .. })
+        ));
+        assert!(matches!(
+            try_clamp(&t1, 1., 0.),
+            Err(RustyGradError::InvalidArgument { op: "Clamp", .. })
+        ));
+        assert!(matches!(
+            try_clamp(&t1, f64::NAN, 0.),
+            Err(RustyGradError::InvalidArgument { op: "Clamp", .. })
+        ));
+        assert!(try_clamp(&t1, 0., f64::NAN).is_err());
+    }
+}
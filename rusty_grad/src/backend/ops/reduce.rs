@@ -0,0 +1,491 @@
+use crate::backend::float::{indicator, Float};
+use crate::backend::ops::{
+    broadcast_shape, check_axis, check_children, constant, div, expand, mul, reshape, scale,
+    shape_of, unbroadcast,
+};
+use crate::backend::shared::MaybeSendSync;
+use crate::backend::tensor::{RTensor, Tensor};
+use crate::error::{unwrap_or_panic, Result, RustyGradError};
+use ndarray::{prelude::*, Zip};
+
+/// Validates the axes to reduce and returns them sorted and without duplicates.
+/// If `axes` is `None` all the axes of the tensor are reduced.
+fn reduction_axes(op: &'static str, axes: Option<&[usize]>, ndim: usize) -> Result<Vec<usize>> {
+    let mut axes = match axes {
+        Some(axes) => axes.to_vec(),
+        None => (0..ndim).collect(),
+    };
+    for &axis in axes.iter() {
+        check_axis(op, axis, ndim)?;
+    }
+    axes.sort_unstable();
+    axes.dedup();
+    Ok(axes)
+}
+
+/// Returns the shape of the reduction output without the reduced axes
+fn squeezed_shape(shape: &[usize], axes: &[usize]) -> Vec<usize> {
+    shape
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !axes.contains(i))
+        .map(|(_, &dim)| dim)
+        .collect()
+}
+
+/// Folds `data` over each of the `axes`, keeping the reduced axes with size 1
+fn fold_axes<T: Float>(
+    data: &ArrayD<T>,
+    axes: &[usize],
+    init: T,
+    fold: impl Fn(T, T) -> T,
+) -> ArrayD<T> {
+    let mut res = data.clone();
+    for &axis in axes {
+        res = res
+            .fold_axis(Axis(axis), init, |&acc, &x| fold(acc, x))
+            .insert_axis(Axis(axis));
+    }
+    res
+}
+
+/// Creates the output tensor of a reduction op from the result computed with
+/// the reduced axes kept. The backward function receives the input tensor, the
+/// upstream gradient and the output data, both reshaped to the kept dimensions
+/// so that they broadcast with the input, and returns the gradient of the input.
+fn reduction<T: Float>(
+    op: &'static str,
+    t: &RTensor<T>,
+    kept: ArrayD<T>,
+    axes: &[usize],
+    keepdims: bool,
+    backward_fn: impl Fn(&RTensor<T>, &RTensor<T>, &ArrayD<T>) -> RTensor<T> + MaybeSendSync + 'static,
+) -> RTensor<T> {
+    let kept_shape = kept.shape().to_vec();
+    let data = if keepdims {
+        kept
+    } else {
+        let out_shape = squeezed_shape(kept.shape(), axes);
+        kept.into_shape(IxDyn(&out_shape)).unwrap()
+    };
+    Tensor::from_op(data, vec![t.clone()], move |t, grad| {
+        check_children(op, t, 1)?;
+        let grad = reshape(grad, &kept_shape);
+        let out = t
+            .data
+            .borrow()
+            .clone()
+            .into_shape(IxDyn(&kept_shape))
+            .unwrap();
+        Ok(vec![backward_fn(&t.prev[0], &grad, &out)])
+    })
+}
+
+/// Fallible version of [`sum`], returns an error if any of the axes is not
+/// valid
+pub fn try_sum<T: Float>(
+    t: &RTensor<T>,
+    axes: Option<&[usize]>,
+    keepdims: bool,
+) -> Result<RTensor<T>> {
+    let axes = reduction_axes("Sum", axes, t.borrow().data.borrow().ndim())?;
+    let kept = fold_axes(&t.borrow().data.borrow(), &axes, T::zero(), |acc, x| {
+        acc + x
+    });
+    Ok(reduction(
+        "Sum",
+        t,
+        kept,
+        &axes,
+        keepdims,
+        |prev, grad, _| expand(grad, &shape_of(prev)),
+    ))
+}
+
+/// Sums the elements of `t` over the given `axes` (all of them if `None`). If
+/// `keepdims` is true the reduced axes are kept in the output with size 1.
+pub fn sum<T: Float>(t: &RTensor<T>, axes: Option<&[usize]>, keepdims: bool) -> RTensor<T> {
+    unwrap_or_panic(try_sum(t, axes, keepdims))
+}
+
+/// Fallible version of [`sum_to`], returns an error if `shape` can't be
+/// broadcasted to the shape of `t`
+pub fn try_sum_to<T: Float>(t: &RTensor<T>, shape: &[usize]) -> Result<RTensor<T>> {
+    let in_shape = shape_of(t);
+    if in_shape == shape {
+        return Ok(t.clone());
+    }
+    if broadcast_shape(shape, &in_shape).as_deref() != Some(&in_shape[..]) {
+        return Err(RustyGradError::ShapeMismatch {
+            op: "SumTo",
+            lhs: in_shape,
+            rhs: shape.to_vec(),
+        });
+    }
+    let data = unbroadcast(&t.borrow().data.borrow(), shape);
+    Ok(Tensor::from_op(data, vec![t.clone()], move |t, grad| {
+        check_children("SumTo", t, 1)?;
+        Ok(vec![expand(grad, &in_shape)])
+    }))
+}
+
+/// Sums `t` to `shape`, the reverse of broadcasting `shape` to the shape of
+/// `t`: the elements are summed over the leading axes that are not in `shape`
+/// and over the axes where `shape` has size 1. It returns `t` itself if it
+/// already has the given `shape`.
+pub fn sum_to<T: Float>(t: &RTensor<T>, shape: &[usize]) -> RTensor<T> {
+    unwrap_or_panic(try_sum_to(t, shape))
+}
+
+/// Fallible version of [`mean`], returns an error if any of the axes is not
+/// valid
+pub fn try_mean<T: Float>(
+    t: &RTensor<T>,
+    axes: Option<&[usize]>,
+    keepdims: bool,
+) -> Result<RTensor<T>> {
+    let axes = reduction_axes("Mean", axes, t.borrow().data.borrow().ndim())?;
+    let shape = t.borrow().data.borrow().shape().to_vec();
+    let n = T::from_usize(axes.iter().map(|&axis| shape[axis]).product()).unwrap();
+    let kept = fold_axes(&t.borrow().data.borrow(), &axes, T::zero(), |acc, x| {
+        acc + x
+    }) / n;
+    Ok(reduction(
+        "Mean",
+        t,
+        kept,
+        &axes,
+        keepdims,
+        move |prev, grad, _| scale(&expand(grad, &shape_of(prev)), n.recip()),
+    ))
+}
+
+/// Computes the mean of the elements of `t` over the given `axes` (all of them
+/// if `None`). If `keepdims` is true the reduced axes are kept with size 1.
+pub fn mean<T: Float>(t: &RTensor<T>, axes: Option<&[usize]>, keepdims: bool) -> RTensor<T> {
+    unwrap_or_panic(try_mean(t, axes, keepdims))
+}
+
+/// Fallible version of [`max`], returns an error if any of the axes is not
+/// valid
+pub fn try_max<T: Float>(
+    t: &RTensor<T>,
+    axes: Option<&[usize]>,
+    keepdims: bool,
+) -> Result<RTensor<T>> {
+    let axes = reduction_axes("Max", axes, t.borrow().data.borrow().ndim())?;
+    let kept = fold_axes(&t.borrow().data.borrow(), &axes, T::neg_infinity(), T::max);
+    let backward_axes = axes.clone();
+    Ok(reduction(
+        "Max",
+        t,
+        kept,
+        &axes,
+        keepdims,
+        move |prev, grad, out| extremum_backward(prev, grad, out, &backward_axes),
+    ))
+}
+
+/// Computes the maximum of `t` over the given `axes` (all of them if `None`).
+/// If `keepdims` is true the reduced axes are kept with size 1. When several
+/// elements are tied for the maximum the gradient is split evenly among them.
+pub fn max<T: Float>(t: &RTensor<T>, axes: Option<&[usize]>, keepdims: bool) -> RTensor<T> {
+    unwrap_or_panic(try_max(t, axes, keepdims))
+}
+
+/// Fallible version of [`min`], returns an error if any of the axes is not
+/// valid
+pub fn try_min<T: Float>(
+    t: &RTensor<T>,
+    axes: Option<&[usize]>,
+    keepdims: bool,
+) -> Result<RTensor<T>> {
+    let axes = reduction_axes("Min", axes, t.borrow().data.borrow().ndim())?;
+    let kept = fold_axes(&t.borrow().data.borrow(), &axes, T::infinity(), T::min);
+    let backward_axes = axes.clone();
+    Ok(reduction(
+        "Min",
+        t,
+        kept,
+        &axes,
+        keepdims,
+        move |prev, grad, out| extremum_backward(prev, grad, out, &backward_axes),
+    ))
+}
+
+/// Computes the minimum of `t` over the given `axes` (all of them if `None`).
+/// If `keepdims` is true the reduced axes are kept with size 1. When several
+/// elements are tied for the minimum the gradient is split evenly among them.
+pub fn min<T: Float>(t: &RTensor<T>, axes: Option<&[usize]>, keepdims: bool) -> RTensor<T> {
+    unwrap_or_panic(try_min(t, axes, keepdims))
+}
+
+fn extremum_backward<T: Float>(
+    prev: &RTensor<T>,
+    grad: &RTensor<T>,
+    out: &ArrayD<T>,
+    axes: &[usize],
+) -> RTensor<T> {
+    // Select the elements equal to the extremum and count the ties
+    let mask = Zip::from(&*prev.borrow().data.borrow())
+        .and_broadcast(out)
+        .map_collect(|&x, &selected| indicator(x == selected));
+    let n_ties = fold_axes(&mask, axes, T::zero(), |acc, x| acc + x);
+    let weights = Zip::from(&mask)
+        .and_broadcast(&n_ties)
+        .map_collect(|&m, &n| m / n);
+    mul(&expand(grad, &shape_of(prev)), &constant(weights))
+}
+
+/// Fallible version of [`prod`], returns an error if any of the axes is not
+/// valid
+pub fn try_prod<T: Float>(
+    t: &RTensor<T>,
+    axes: Option<&[usize]>,
+    keepdims: bool,
+) -> Result<RTensor<T>> {
+    let axes = reduction_axes("Prod", axes, t.borrow().data.borrow().ndim())?;
+    let kept = fold_axes(&t.borrow().data.borrow(), &axes, T::one(), |acc, x| acc * x);
+    let backward_axes = axes.clone();
+    Ok(reduction(
+        "Prod",
+        t,
+        kept,
+        &axes,
+        keepdims,
+        move |prev, grad, _| {
+            // The gradient of each element is the product of the rest of elements
+            let grad = expand(grad, &shape_of(prev));
+            if !prev.borrow().data.borrow().iter().any(|x| x.is_zero()) {
+                let rest = div(&prod(prev, Some(&backward_axes), true), prev);
+                return mul(&grad, &rest);
+            }
+            // With zeros the product of the rest of elements is taken as a
+            // constant, so the gradient is not differentiable in that case
+            let rest = prod_rest(&prev.borrow().data.borrow(), &backward_axes);
+            mul(&grad, &constant(rest))
+        },
+    ))
+}
+
+/// Returns the product of the rest of elements of the reduced `axes` for each
+/// element of `data`, i.e. the derivative of the product with respect to each
+/// element. It can't be computed as `prod / x` when there are zeros, so we
+/// count them and take the product of the non-zero elements instead.
+pub(crate) fn prod_rest<T: Float>(data: &ArrayD<T>, axes: &[usize]) -> ArrayD<T> {
+    let is_zero = data.mapv(|x| indicator(x.is_zero()));
+    let n_zeros = fold_axes(&is_zero, axes, T::zero(), |acc, x| acc + x);
+    let prod_non_zero = fold_axes(data, axes, T::one(), |acc, x| {
+        if x.is_zero() {
+            acc
+        } else {
+            acc * x
+        }
+    });
+    Zip::from(data)
+        .and_broadcast(&n_zeros)
+        .and_broadcast(&prod_non_zero)
+        .map_collect(
+            |&x, &zeros, &p| match (x.is_zero(), zeros.to_usize().unwrap()) {
+                (false, 0) => p / x,
+                (true, 1) => p,
+                _ => T::zero(),
+            },
+        )
+}
+
+/// Computes the product of the elements of `t` over the given `axes` (all of
+/// them if `None`). If `keepdims` is true the reduced axes are kept with size 1.
+pub fn prod<T: Float>(t: &RTensor<T>, axes: Option<&[usize]>, keepdims: bool) -> RTensor<T> {
+    unwrap_or_panic(try_prod(t, axes, keepdims))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RustyGradError;
+
+    fn arr(shape: &[usize], data: Vec<f32>) -> ArrayD<f32> {
+        ArrayD::from_shape_vec(IxDyn(shape), data).unwrap()
+    }
+
+    #[test]
+    fn sum_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., 2., 3., 4., 5., 6.]));
+        assert_eq!(sum(&t, None, false).borrow().data, arr0(21.).into_dyn());
+        assert_eq!(sum(&t, None, true).borrow().data, arr(&[1, 1], vec![21.]));
+        assert_eq!(
+            sum(&t, Some(&[0]), false).borrow().data,
+            arr(&[3], vec![5., 7., 9.])
+        );
+        assert_eq!(
+            sum(&t, Some(&[1]), true).borrow().data,
+            arr(&[2, 1], vec![6., 15.])
+        );
+    }
+
+    #[test]
+    fn sum_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., 2., 3., 4., 5., 6.]));
+        let res = sum(&t, Some(&[1]), false);
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, Array::ones(IxDyn(&[2, 3])));
+    }
+
+    #[test]
+    fn sum_multiple_axes_ok() {
+        let t = Tensor::<f32>::new_ref(&Array::ones(IxDyn(&[2, 3, 4])));
+        let res = sum(&t, Some(&[2, 0]), false);
+        assert_eq!(res.borrow().data, Array::from_elem(IxDyn(&[3]), 8.));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, Array::ones(IxDyn(&[2, 3, 4])));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid axis 2 in Sum op")]
+    fn sum_invalid_axis_panics() {
+        let t = Tensor::<f32>::new_ref(&Array::ones(IxDyn(&[2, 3])));
+        sum(&t, Some(&[2]), false);
+    }
+
+    #[test]
+    fn try_reductions_invalid_axis_err() {
+        let t = Tensor::<f32>::new_ref(&Array::ones(IxDyn(&[2, 3])));
+        let expected = |op| RustyGradError::InvalidAxis {
+            op,
+            axis: 3,
+            ndim: 2,
+        };
+        assert_eq!(
+            try_sum(&t, Some(&[0, 3]), false).err(),
+            Some(expected("Sum"))
+        );
+        assert_eq!(
+            try_mean(&t, Some(&[3]), false).err(),
+            Some(expected("Mean"))
+        );
+        assert_eq!(try_max(&t, Some(&[3]), false).err(), Some(expected("Max")));
+        assert_eq!(try_min(&t, Some(&[3]), false).err(), Some(expected("Min")));
+        assert_eq!(try_prod(&t, Some(&[3]), true).err(), Some(expected("Prod")));
+    }
+
+    #[test]
+    fn mean_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., 2., 3., 4., 5., 6.]));
+        assert_eq!(mean(&t, None, false).borrow().data, arr0(3.5).into_dyn());
+        assert_eq!(
+            mean(&t, Some(&[0]), true).borrow().data,
+            arr(&[1, 3], vec![2.5, 3.5, 4.5])
+        );
+    }
+
+    #[test]
+    fn mean_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 2], vec![1., 2., 3., 4.]));
+        let res = mean(&t, None, false);
+        res.borrow_mut().backward();
+        assert_eq!(t.borrow().grad, Array::from_elem(IxDyn(&[2, 2]), 0.25));
+    }
+
+    #[test]
+    fn max_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., 7., 3., 4., 5., -6.]));
+        assert_eq!(max(&t, None, false).borrow().data, arr0(7.).into_dyn());
+        assert_eq!(
+            max(&t, Some(&[1]), false).borrow().data,
+            arr(&[2], vec![7., 5.])
+        );
+    }
+
+    #[test]
+    fn max_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., 7., 3., 4., 5., -6.]));
+        let res = max(&t, Some(&[0]), true);
+        assert_eq!(res.borrow().data, arr(&[1, 3], vec![4., 7., 3.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr(&[2, 3], vec![0., 1., 1., 1., 0., 0.]));
+    }
+
+    #[test]
+    fn max_backward_ties_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![2., 2., 1., 3., 3., 3.]));
+        let res = max(&t, Some(&[1]), false);
+        assert_eq!(res.borrow().data, arr(&[2], vec![2., 3.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        let third = 1. / 3.;
+        assert_eq!(
+            t.borrow().grad,
+            arr(&[2, 3], vec![0.5, 0.5, 0., third, third, third])
+        );
+    }
+
+    #[test]
+    fn min_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., -2., 3., -2., 5., 0.]));
+        let res = min(&t, None, false);
+        assert_eq!(res.borrow().data, arr0(-2.).into_dyn());
+
+        res.borrow_mut().backward();
+        assert_eq!(
+            t.borrow().grad,
+            arr(&[2, 3], vec![0., 0.5, 0., 0.5, 0., 0.])
+        );
+    }
+
+    #[test]
+    fn prod_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., 2., 3., 4., 5., 6.]));
+        assert_eq!(prod(&t, None, false).borrow().data, arr0(720.).into_dyn());
+        assert_eq!(
+            prod(&t, Some(&[1]), false).borrow().data,
+            arr(&[2], vec![6., 120.])
+        );
+    }
+
+    #[test]
+    fn prod_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., 2., 3., 4., 5., 6.]));
+        let res = prod(&t, Some(&[1]), false);
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(
+            t.borrow().grad,
+            arr(&[2, 3], vec![6., 3., 2., 30., 24., 20.])
+        );
+    }
+
+    #[test]
+    fn prod_backward_zeros_ok() {
+        // One zero: only the zero gets a gradient. Two zeros: all gradients are 0
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![2., 0., 3., 0., 5., 0.]));
+        let res = prod(&t, Some(&[1]), false);
+        assert_eq!(res.borrow().data, arr(&[2], vec![0., 0.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr(&[2, 3], vec![0., 6., 0., 0., 0., 0.]));
+
+        // Over several axes the zeros are counted across all the reduced rows:
+        // the first column has one zero and the second one has two, in
+        // different rows
+        let t = Tensor::new_ref(&arr(
+            &[3, 2, 2],
+            vec![1., 2., 0., 3., 2., 0., 1., 1., 3., 4., 1., 0.],
+        ));
+        let res = prod(&t, Some(&[0, 1]), false);
+        assert_eq!(res.borrow().data, arr(&[2], vec![0., 0.]));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        let mut expected = vec![0.; 12];
+        expected[2] = 6.;
+        assert_eq!(t.borrow().grad, arr(&[3, 2, 2], expected));
+    }
+}
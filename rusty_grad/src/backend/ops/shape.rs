@@ -0,0 +1,392 @@
+use crate::backend::float::Float;
+use crate::backend::ops::{check_axis, check_children, sum_to};
+use crate::backend::shared::MaybeSendSync;
+use crate::backend::tensor::{RTensor, Tensor};
+use crate::error::{invalid_argument, unwrap_or_panic, Result, RustyGradError};
+use ndarray::prelude::*;
+
+/// Reshapes `data` to `shape`, copying it first if it is not in standard layout
+fn reshape_array<T: Float>(data: &ArrayD<T>, shape: &[usize]) -> ArrayD<T> {
+    data.as_standard_layout()
+        .into_owned()
+        .into_shape(IxDyn(shape))
+        .unwrap()
+}
+
+/// Creates the output tensor of a shape op. The backward function maps the
+/// gradient of the output back to the layout of the input tensor.
+fn shape_op<T: Float>(
+    op: &'static str,
+    t: &RTensor<T>,
+    data: ArrayD<T>,
+    backward_fn: impl Fn(&RTensor<T>) -> RTensor<T> + MaybeSendSync + 'static,
+) -> RTensor<T> {
+    Tensor::from_op(data, vec![t.clone()], move |t, grad| {
+        check_children(op, t, 1)?;
+        Ok(vec![backward_fn(grad)])
+    })
+}
+
+/// Fallible version of [`reshape`], returns an error if the number of
+/// elements of `shape` is not the same as in `t`
+pub fn try_reshape<T: Float>(t: &RTensor<T>, shape: &[usize]) -> Result<RTensor<T>> {
+    let in_shape = t.borrow().data.borrow().shape().to_vec();
+    if in_shape.iter().product::<usize>() != shape.iter().product::<usize>() {
+        return Err(RustyGradError::ShapeMismatch {
+            op: "Reshape",
+            lhs: in_shape,
+            rhs: shape.to_vec(),
+        });
+    }
+    let data = reshape_array(&t.borrow().data.borrow(), shape);
+    Ok(shape_op("Reshape", t, data, move |grad| {
+        reshape(grad, &in_shape)
+    }))
+}
+
+/// Returns a tensor with the same data as `t` and the given `shape`. The
+/// number of elements must not change.
+pub fn reshape<T: Float>(t: &RTensor<T>, shape: &[usize]) -> RTensor<T> {
+    unwrap_or_panic(try_reshape(t, shape))
+}
+
+/// Fallible version of [`transpose`], returns an error if any of the axes is
+/// not valid
+pub fn try_transpose<T: Float>(t: &RTensor<T>, axis1: usize, axis2: usize) -> Result<RTensor<T>> {
+    let ndim = t.borrow().data.borrow().ndim();
+    check_axis("Transpose", axis1, ndim)?;
+    check_axis("Transpose", axis2, ndim)?;
+    let mut axes: Vec<usize> = (0..ndim).collect();
+    axes.swap(axis1, axis2);
+    try_permute(t, &axes)
+}
+
+/// Swaps the axes `axis1` and `axis2` of `t`
+pub fn transpose<T: Float>(t: &RTensor<T>, axis1: usize, axis2: usize) -> RTensor<T> {
+    unwrap_or_panic(try_transpose(t, axis1, axis2))
+}
+
+/// Fallible version of [`permute`], returns an error if `axes` is not a
+/// permutation of the axes of `t`
+pub fn try_permute<T: Float>(t: &RTensor<T>, axes: &[usize]) -> Result<RTensor<T>> {
+    let ndim = t.borrow().data.borrow().ndim();
+    let mut sorted_axes = axes.to_vec();
+    sorted_axes.sort_unstable();
+    if sorted_axes != (0..ndim).collect::<Vec<usize>>() {
+        return invalid_argument(
+            "Permute",
+            format!(
+                "{:?} is not a permutation of the {} axes of the tensor",
+                axes, ndim
+            ),
+        );
+    }
+    let data = t
+        .borrow()
+        .data
+        .borrow()
+        .clone()
+        .permuted_axes(axes.to_vec())
+        .as_standard_layout()
+        .into_owned();
+    // The gradient is permuted back with the inverse permutation
+    let mut inverse = vec![0; ndim];
+    for (i, &axis) in axes.iter().enumerate() {
+        inverse[axis] = i;
+    }
+    Ok(shape_op("Permute", t, data, move |grad| {
+        permute(grad, &inverse)
+    }))
+}
+
+/// Reorders the axes of `t`, the axis `i` of the output is the axis `axes[i]`
+/// of the input
+pub fn permute<T: Float>(t: &RTensor<T>, axes: &[usize]) -> RTensor<T> {
+    unwrap_or_panic(try_permute(t, axes))
+}
+
+/// Fallible version of [`squeeze`], returns an error if any of the axes is not
+/// valid or doesn't have size 1
+pub fn try_squeeze<T: Float>(t: &RTensor<T>, axes: Option<&[usize]>) -> Result<RTensor<T>> {
+    let in_shape = t.borrow().data.borrow().shape().to_vec();
+    let axes: Vec<usize> = match axes {
+        Some(axes) => {
+            for &axis in axes {
+                check_axis("Squeeze", axis, in_shape.len())?;
+                if in_shape[axis] != 1 {
+                    return invalid_argument(
+                        "Squeeze",
+                        format!("can't squeeze axis {} with size {}", axis, in_shape[axis]),
+                    );
+                }
+            }
+            axes.to_vec()
+        }
+        None => (0..in_shape.len()).filter(|&i| in_shape[i] == 1).collect(),
+    };
+    let out_shape: Vec<usize> = in_shape
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !axes.contains(i))
+        .map(|(_, &dim)| dim)
+        .collect();
+    try_reshape(t, &out_shape)
+}
+
+/// Removes the given `axes` of `t`, that must have size 1. If `axes` is `None`
+/// all the axes with size 1 are removed.
+pub fn squeeze<T: Float>(t: &RTensor<T>, axes: Option<&[usize]>) -> RTensor<T> {
+    unwrap_or_panic(try_squeeze(t, axes))
+}
+
+/// Fallible version of [`unsqueeze`], returns an error if `axis` is greater
+/// than the number of dimensions of `t`
+pub fn try_unsqueeze<T: Float>(t: &RTensor<T>, axis: usize) -> Result<RTensor<T>> {
+    let mut out_shape = t.borrow().data.borrow().shape().to_vec();
+    check_axis("Unsqueeze", axis, out_shape.len() + 1)?;
+    out_shape.insert(axis, 1);
+    try_reshape(t, &out_shape)
+}
+
+/// Inserts a new axis of size 1 at position `axis` of `t`
+pub fn unsqueeze<T: Float>(t: &RTensor<T>, axis: usize) -> RTensor<T> {
+    unwrap_or_panic(try_unsqueeze(t, axis))
+}
+
+/// Fallible version of [`flatten`], returns an error if any of the axes is not
+/// valid or `start_axis` is greater than `end_axis`
+pub fn try_flatten<T: Float>(
+    t: &RTensor<T>,
+    start_axis: usize,
+    end_axis: usize,
+) -> Result<RTensor<T>> {
+    let in_shape = t.borrow().data.borrow().shape().to_vec();
+    check_axis("Flatten", start_axis, in_shape.len())?;
+    check_axis("Flatten", end_axis, in_shape.len())?;
+    if start_axis > end_axis {
+        return invalid_argument(
+            "Flatten",
+            format!(
+                "the start axis {} can't be greater than the end axis {}",
+                start_axis, end_axis
+            ),
+        );
+    }
+    let mut out_shape = in_shape[..start_axis].to_vec();
+    out_shape.push(in_shape[start_axis..=end_axis].iter().product());
+    out_shape.extend_from_slice(&in_shape[end_axis + 1..]);
+    try_reshape(t, &out_shape)
+}
+
+/// Merges the axes from `start_axis` to `end_axis` (both included) of `t`
+/// into a single axis
+pub fn flatten<T: Float>(t: &RTensor<T>, start_axis: usize, end_axis: usize) -> RTensor<T> {
+    unwrap_or_panic(try_flatten(t, start_axis, end_axis))
+}
+
+/// Fallible version of [`expand`], returns an error if `t` can't be
+/// broadcasted to `shape`
+pub fn try_expand<T: Float>(t: &RTensor<T>, shape: &[usize]) -> Result<RTensor<T>> {
+    let in_shape = t.borrow().data.borrow().shape().to_vec();
+    let data = match t.borrow().data.borrow().broadcast(IxDyn(shape)) {
+        Some(view) => view.to_owned(),
+        None => {
+            return Err(RustyGradError::ShapeMismatch {
+                op: "Expand",
+                lhs: in_shape,
+                rhs: shape.to_vec(),
+            })
+        }
+    };
+    Ok(shape_op("Expand", t, data, move |grad| {
+        sum_to(grad, &in_shape)
+    }))
+}
+
+/// Broadcasts `t` to `shape` following the NumPy broadcasting rules. The
+/// gradient is summed over the expanded axes.
+pub fn expand<T: Float>(t: &RTensor<T>, shape: &[usize]) -> RTensor<T> {
+    unwrap_or_panic(try_expand(t, shape))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arr(shape: &[usize], data: Vec<f32>) -> ArrayD<f32> {
+        ArrayD::from_shape_vec(IxDyn(shape), data).unwrap()
+    }
+
+    #[test]
+    fn reshape_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., 2., 3., 4., 5., 6.]));
+        let res = reshape(&t, &[3, 2]);
+        assert_eq!(
+            res.borrow().data,
+            arr(&[3, 2], vec![1., 2., 3., 4., 5., 6.])
+        );
+    }
+
+    #[test]
+    fn reshape_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., 2., 3., 4., 5., 6.]));
+        let weights = Tensor::new_ref(&arr(&[6], vec![1., 2., 3., 4., 5., 6.]));
+        let res = crate::backend::ops::mul(&reshape(&t, &[6]), &weights);
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr(&[2, 3], vec![1., 2., 3., 4., 5., 6.]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Incompatible shapes [2, 3] and [4] in Reshape op")]
+    fn reshape_invalid_shape_panics() {
+        let t = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[2, 3])));
+        reshape(&t, &[4]);
+    }
+
+    #[test]
+    fn transpose_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., 2., 3., 4., 5., 6.]));
+        let res = transpose(&t, 0, 1);
+        assert_eq!(
+            res.borrow().data,
+            arr(&[3, 2], vec![1., 4., 2., 5., 3., 6.])
+        );
+    }
+
+    #[test]
+    fn transpose_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 3], vec![1., 2., 3., 4., 5., 6.]));
+        let weights = Tensor::new_ref(&arr(&[3, 2], vec![1., 2., 3., 4., 5., 6.]));
+        let res = crate::backend::ops::mul(&transpose(&t, 1, 0), &weights);
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr(&[2, 3], vec![1., 3., 5., 2., 4., 6.]));
+    }
+
+    #[test]
+    fn permute_backward_ok() {
+        let t = Tensor::new_ref(&Array::from_iter((0..24).map(|x| x as f32)).into_dyn());
+        let t3 = reshape(&t, &[2, 3, 4]);
+        let res = permute(&t3, &[2, 0, 1]);
+        assert_eq!(res.borrow().data.borrow().shape(), &[4, 2, 3]);
+        assert_eq!(
+            res.borrow().data.borrow()[[3, 1, 2]],
+            t3.borrow().data.borrow()[[1, 2, 3]]
+        );
+
+        // Weight each output element by its own value to check the routing
+        let weights = Tensor::new_ref(&res.borrow().data.borrow());
+        let out = crate::backend::ops::mul(&res, &weights);
+        let ones = Array::ones(out.borrow().data.borrow().raw_dim());
+        out.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().data, t.borrow().grad);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a permutation")]
+    fn permute_invalid_axes_panics() {
+        let t = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[2, 3])));
+        permute(&t, &[0, 0]);
+    }
+
+    #[test]
+    fn squeeze_ok() {
+        let t = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[1, 3, 1, 2])));
+        assert_eq!(squeeze(&t, None).borrow().data.borrow().shape(), &[3, 2]);
+        assert_eq!(
+            squeeze(&t, Some(&[2])).borrow().data.borrow().shape(),
+            &[1, 3, 2]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "can't squeeze axis 1 with size 3")]
+    fn squeeze_invalid_axis_panics() {
+        let t = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[1, 3])));
+        squeeze(&t, Some(&[1]));
+    }
+
+    #[test]
+    fn unsqueeze_backward_ok() {
+        let t = Tensor::new_ref(&arr(&[3], vec![1., 2., 3.]));
+        let res = unsqueeze(&t, 1);
+        assert_eq!(res.borrow().data, arr(&[3, 1], vec![1., 2., 3.]));
+        assert_eq!(unsqueeze(&t, 0).borrow().data.borrow().shape(), &[1, 3]);
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, Array::ones(IxDyn(&[3])));
+    }
+
+    #[test]
+    fn flatten_ok() {
+        let t = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[2, 3, 4, 5])));
+        assert_eq!(flatten(&t, 1, 3).borrow().data.borrow().shape(), &[2, 60]);
+        assert_eq!(flatten(&t, 0, 1).borrow().data.borrow().shape(), &[6, 4, 5]);
+        assert_eq!(
+            flatten(&t, 2, 2).borrow().data.borrow().shape(),
+            &[2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn expand_ok() {
+        let t = Tensor::new_ref(&arr(&[2, 1], vec![1., 2.]));
+        let res = expand(&t, &[3, 2, 3]);
+        assert_eq!(res.borrow().data.borrow().shape(), &[3, 2, 3]);
+        assert_eq!(res.borrow().data.borrow()[[2, 1, 2]], 2.);
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, arr(&[2, 1], vec![9., 9.]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Incompatible shapes [2] and [3] in Expand op")]
+    fn expand_invalid_shape_panics() {
+        let t = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[2])));
+        expand(&t, &[3]);
+    }
+
+    #[test]
+    fn try_shape_ops_err() {
+        let t = Tensor::<f32>::new_ref(&Array::zeros(IxDyn(&[2, 3])));
+        assert!(matches!(
+            try_reshape(&t, &[5]),
+            Err(RustyGradError::ShapeMismatch { op: "Reshape", .. })
+        ));
+        assert_eq!(
+            try_transpose(&t, 0, 2).err(),
+            Some(RustyGradError::InvalidAxis {
+                op: "Transpose",
+                axis: 2,
+                ndim: 2
+            })
+        );
+        assert!(matches!(
+            try_permute(&t, &[1]),
+            Err(RustyGradError::InvalidArgument { op: "Permute", .. })
+        ));
+        assert!(matches!(
+            try_squeeze(&t, Some(&[0])),
+            Err(RustyGradError::InvalidArgument { op: "Squeeze", .. })
+        ));
+        assert!(matches!(
+            try_unsqueeze(&t, 3),
+            Err(RustyGradError::InvalidAxis {
+                op: "Unsqueeze",
+                ..
+            })
+        ));
+        assert!(matches!(
+            try_flatten(&t, 1, 0),
+            Err(RustyGradError::InvalidArgument { op: "Flatten", .. })
+        ));
+        assert!(matches!(
+            try_expand(&t, &[3, 3]),
+            Err(RustyGradError::ShapeMismatch { op: "Expand", .. })
+        ));
+        assert!(try_expand(&t, &[4, 2, 3]).is_ok());
+    }
+}
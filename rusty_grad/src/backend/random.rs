@@ -0,0 +1,178 @@
+//! Random number generator of the library. Every thread has its own generator,
+//! seeded from the OS entropy until [`manual_seed`] is called. It is used by
+//! the random constructors of [`creation`](crate::backend::creation) when they
+//! don't receive a generator, and so by the layer initializers, as well as by
+//! [`shuffle`] and [`permutation`]. Seeding it makes a whole training run
+//! reproducible:
+//!
+//! ```
+//! use rusty_grad::backend::random::{manual_seed, permutation};
+//!
+//! manual_seed(42);
+//! let p1 = permutation(10);
+//! manual_seed(42);
+//! assert_eq!(permutation(10), p1);
+//! ```
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::cell::RefCell;
+
+thread_local! {
+    /// Generator of the current thread
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
+/// Seeds the generator of the current thread
+pub fn manual_seed(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+/// Calls `f` with the generator of the current thread. The generator can't be
+/// accessed again from `f`.
+pub fn with_rng<R>(f: impl FnOnce(&mut StdRng) -> R) -> R {
+    RNG.with(|rng| f(&mut rng.borrow_mut()))
+}
+
+/// State of the generator of a thread, to restore it later with
+/// [`set_rng_state`]
+#[derive(Clone)]
+pub struct RngState(StdRng);
+
+/// Returns the state of the generator of the current thread
+pub fn get_rng_state() -> RngState {
+    RNG.with(|rng| RngState(rng.borrow().clone()))
+}
+
+/// Restores the generator of the current thread to `state`
+pub fn set_rng_state(state: RngState) {
+    RNG.with(|rng| *rng.borrow_mut() = state.0);
+}
+
+/// Guard returned by [`fork_rng`]. Restores the state of the generator when
+/// dropped.
+#[must_use = "the state of the generator is restored as soon as the guard is dropped"]
+pub struct ForkRngGuard {
+    state: Option<RngState>,
+}
+
+impl Drop for ForkRngGuard {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            set_rng_state(state);
+        }
+    }
+}
+
+/// Saves the state of the generator of the current thread and restores it
+/// when the returned guard is dropped, so that the random numbers drawn in the
+/// meantime don't change the ones drawn afterwards
+pub fn fork_rng() -> ForkRngGuard {
+    ForkRngGuard {
+        state: Some(get_rng_state()),
+    }
+}
+
+/// Shuffles `items` in place with the generator of the current thread
+pub fn shuffle<A>(items: &mut [A]) {
+    with_rng(|rng| items.shuffle(rng));
+}
+
+/// Returns a random permutation of `0..n`, e.g. the order of the samples of an
+/// epoch
+pub fn permutation(n: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..n).collect();
+    shuffle(&mut indices);
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::creation::{rand, randn};
+    use crate::backend::ops::{index_select, mean};
+    use crate::backend::tensor::RTensor;
+    use crate::nn::{components::Module, losses::squared_error, models::MLP};
+
+    #[test]
+    fn manual_seed_ok() {
+        manual_seed(0);
+        let t1 = randn::<f32>(&[4], None, true);
+        let p1 = permutation(8);
+        manual_seed(0);
+        assert_eq!(
+            randn::<f32>(&[4], None, true).borrow().data,
+            t1.borrow().data
+        );
+        assert_eq!(permutation(8), p1);
+        // The generator advances
+        assert_ne!(
+            randn::<f32>(&[4], None, true).borrow().data,
+            t1.borrow().data
+        );
+    }
+
+    #[test]
+    fn fork_and_restore_ok() {
+        manual_seed(1);
+        let state = get_rng_state();
+        let t1 = rand::<f64>(&[3], None, false);
+        {
+            let _guard = fork_rng();
+            rand::<f64>(&[100], None, false);
+        }
+        // The draws of the fork don't affect the following ones
+        let t2 = rand::<f64>(&[3], None, false);
+        set_rng_state(state);
+        assert_eq!(
+            rand::<f64>(&[3], None, false).borrow().data,
+            t1.borrow().data
+        );
+        assert_eq!(
+            rand::<f64>(&[3], None, false).borrow().data,
+            t2.borrow().data
+        );
+    }
+
+    #[test]
+    fn threads_have_own_generator() {
+        manual_seed(2);
+        let p = permutation(16);
+        // Seeding another thread doesn't change the generator of this one
+        std::thread::spawn(|| manual_seed(2)).join().unwrap();
+        manual_seed(2);
+        assert_eq!(permutation(16), p);
+    }
+
+    /// Trains a small model with shuffled mini-batches and returns the losses
+    fn train(seed: u64) -> Vec<f32> {
+        manual_seed(seed);
+        let model: MLP = MLP::new(3, vec![4, 1]);
+        let x: RTensor = randn(&[8, 3], None, false);
+        let y: RTensor = randn(&[8, 1], None, false);
+        let mut losses = vec![];
+        for _ in 0..5 {
+            let order = permutation(8);
+            for batch in order.chunks(4) {
+                let pred = model.forward(&index_select(&x, 0, batch));
+                let target = index_select(&y, 0, batch);
+                let loss = mean(&squared_error(&target, &pred), None, false);
+                model.zero_grad();
+                loss.borrow_mut().backward();
+                for param in model.parameters() {
+                    let delta = &param.borrow().grad * 0.1;
+                    *param.borrow().data.borrow_mut() -= &delta;
+                }
+                losses.push(loss.borrow().data.borrow().sum());
+            }
+        }
+        losses
+    }
+
+    #[test]
+    fn seeded_training_reproducible() {
+        let losses = train(7);
+        let bits = |losses: &[f32]| losses.iter().map(|l| l.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&train(7)), bits(&losses));
+        assert_ne!(bits(&train(8)), bits(&losses));
+    }
+}
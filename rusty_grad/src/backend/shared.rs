@@ -0,0 +1,185 @@
+//! Shared ownership of the tensors of a graph. By default the tensors are
+//! reference counted with [`Rc`](std::rc::Rc) and borrowed with
+//! [`RefCell`](std::cell::RefCell). With the `sync` feature they use
+//! [`Arc`](std::sync::Arc) and a [`RwLock`], so that tensors and models are
+//! `Send + Sync` and can be shared between threads. Both variants are borrowed
+//! with the same `borrow` and `borrow_mut` methods.
+
+#[cfg(feature = "sync")]
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(not(feature = "sync"))]
+pub use std::{cell::RefCell as Lock, rc::Rc as Shared};
+
+#[cfg(feature = "sync")]
+pub use std::sync::Arc as Shared;
+
+/// Lock with the borrowing interface of `RefCell`. The borrows block until the
+/// lock is available instead of panicking, so a tensor can be read from several
+/// threads at the same time while it is only written by one of them.
+///
+/// Unlike a `RefCell`, a thread must not borrow the same value twice at the
+/// same time: the second read waits for any writer that started waiting in
+/// between, which in turn waits for the first read, so both block forever.
+/// The ops borrow the data shared by several operands (the same tensor, or a
+/// tensor and its detached version) only once.
+///
+/// A thread that panics while writing doesn't poison the lock, like a
+/// `RefCell` is still usable after a panic.
+#[cfg(feature = "sync")]
+#[derive(Default)]
+pub struct Lock<T>(RwLock<T>);
+
+#[cfg(feature = "sync")]
+impl<T> Lock<T> {
+    pub fn new(value: T) -> Self {
+        Lock(RwLock::new(value))
+    }
+
+    /// Locks the value for reading
+    pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the value for writing
+    pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns a mutable reference to the value, no locking is needed since
+    /// the lock is borrowed mutably
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Bound of the closures stored in the graph (the backward functions). It is
+/// `Send + Sync` with the `sync` feature and it has no requirements otherwise.
+#[cfg(feature = "sync")]
+pub trait MaybeSendSync: Send + Sync {}
+
+#[cfg(feature = "sync")]
+impl<T: Send + Sync + ?Sized> MaybeSendSync for T {}
+
+/// Bound of the closures stored in the graph (the backward functions). It is
+/// `Send + Sync` with the `sync` feature and it has no requirements otherwise.
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSendSync {}
+
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSendSync for T {}
+
+#[cfg(all(test, feature = "sync"))]
+mod tests {
+    use super::*;
+    use crate::backend::ops::{concat, gt, mean, minimum, mul, where_};
+    use crate::backend::tensor::{RTensor, Tensor};
+    use crate::nn::{components::Module, models::MLP, Sequential};
+    use ndarray::prelude::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn lock_ok() {
+        let lock = Shared::new(Lock::new(1));
+        {
+            // Several simultaneous reads are allowed, like with a `RefCell`
+            let (a, b) = (lock.borrow(), lock.borrow());
+            assert_eq!(*a + *b, 2);
+        }
+        *lock.borrow_mut() += 1;
+        let handle = {
+            let lock = lock.clone();
+            std::thread::spawn(move || *lock.borrow_mut() *= 10)
+        };
+        handle.join().unwrap();
+        assert_eq!(*lock.borrow(), 20);
+        assert_eq!(Shared::try_unwrap(lock).ok().unwrap().into_inner(), 20);
+    }
+
+    #[test]
+    fn same_operand_while_writing_ok() {
+        // The ops read the data once, so they don't deadlock with the thread
+        // that keeps writing the gradient and the data
+        let w: RTensor = Tensor::new_ref(&Array::from_elem(4, 2.).into_dyn());
+        let detached = w.borrow().detach().to_ref();
+        let done = Shared::new(AtomicBool::new(false));
+        let writer = {
+            let (w, done) = (w.clone(), done.clone());
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    w.borrow_mut().accumulate_grad(&Array::ones(4).into_dyn());
+                    w.borrow().data.borrow_mut().fill(2.);
+                }
+            })
+        };
+        let mask = gt(&w, &w);
+        for _ in 0..10_000 {
+            assert_eq!(mul(&w, &w).borrow().data.borrow()[0], 4.);
+            assert_eq!(mul(&w, &detached).borrow().data.borrow()[0], 4.);
+            assert_eq!(minimum(&w, &w).borrow().data.borrow()[0], 2.);
+            assert_eq!(where_(&mask, &w, &w).borrow().data.borrow()[0], 2.);
+            assert_eq!(
+                concat(&[w.clone(), w.clone()], 0)
+                    .borrow()
+                    .data
+                    .borrow()
+                    .len(),
+                8
+            );
+        }
+        done.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+        assert!(w.borrow().grad[0] > 0.);
+    }
+
+    #[test]
+    fn models_send_sync() {
+        assert_send_sync::<RTensor>();
+        assert_send_sync::<MLP>();
+        assert_send_sync::<Sequential>();
+    }
+
+    #[test]
+    fn data_parallel_backward_ok() {
+        let model = Shared::new(MLP::new(3, vec![4, 1]));
+        let batches: Vec<RTensor> = (0..4)
+            .map(|i| Tensor::new_ref(&Array::from_elem((2, 3), i as f32 * 0.1).into_dyn()))
+            .collect();
+        let loss = |model: &MLP, x: &RTensor| {
+            let out = model.forward(x);
+            mean(&mul(&out, &out), None, false)
+        };
+
+        // Gradients accumulated by one thread per batch
+        let handles: Vec<_> = batches
+            .iter()
+            .map(|x| {
+                let (model, x) = (model.clone(), x.clone());
+                std::thread::spawn(move || loss(&model, &x).borrow_mut().backward())
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let parallel: Vec<ArrayD<f32>> = model
+            .parameters()
+            .iter()
+            .map(|p| p.borrow().grad.clone())
+            .collect();
+
+        model.zero_grad();
+        for x in batches.iter() {
+            loss(&model, x).borrow_mut().backward();
+        }
+        for (p, grad) in model.parameters().iter().zip(parallel) {
+            let diff = (&p.borrow().grad - &grad).mapv(f32::abs);
+            assert!(diff.iter().all(|&d| d < 1e-5));
+        }
+    }
+}
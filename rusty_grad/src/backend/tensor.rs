@@ -1,104 +1,538 @@
+use crate::backend::float::Float;
+use crate::backend::grad_mode::{is_grad_enabled, set_grad_enabled};
+use crate::backend::ops::add;
+use crate::backend::shared::{Lock, MaybeSendSync, Shared};
+use crate::error::{invalid_argument, unwrap_or_panic, Result, RustyGradError};
 use by_address::ByAddress;
 use core::fmt;
-use ndarray::prelude::*;
-use std::cell::RefCell;
-use std::collections::HashSet;
-use std::rc::Rc;
+use ndarray::{prelude::*, Data};
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
 
-pub struct Tensor {
-    pub data: ArrayD<f32>,
-    pub grad: ArrayD<f32>,
-    pub prev: Vec<RTensor>,
-    pub backward_fn: Box<dyn Fn(&Tensor)>,
+pub struct Tensor<T: Float = f32> {
+    /// Values of the tensor, shared with the tensors detached from it
+    pub data: TensorData<T>,
+    pub grad: ArrayD<T>,
+    pub prev: Vec<RTensor<T>>,
+    pub backward_fn: BackwardFn<T>,
+    /// Whether the gradient of the tensor is computed in the backpropagation.
+    /// Tensors that don't require grad have an empty `grad` buffer.
+    pub requires_grad: bool,
+    /// Gradient of the leaf tensor as a node of the graph, so it can be
+    /// differentiated again. It is only accumulated by the backpropagations
+    /// with `create_graph` set.
+    pub grad_graph: Option<RTensor<T>>,
+    /// Whether the history of the tensor was dropped by a backpropagation
+    graph_freed: bool,
 }
 
-pub type RTensor = Rc<RefCell<Tensor>>;
+pub type RTensor<T = f32> = Shared<Lock<Tensor<T>>>;
 
-impl Tensor {
-    pub fn new(data: &ArrayD<f32>) -> Self {
+/// Storage of the values of a tensor. It is shared by a tensor and the tensors
+/// detached from it, so the changes made in place through any of them are seen
+/// by all of them. It is borrowed like the tensor itself.
+pub struct TensorData<T: Float = f32>(pub(crate) Shared<Lock<ArrayD<T>>>);
+
+impl<T: Float> TensorData<T> {
+    pub fn new(data: ArrayD<T>) -> Self {
+        TensorData(Shared::new(Lock::new(data)))
+    }
+
+    /// Borrows the values for reading
+    pub fn borrow(&self) -> impl Deref<Target = ArrayD<T>> + '_ {
+        self.0.borrow()
+    }
+
+    /// Borrows the values for writing
+    pub fn borrow_mut(&self) -> impl DerefMut<Target = ArrayD<T>> + '_ {
+        self.0.borrow_mut()
+    }
+
+    /// Returns another handle to the same storage
+    pub(crate) fn share(&self) -> Self {
+        TensorData(self.0.clone())
+    }
+
+    /// Returns whether `self` and `other` are the same storage
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Shared::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<T: Float> PartialEq for TensorData<T> {
+    fn eq(&self, other: &Self) -> bool {
+        // The same storage is not borrowed twice, see `Lock`
+        self.ptr_eq(other) || *self.borrow() == *other.borrow()
+    }
+}
+
+impl<T: Float> PartialEq<ArrayD<T>> for TensorData<T> {
+    fn eq(&self, other: &ArrayD<T>) -> bool {
+        *self.borrow() == *other
+    }
+}
+
+impl<T: Float> fmt::Debug for TensorData<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.borrow(), f)
+    }
+}
+
+impl<T: Float> fmt::Display for TensorData<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.borrow(), f)
+    }
+}
+
+/// Function that receives a tensor and its gradient and returns the gradients
+/// of its children (in the order of `prev`). It is expressed with the autograd
+/// ops, so the returned gradients are differentiable when the graph is
+/// recorded (see [`BackwardOptions::create_graph`]).
+#[cfg(not(feature = "sync"))]
+pub type BackwardFn<T = f32> = Box<dyn Fn(&Tensor<T>, &RTensor<T>) -> Result<Vec<RTensor<T>>>>;
+
+/// Function that receives a tensor and its gradient and returns the gradients
+/// of its children (in the order of `prev`). It is expressed with the autograd
+/// ops, so the returned gradients are differentiable when the graph is
+/// recorded (see [`BackwardOptions::create_graph`]).
+#[cfg(feature = "sync")]
+pub type BackwardFn<T = f32> =
+    Box<dyn Fn(&Tensor<T>, &RTensor<T>) -> Result<Vec<RTensor<T>>> + Send + Sync>;
+
+impl<T: Float> Tensor<T> {
+    pub fn new(data: &ArrayD<T>) -> Self {
+        Self::leaf(TensorData::new(data.clone()))
+    }
+
+    /// Creates a leaf tensor that requires grad with the values of `data`
+    fn leaf(data: TensorData<T>) -> Self {
+        let grad = Array::zeros(data.borrow().raw_dim());
         Tensor {
-            data: data.clone(),
-            grad: Array::zeros(data.raw_dim()),
+            data,
+            grad,
             prev: vec![],
-            backward_fn: Box::new(|_| ()),
+            backward_fn: Box::new(|_, _| Ok(vec![])),
+            requires_grad: true,
+            grad_graph: None,
+            graph_freed: false,
         }
     }
 
-    pub fn new_ref(data: &ArrayD<f32>) -> RTensor {
-        Rc::new(RefCell::new(Self::new(data)))
+    /// Returns the tensor with `requires_grad` set, allocating or freeing the
+    /// gradient buffer accordingly
+    pub fn with_requires_grad(mut self, requires_grad: bool) -> Self {
+        self.set_requires_grad(requires_grad);
+        self
     }
 
-    pub fn to_ref(self) -> RTensor {
-        Rc::new(RefCell::new(self))
+    /// Sets whether the gradient of the tensor is computed in the
+    /// backpropagation, allocating or freeing the gradient buffer accordingly
+    pub fn set_requires_grad(&mut self, requires_grad: bool) {
+        self.grad = if requires_grad {
+            Array::zeros(self.data.borrow().raw_dim())
+        } else {
+            empty_grad()
+        };
+        self.requires_grad = requires_grad;
     }
 
-    fn topological_sort(
-        value: RTensor,
-        topo: &mut Vec<RTensor>,
-        visited: &mut HashSet<ByAddress<RTensor>>,
-    ) {
-        if !visited.contains(&ByAddress(value.clone())) {
-            visited.insert(ByAddress(value.clone()));
-            for child in &value.borrow().prev {
-                Self::topological_sort(child.clone(), topo, visited);
-            }
-            topo.push(value);
+    /// Creates the output tensor of an op from its `data`, its inputs `prev`
+    /// and the function that backpropagates its gradient to them. The graph is
+    /// only recorded if the grad mode is enabled and any input requires grad,
+    /// otherwise the output is a leaf that doesn't require grad.
+    pub fn from_op(
+        data: ArrayD<T>,
+        prev: Vec<RTensor<T>>,
+        backward_fn: impl Fn(&Tensor<T>, &RTensor<T>) -> Result<Vec<RTensor<T>>>
+            + MaybeSendSync
+            + 'static,
+    ) -> RTensor<T> {
+        if !is_grad_enabled() || !prev.iter().any(|p| p.borrow().requires_grad) {
+            return Self::new(&data).with_requires_grad(false).to_ref();
+        }
+        Tensor {
+            grad: Array::zeros(data.raw_dim()),
+            data: TensorData::new(data),
+            prev,
+            backward_fn: Box::new(backward_fn),
+            requires_grad: true,
+            grad_graph: None,
+            graph_freed: false,
         }
+        .to_ref()
     }
 
-    pub fn backward(&mut self) {
-        // Tracks the already visited values
-        let mut visited: HashSet<ByAddress<RTensor>> = HashSet::new();
+    /// Adds `grad` to the gradient of the tensor. It does nothing if the
+    /// tensor doesn't require grad.
+    pub fn accumulate_grad<S: Data<Elem = T>>(&mut self, grad: &ArrayBase<S, IxDyn>) {
+        if self.requires_grad {
+            self.grad += grad;
+        }
+    }
+
+    /// Resets the gradient of the tensor, including its differentiable version
+    pub fn zero_grad(&mut self) {
+        self.grad.fill(T::zero());
+        self.grad_graph = None;
+    }
+
+    pub fn new_ref(data: &ArrayD<T>) -> RTensor<T> {
+        Shared::new(Lock::new(Self::new(data)))
+    }
+
+    /// Creates a tensor with the given `shape` from the elements of `data` in
+    /// row-major order. Returns an error if the number of elements of `data`
+    /// doesn't match the `shape`.
+    pub fn try_from_shape_vec(shape: &[usize], data: Vec<T>) -> Result<Self> {
+        let n_elements = data.len();
+        ArrayD::from_shape_vec(IxDyn(shape), data)
+            .map(|arr| Self::new(&arr))
+            .map_err(|_| RustyGradError::ShapeMismatch {
+                op: "FromShapeVec",
+                lhs: vec![n_elements],
+                rhs: shape.to_vec(),
+            })
+    }
+
+    /// Panicking version of [`Tensor::try_from_shape_vec`]
+    pub fn from_shape_vec(shape: &[usize], data: Vec<T>) -> Self {
+        unwrap_or_panic(Self::try_from_shape_vec(shape, data))
+    }
+
+    pub fn to_ref(self) -> RTensor<T> {
+        Shared::new(Lock::new(self))
+    }
+
+    /// Returns the tensors of the graphs of `roots` (children before parents).
+    /// The traversal uses an explicit stack instead of recursion, so deep
+    /// graphs can't overflow the call stack.
+    #[allow(clippy::mutable_key_type)] // ByAddress only hashes the pointer
+    fn topological_sort(roots: &[RTensor<T>]) -> Vec<RTensor<T>> {
         // Stores the values in topological order
-        let mut topo: Vec<RTensor> = vec![];
+        let mut topo: Vec<RTensor<T>> = vec![];
+        // Tracks the already visited values
+        let mut visited: HashSet<ByAddress<RTensor<T>>> = HashSet::new();
+        // Each value is pushed twice: first to visit its children and then
+        // (with the flag set) to add it to `topo` once they are all added
+        let mut stack: Vec<(RTensor<T>, bool)> =
+            roots.iter().rev().map(|r| (r.clone(), false)).collect();
+        while let Some((value, children_done)) = stack.pop() {
+            if children_done {
+                topo.push(value);
+                continue;
+            }
+            if !visited.insert(ByAddress(value.clone())) {
+                continue;
+            }
+            stack.push((value.clone(), true));
+            for child in value.borrow().prev.iter().rev() {
+                if !visited.contains(&ByAddress(child.clone())) {
+                    stack.push((child.clone(), false));
+                }
+            }
+        }
+        topo
+    }
+
+    /// Returns a leaf tensor that shares the data of this tensor, without
+    /// history and that doesn't require grad. The ops computed from it are not
+    /// backpropagated to this tensor, but the changes made in place to the
+    /// data of either of them are seen by both.
+    pub fn detach(&self) -> Self {
+        Self::leaf(self.data.share()).with_requires_grad(false)
+    }
+
+    /// Returns a leaf tensor with a copy of the data and the gradient of this
+    /// tensor, that requires grad if this tensor does, but without history
+    pub fn clone_with_grad(&self) -> Self {
+        let mut res = Self::new(&self.data.borrow());
+        res.grad = self.grad.clone();
+        res.requires_grad = self.requires_grad;
+        res
+    }
+
+    /// Drops the history of a non-leaf tensor once it has been backpropagated.
+    /// Backpropagating through it again returns an error.
+    fn free_graph(&mut self) {
+        if !self.prev.is_empty() {
+            self.prev.clear();
+            self.backward_fn = Box::new(|_, _| Err(RustyGradError::GraphFreed));
+            self.graph_freed = true;
+        }
+    }
+
+    /// Stores `grad`, the total gradient of the tensor in a backpropagation.
+    /// Leaves accumulate it, the rest of tensors just keep the last one.
+    fn store_grad(&mut self, grad: &RTensor<T>, create_graph: bool) {
+        if !self.prev.is_empty() {
+            self.grad = grad.borrow().data.borrow().clone();
+            return;
+        }
+        self.accumulate_grad(&grad.borrow().data.borrow());
+        if create_graph {
+            self.grad_graph = Some(match self.grad_graph.take() {
+                Some(acc) => add(&acc, grad),
+                None => grad.clone(),
+            });
+        }
+    }
+
+    /// Backpropagates `grad`, the gradient of this tensor, through its graph.
+    /// If `targets` is `None` the gradients are stored in the tensors of the
+    /// graph (accumulated in the leaves). Otherwise the tensors are not
+    /// modified and the gradients of the `targets` in the graph of `self` are
+    /// returned instead (`None` for the targets that are not reached).
+    /// `self` is not compared with the targets, since it is already borrowed.
+    #[allow(clippy::mutable_key_type)] // ByAddress only hashes the pointer
+    fn run_backward(
+        &mut self,
+        grad: RTensor<T>,
+        options: BackwardOptions,
+        targets: Option<&[RTensor<T>]>,
+    ) -> Result<Vec<Option<RTensor<T>>>> {
+        if !self.requires_grad {
+            return invalid_argument("Backward", "the tensor doesn't require grad".to_string());
+        }
+        // The backward functions are computed with the autograd ops, so the
+        // graph of the gradients is only recorded if it is requested
+        let _guard = set_grad_enabled(options.create_graph);
+        let free_graph = !options.retain_graph && !options.create_graph;
 
         // Compute the topological order from the childs of `self`. We already know
         // that `self` must be the fist value in topological order
-        for child in &self.prev {
-            Self::topological_sort(child.clone(), &mut topo, &mut visited);
+        let topo = Self::topological_sort(&self.prev);
+        // A freed graph is detected before any gradient is accumulated, so
+        // that the failed backpropagation doesn't modify the leaves it reaches
+        if self.graph_freed || topo.iter().any(|v| v.borrow().graph_freed) {
+            return Err(RustyGradError::GraphFreed);
         }
 
-        // Set the initial gradients to 1.0 to start the backpropagation
-        self.grad.fill(1.0);
+        // Gradients of the tensors that are pending to be backpropagated
+        let mut grads: HashMap<ByAddress<RTensor<T>>, RTensor<T>> = HashMap::new();
+        let mut captured: Vec<Option<RTensor<T>>> = vec![None; targets.map_or(0, <[_]>::len)];
+        if targets.is_none() {
+            self.store_grad(&grad, options.create_graph);
+        }
         // Apply the backpropagation in topological order (from parents to childs)
-        (self.backward_fn)(self);
+        propagate(self, &grad, &mut grads)?;
+        if free_graph {
+            self.free_graph();
+        }
         for v in topo.iter().rev() {
-            (v.borrow().backward_fn)(&v.borrow());
+            // The tensors that are not reached by the gradient are skipped
+            let Some(grad) = grads.remove(&ByAddress(v.clone())) else {
+                continue;
+            };
+            match targets {
+                Some(targets) => {
+                    for (i, target) in targets.iter().enumerate() {
+                        if Shared::ptr_eq(target, v) {
+                            captured[i] = Some(grad.clone());
+                        }
+                    }
+                }
+                None => v.borrow_mut().store_grad(&grad, options.create_graph),
+            }
+            propagate(&v.borrow(), &grad, &mut grads)?;
+            if free_graph {
+                v.borrow_mut().free_graph();
+            }
+        }
+        Ok(captured)
+    }
+
+    /// Backpropagates `grad`, the gradient of this tensor, accumulating the
+    /// gradients of all the tensors of its graph
+    fn backpropagate(&mut self, grad: ArrayD<T>, options: BackwardOptions) -> Result<()> {
+        let grad = Self::new(&grad).with_requires_grad(false).to_ref();
+        self.run_backward(grad, options, None).map(|_| ())
+    }
+
+    /// Returns the gradients of `targets` backpropagating `grad`, the gradient
+    /// of `root` (that can be part of a graph itself). Unlike `backward`, the
+    /// gradients are not stored in the tensors of the graph. The targets that
+    /// are not reached by the gradient get `None`.
+    pub(crate) fn try_grad_of(
+        root: &RTensor<T>,
+        grad: RTensor<T>,
+        targets: &[RTensor<T>],
+        options: BackwardOptions,
+    ) -> Result<Vec<Option<RTensor<T>>>> {
+        let mut captured = root
+            .borrow_mut()
+            .run_backward(grad.clone(), options, Some(targets))?;
+        for (captured, target) in captured.iter_mut().zip(targets) {
+            if Shared::ptr_eq(target, root) {
+                *captured = Some(grad.clone());
+            }
+        }
+        Ok(captured)
+    }
+
+    /// Fallible version of [`Tensor::backward_with_options`], returns an error
+    /// if the tensor is not a scalar, doesn't require grad, the graph was
+    /// already freed or a node of the graph is not valid
+    pub fn try_backward_with_options(&mut self, options: BackwardOptions) -> Result<()> {
+        if self.data.borrow().len() != 1 {
+            return Err(RustyGradError::NonScalarBackward {
+                shape: self.data.borrow().shape().to_vec(),
+            });
+        }
+        let seed = Array::ones(self.data.borrow().raw_dim());
+        self.backpropagate(seed, options)
+    }
+
+    /// Backpropagates from this scalar tensor (a tensor with a single element)
+    /// with the given `options`, accumulating the gradients of all the tensors
+    /// of its graph
+    pub fn backward_with_options(&mut self, options: BackwardOptions) {
+        unwrap_or_panic(self.try_backward_with_options(options))
+    }
+
+    /// Fallible version of [`Tensor::backward`], returns an error if the tensor
+    /// is not a scalar, doesn't require grad, the graph was already freed or a
+    /// node of the graph is not valid
+    pub fn try_backward(&mut self) -> Result<()> {
+        self.try_backward_with_options(BackwardOptions::default())
+    }
+
+    /// Backpropagates from this scalar tensor (a tensor with a single element)
+    /// with the default options, accumulating the gradients of all the tensors
+    /// of its graph and freeing the graph afterwards
+    pub fn backward(&mut self) {
+        unwrap_or_panic(self.try_backward())
+    }
+
+    /// Fallible version of [`Tensor::backward_with`], returns an error if the
+    /// shape of `grad` doesn't match the tensor, the tensor doesn't require
+    /// grad, the graph was already freed or a node of the graph is not valid
+    pub fn try_backward_with(&mut self, grad: ArrayD<T>) -> Result<()> {
+        if grad.shape() != self.data.borrow().shape() {
+            return Err(RustyGradError::ShapeMismatch {
+                op: "Backward",
+                lhs: self.data.borrow().shape().to_vec(),
+                rhs: grad.shape().to_vec(),
+            });
+        }
+        self.backpropagate(grad, BackwardOptions::default())
+    }
+
+    /// Backpropagates `grad`, the upstream gradient of this tensor, with the
+    /// default options. The gradients accumulated in the graph are the
+    /// vector-Jacobian products of `grad`, so it allows backpropagating from
+    /// tensors that are not scalars.
+    pub fn backward_with(&mut self, grad: ArrayD<T>) {
+        unwrap_or_panic(self.try_backward_with(grad))
+    }
+}
+
+/// Options of the backpropagation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BackwardOptions {
+    /// Keeps the graph after the backpropagation so it can be backpropagated
+    /// again. Otherwise the `prev` and backward function of the non-leaf
+    /// tensors are dropped as soon as they are used.
+    pub retain_graph: bool,
+    /// Records the graph of the backpropagation, so the gradients can be
+    /// differentiated again (e.g. for Hessian-vector products or gradient
+    /// penalties). The differentiable gradients of the leaves are accumulated
+    /// in their `grad_graph`. It implies `retain_graph`.
+    pub create_graph: bool,
+}
+
+/// Computes the gradients of the children of `t` from its gradient `grad`,
+/// adding them to the pending gradients `grads`
+#[allow(clippy::mutable_key_type)] // ByAddress only hashes the pointer
+fn propagate<T: Float>(
+    t: &Tensor<T>,
+    grad: &RTensor<T>,
+    grads: &mut HashMap<ByAddress<RTensor<T>>, RTensor<T>>,
+) -> Result<()> {
+    let child_grads = (t.backward_fn)(t, grad)?;
+    if child_grads.len() != t.prev.len() {
+        return Err(RustyGradError::InvalidChildren {
+            op: "Backward",
+            expected: t.prev.len(),
+            got: child_grads.len(),
+        });
+    }
+    for (child, child_grad) in t.prev.iter().zip(child_grads) {
+        if !child.borrow().requires_grad {
+            continue;
         }
+        let key = ByAddress(child.clone());
+        let acc = match grads.remove(&key) {
+            Some(acc) => add(&acc, &child_grad),
+            None => child_grad,
+        };
+        grads.insert(key, acc);
     }
+    Ok(())
 }
 
+/// Gradient buffer of the tensors that don't require grad
+fn empty_grad<T: Float>() -> ArrayD<T> {
+    ArrayD::zeros(IxDyn(&[0]))
+}
+
+/// Creates a tensor from a shape and its elements. The element type is
+/// inferred from the literals or the context, e.g. `&[1.0f64, 2.0]` creates
+/// a `Tensor<f64>`.
 #[macro_export]
 macro_rules! tensor {
 	(&[$($s:expr),*], &[$($d:expr),*]) => {
-        {
-            let shape = IxDyn(&[$($s,)*]);
-            let data = vec![$($d,)*];
-            let data_array = &ArrayD::from_shape_vec(shape, data).unwrap();
-            Tensor::new(data_array)
-        }
+        $crate::backend::tensor::Tensor::from_shape_vec(&[$($s,)*], vec![$($d,)*])
 	};
 }
 
+/// Like [`tensor!`], but returns a shared reference to the tensor
 #[macro_export]
 macro_rules! rtensor {
 	(&[$($s:expr),*], &[$($d:expr),*]) => {
-        {
-            let shape = IxDyn(&[$($s,)*]);
-            let data = vec![$($d,)*];
-            let data_array = &ArrayD::from_shape_vec(shape, data).unwrap();
-            Tensor::new_ref(data_array)
-        }
+        $crate::backend::tensor::Tensor::from_shape_vec(&[$($s,)*], vec![$($d,)*]).to_ref()
 	};
 }
 
-impl fmt::Display for Tensor {
+/// Fallible version of [`tensor!`], returns a `Result` instead of panicking if
+/// the number of elements doesn't match the shape
+#[macro_export]
+macro_rules! try_tensor {
+	(&[$($s:expr),*], &[$($d:expr),*]) => {
+        $crate::backend::tensor::Tensor::try_from_shape_vec(&[$($s,)*], vec![$($d,)*])
+	};
+}
+
+/// Fallible version of [`rtensor!`], returns a `Result` instead of panicking
+/// if the number of elements doesn't match the shape
+#[macro_export]
+macro_rules! try_rtensor {
+	(&[$($s:expr),*], &[$($d:expr),*]) => {
+        $crate::backend::tensor::Tensor::try_from_shape_vec(&[$($s,)*], vec![$($d,)*])
+            .map($crate::backend::tensor::Tensor::to_ref)
+	};
+}
+
+impl<T: Float> Drop for Tensor<T> {
+    fn drop(&mut self) {
+        // Free the graph iteratively. Otherwise dropping the last reference to
+        // a long chain of ops drops each `prev` recursively and can overflow
+        // the stack.
+        let mut stack = std::mem::take(&mut self.prev);
+        while let Some(child) = stack.pop() {
+            if let Ok(mut child) = Shared::try_unwrap(child) {
+                stack.append(&mut child.get_mut().prev);
+            }
+        }
+    }
+}
+
+impl<T: Float> fmt::Display for Tensor<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
             "Tensor(data={}, shape={:?})",
             self.data,
-            self.data.shape()
+            self.data.borrow().shape()
         )
     }
 }
@@ -106,6 +540,7 @@ impl fmt::Display for Tensor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::ops::{add, mul};
 
     fn zero_array(shape: &[usize]) -> ArrayD<f32> {
         ArrayD::zeros(IxDyn(shape))
@@ -133,6 +568,15 @@ mod tests {
         assert_eq!(t.borrow().grad, zero_array(shape));
     }
 
+    #[test]
+    fn macro_infers_element_type() {
+        let t = rtensor![&[2], &[1.0f64, 2.0]];
+        let data: ArrayD<f64> = t.borrow().data.borrow().clone();
+        assert_eq!(data, array![1.0, 2.0].into_dyn());
+        let t: Tensor<f64> = tensor![&[1], &[0.1]];
+        assert_eq!(t.data.borrow()[0], 0.1f64);
+    }
+
     #[test]
     fn macro_tensor_ok() {
         let shape = &[2, 3];
@@ -154,4 +598,270 @@ mod tests {
         assert_eq!(t.borrow().data, arr);
         assert_eq!(t.borrow().grad, zero_array(shape));
     }
+
+    #[test]
+    fn topological_sort_ok() {
+        let a = rtensor![&[1], &[1.0]];
+        let b = add(&a, &a);
+        let c = add(&b, &a);
+        let d = add(&c, &b);
+        let topo = Tensor::topological_sort(std::slice::from_ref(&d));
+        let position = |t: &RTensor| topo.iter().position(|x| Shared::ptr_eq(x, t)).unwrap();
+        assert_eq!(topo.len(), 4);
+        assert!(position(&a) < position(&b));
+        assert!(position(&b) < position(&c));
+        assert!(position(&c) < position(&d));
+    }
+
+    #[test]
+    fn backward_deep_graph_ok() {
+        // A recursive traversal (or drop) of this graph overflows the stack
+        let n_ops = 1_000_000;
+        let x = rtensor![&[1], &[0.0]];
+        let one = rtensor![&[1], &[1.0]];
+        let mut res = x.clone();
+        for _ in 0..n_ops {
+            res = add(&res, &one);
+        }
+        assert_eq!(res.borrow().data.borrow()[[0]], n_ops as f32);
+
+        res.borrow_mut().backward();
+        assert_eq!(x.borrow().grad[[0]], 1.0);
+        assert_eq!(one.borrow().grad[[0]], n_ops as f32);
+    }
+
+    #[test]
+    fn detach_ok() {
+        let x = rtensor![&[2], &[1.0, 2.0]];
+        let y = add(&x, &x);
+        let detached = y.borrow().detach().to_ref();
+        assert_eq!(detached.borrow().data, y.borrow().data);
+        assert!(!detached.borrow().requires_grad);
+        assert!(detached.borrow().prev.is_empty());
+
+        // The gradient doesn't flow through the detached tensor
+        let res = add(&detached, &x);
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(x.borrow().grad, ArrayD::ones(IxDyn(&[2])));
+
+        // The data is shared, so the changes made in place are seen by both
+        *detached.borrow().data.borrow_mut() *= 2.0;
+        assert_eq!(y.borrow().data, array![4.0, 8.0].into_dyn());
+        y.borrow().data.borrow_mut()[[0]] = 0.0;
+        assert_eq!(detached.borrow().data, array![0.0, 8.0].into_dyn());
+        assert_eq!(
+            mul(&y, &detached).borrow().data,
+            array![0.0, 64.0].into_dyn()
+        );
+    }
+
+    #[test]
+    fn clone_with_grad_ok() {
+        let x = rtensor![&[2], &[1.0, 2.0]];
+        let y = add(&x, &x);
+        y.borrow_mut().grad.fill(3.0);
+        let cloned = y.borrow().clone_with_grad();
+        assert_eq!(cloned.data, y.borrow().data);
+        assert_eq!(cloned.grad, y.borrow().grad);
+        assert!(cloned.requires_grad);
+        assert!(cloned.prev.is_empty());
+    }
+
+    #[test]
+    fn backward_frees_graph() {
+        let x = rtensor![&[1], &[2.0]];
+        let y = add(&x, &x);
+        let res = add(&y, &x);
+        res.borrow_mut().backward();
+        assert_eq!(x.borrow().grad[[0]], 3.0);
+        assert!(res.borrow().prev.is_empty());
+        assert!(y.borrow().prev.is_empty());
+
+        assert_eq!(
+            res.borrow_mut().try_backward(),
+            Err(RustyGradError::GraphFreed)
+        );
+        // The leaves can still be used in new graphs
+        let res = add(&x, &x);
+        res.borrow_mut().backward();
+        assert_eq!(x.borrow().grad[[0]], 5.0);
+    }
+
+    #[test]
+    fn backward_freed_subgraph_err() {
+        let x = rtensor![&[1], &[2.0]];
+        let y = add(&x, &x);
+        add(&y, &x).borrow_mut().backward();
+        assert_eq!(x.borrow().grad[[0]], 3.0);
+
+        // A new graph that reaches the freed `y` fails without accumulating
+        // any gradient, neither in `x` nor in the new leaf `w`
+        let w = rtensor![&[1], &[1.0]];
+        let res = add(&y, &add(&w, &x));
+        assert_eq!(
+            res.borrow_mut().try_backward(),
+            Err(RustyGradError::GraphFreed)
+        );
+        assert_eq!(x.borrow().grad[[0]], 3.0);
+        assert_eq!(w.borrow().grad[[0]], 0.0);
+    }
+
+    #[test]
+    fn backward_retain_graph_ok() {
+        let x = rtensor![&[1], &[2.0]];
+        let res = add(&add(&x, &x), &x);
+        let options = BackwardOptions {
+            retain_graph: true,
+            ..Default::default()
+        };
+        res.borrow_mut().backward_with_options(options);
+        res.borrow_mut().backward_with_options(options);
+        // The gradients of both backpropagations are accumulated
+        assert_eq!(x.borrow().grad[[0]], 6.0);
+        assert_eq!(res.borrow().prev.len(), 2);
+    }
+
+    #[test]
+    fn backward_non_scalar_err() {
+        let x = rtensor![&[2], &[1.0, 2.0]];
+        let res = add(&x, &x);
+        assert_eq!(
+            res.borrow_mut().try_backward(),
+            Err(RustyGradError::NonScalarBackward { shape: vec![2] })
+        );
+
+        // Tensors with a single element are scalars whatever their shape is
+        let x = rtensor![&[1, 1], &[1.0]];
+        let res = add(&x, &x);
+        res.borrow_mut().backward();
+        assert_eq!(x.borrow().grad[[0, 0]], 2.0);
+    }
+
+    #[test]
+    fn backward_with_ok() {
+        let x = rtensor![&[2], &[1.0, 2.0]];
+        let res = add(&x, &x);
+        res.borrow_mut()
+            .backward_with(ArrayD::from_shape_vec(IxDyn(&[2]), vec![1., -3.]).unwrap());
+        assert_eq!(
+            x.borrow().grad,
+            ArrayD::from_shape_vec(IxDyn(&[2]), vec![2., -6.]).unwrap()
+        );
+    }
+
+    #[test]
+    fn try_backward_with_err() {
+        let x = rtensor![&[2], &[1.0, 2.0]];
+        let res = add(&x, &x);
+        assert_eq!(
+            res.borrow_mut().try_backward_with(zero_array(&[3])),
+            Err(RustyGradError::ShapeMismatch {
+                op: "Backward",
+                lhs: vec![2],
+                rhs: vec![3]
+            })
+        );
+    }
+
+    /// Computes the second derivative of `f` at `x` differentiating the
+    /// gradient computed with `create_graph`
+    fn second_derivative(f: impl Fn(&RTensor) -> RTensor, value: f32) -> f32 {
+        let x = rtensor![&[1], &[value]];
+        let options = BackwardOptions {
+            create_graph: true,
+            ..Default::default()
+        };
+        f(&x).borrow_mut().backward_with_options(options);
+        let grad = x.borrow_mut().grad_graph.take().unwrap();
+        x.borrow_mut().zero_grad();
+        grad.borrow_mut().backward();
+        let res = x.borrow().grad[[0]];
+        res
+    }
+
+    #[test]
+    fn backward_create_graph_ok() {
+        use crate::backend::ops::{exp, log, mul, pow, sin, sqrt};
+        use crate::nn::activations::tanh;
+
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4 * b.abs().max(1.);
+        let x = 0.7f32;
+        assert!(close(second_derivative(|t| pow(t, 3.), x), 6. * x));
+        assert!(close(
+            second_derivative(|t| mul(t, &exp(t)), x),
+            (x + 2.) * x.exp()
+        ));
+        assert!(close(second_derivative(log, x), -1. / (x * x)));
+        assert!(close(second_derivative(sin, x), -x.sin()));
+        assert!(close(second_derivative(sqrt, x), -0.25 * x.powf(-1.5)));
+        let th = x.tanh();
+        assert!(close(
+            second_derivative(|t| tanh(t.clone()), x),
+            -2. * th * (1. - th * th)
+        ));
+    }
+
+    #[test]
+    fn backward_create_graph_gradient_penalty_ok() {
+        use crate::backend::ops::{mul, pow, sum};
+
+        // f = sum(x * x * y), so df/dx = 2xy and the penalty sum((df/dx)^2)
+        // has gradients 8xy^2 and 8x^2y
+        let x = rtensor![&[2], &[1.0, 2.0]];
+        let y = rtensor![&[2], &[3.0, 4.0]];
+        let f = sum(&mul(&mul(&x, &x), &y), None, false);
+        f.borrow_mut().backward_with_options(BackwardOptions {
+            create_graph: true,
+            ..Default::default()
+        });
+        let dx = x.borrow_mut().grad_graph.take().unwrap();
+        assert_eq!(
+            dx.borrow().data,
+            ArrayD::from_shape_vec(IxDyn(&[2]), vec![6., 16.]).unwrap()
+        );
+        // The differentiable gradients of all the leaves are stored
+        assert!(y.borrow().grad_graph.is_some());
+
+        x.borrow_mut().zero_grad();
+        y.borrow_mut().zero_grad();
+        let penalty = sum(&pow(&dx, 2.), None, false);
+        penalty.borrow_mut().backward();
+        assert_eq!(
+            x.borrow().grad,
+            ArrayD::from_shape_vec(IxDyn(&[2]), vec![72., 256.]).unwrap()
+        );
+        assert_eq!(
+            y.borrow().grad,
+            ArrayD::from_shape_vec(IxDyn(&[2]), vec![24., 128.]).unwrap()
+        );
+    }
+
+    #[test]
+    fn try_from_shape_vec_err() {
+        assert_eq!(
+            Tensor::try_from_shape_vec(&[2, 2], vec![1., 2., 3.]).err(),
+            Some(RustyGradError::ShapeMismatch {
+                op: "FromShapeVec",
+                lhs: vec![3],
+                rhs: vec![2, 2]
+            })
+        );
+    }
+
+    #[test]
+    fn macro_try_rtensor_ok() {
+        let t = try_rtensor![&[2], &[1.0, 2.0]].unwrap();
+        assert_eq!(
+            t.borrow().data,
+            ArrayD::from_shape_vec(IxDyn(&[2]), vec![1., 2.]).unwrap()
+        );
+        assert!(try_tensor![&[3], &[1.0, 2.0]].is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "Incompatible shapes [3] and [2, 2] in FromShapeVec op")]
+    fn macro_tensor_invalid_shape_panics() {
+        tensor![&[2, 2], &[1.0, 2.0, 3.0]];
+    }
 }
@@ -0,0 +1,277 @@
+use crate::backend::float::Float;
+use crate::backend::ops::{add, diff, div, mul, neg};
+use crate::backend::tensor::{RTensor, Tensor};
+use ndarray::prelude::*;
+use std::ops::{Add, AddAssign, Deref, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+
+/// Handle to a tensor of the graph that implements the arithmetic operators.
+/// Every operator dispatches to the autograd op in `backend::ops`, so the
+/// result is a new node of the graph:
+///
+/// ```
+/// use ndarray::prelude::*;
+/// use rusty_grad::backend::var::Var;
+///
+/// let x: Var = Var::new(&array![1., 2., 3.].into_dyn());
+/// let y = &x * &x - 2. * &x + 1.;
+/// y.borrow_mut().backward_with(Array::ones(3).into_dyn());
+/// assert_eq!(x.borrow().grad, array![0., 2., 4.].into_dyn());
+/// ```
+///
+/// The compound assignment operators (`+=`, `-=`, ...) never modify the data
+/// in place, they make the handle point to the new node instead.
+#[derive(Clone)]
+pub struct Var<T: Float = f32>(pub RTensor<T>);
+
+impl<T: Float> Var<T> {
+    /// Creates a leaf tensor from `data`
+    pub fn new(data: &ArrayD<T>) -> Self {
+        Var(Tensor::new_ref(data))
+    }
+
+    /// Returns the underlying tensor
+    pub fn into_inner(self) -> RTensor<T> {
+        self.0
+    }
+}
+
+impl<T: Float> Deref for Var<T> {
+    type Target = RTensor<T>;
+
+    fn deref(&self) -> &RTensor<T> {
+        &self.0
+    }
+}
+
+impl<T: Float> From<RTensor<T>> for Var<T> {
+    fn from(t: RTensor<T>) -> Self {
+        Var(t)
+    }
+}
+
+impl<T: Float> From<Var<T>> for RTensor<T> {
+    fn from(v: Var<T>) -> Self {
+        v.0
+    }
+}
+
+/// Creates a 0-D constant tensor to operate with a scalar through broadcasting
+fn scalar<T: Float>(value: T) -> RTensor<T> {
+    Tensor::new(&arr0(value).into_dyn())
+        .with_requires_grad(false)
+        .to_ref()
+}
+
+/// Implements a binary operator for all the combinations of owned and
+/// borrowed `Var`s and for scalars on the right side
+macro_rules! impl_binary_op {
+    ($trait:ident, $method:ident, $op:ident) => {
+        impl<T: Float> $trait<&Var<T>> for &Var<T> {
+            type Output = Var<T>;
+
+            fn $method(self, rhs: &Var<T>) -> Var<T> {
+                Var($op(&self.0, &rhs.0))
+            }
+        }
+
+        impl<T: Float> $trait<Var<T>> for &Var<T> {
+            type Output = Var<T>;
+
+            fn $method(self, rhs: Var<T>) -> Var<T> {
+                self.$method(&rhs)
+            }
+        }
+
+        impl<T: Float> $trait<&Var<T>> for Var<T> {
+            type Output = Var<T>;
+
+            fn $method(self, rhs: &Var<T>) -> Var<T> {
+                (&self).$method(rhs)
+            }
+        }
+
+        impl<T: Float> $trait<Var<T>> for Var<T> {
+            type Output = Var<T>;
+
+            fn $method(self, rhs: Var<T>) -> Var<T> {
+                (&self).$method(&rhs)
+            }
+        }
+
+        impl<T: Float> $trait<T> for &Var<T> {
+            type Output = Var<T>;
+
+            fn $method(self, rhs: T) -> Var<T> {
+                Var($op(&self.0, &scalar(rhs)))
+            }
+        }
+
+        impl<T: Float> $trait<T> for Var<T> {
+            type Output = Var<T>;
+
+            fn $method(self, rhs: T) -> Var<T> {
+                (&self).$method(rhs)
+            }
+        }
+    };
+}
+
+impl_binary_op!(Add, add, add);
+impl_binary_op!(Sub, sub, diff);
+impl_binary_op!(Mul, mul, mul);
+impl_binary_op!(Div, div, div);
+
+/// Implements a binary operator with a scalar of type `$t` on the left side.
+/// It can't be generic over the element type because of the orphan rule.
+macro_rules! impl_scalar_lhs_op {
+    ($trait:ident, $method:ident, $op:ident, $t:ty) => {
+        impl $trait<&Var<$t>> for $t {
+            type Output = Var<$t>;
+
+            fn $method(self, rhs: &Var<$t>) -> Var<$t> {
+                Var($op(&scalar(self), &rhs.0))
+            }
+        }
+
+        impl $trait<Var<$t>> for $t {
+            type Output = Var<$t>;
+
+            fn $method(self, rhs: Var<$t>) -> Var<$t> {
+                self.$method(&rhs)
+            }
+        }
+    };
+}
+
+impl_scalar_lhs_op!(Add, add, add, f32);
+impl_scalar_lhs_op!(Sub, sub, diff, f32);
+impl_scalar_lhs_op!(Mul, mul, mul, f32);
+impl_scalar_lhs_op!(Div, div, div, f32);
+impl_scalar_lhs_op!(Add, add, add, f64);
+impl_scalar_lhs_op!(Sub, sub, diff, f64);
+impl_scalar_lhs_op!(Mul, mul, mul, f64);
+impl_scalar_lhs_op!(Div, div, div, f64);
+
+/// Implements a compound assignment operator by rebinding the handle to the
+/// result of the binary operator
+macro_rules! impl_assign_op {
+    ($trait:ident, $method:ident, $op_method:ident) => {
+        impl<T: Float> $trait<&Var<T>> for Var<T> {
+            fn $method(&mut self, rhs: &Var<T>) {
+                *self = (&*self).$op_method(rhs);
+            }
+        }
+
+        impl<T: Float> $trait<Var<T>> for Var<T> {
+            fn $method(&mut self, rhs: Var<T>) {
+                *self = (&*self).$op_method(&rhs);
+            }
+        }
+
+        impl<T: Float> $trait<T> for Var<T> {
+            fn $method(&mut self, rhs: T) {
+                *self = (&*self).$op_method(rhs);
+            }
+        }
+    };
+}
+
+impl_assign_op!(AddAssign, add_assign, add);
+impl_assign_op!(SubAssign, sub_assign, sub);
+impl_assign_op!(MulAssign, mul_assign, mul);
+impl_assign_op!(DivAssign, div_assign, div);
+
+impl<T: Float> Neg for &Var<T> {
+    type Output = Var<T>;
+
+    fn neg(self) -> Var<T> {
+        Var(neg(&self.0))
+    }
+}
+
+impl<T: Float> Neg for Var<T> {
+    type Output = Var<T>;
+
+    fn neg(self) -> Var<T> {
+        -&self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(data: Vec<f32>) -> Var {
+        Var::new(&ArrayD::from_shape_vec(IxDyn(&[data.len()]), data).unwrap())
+    }
+
+    fn arr(data: Vec<f32>) -> ArrayD<f32> {
+        ArrayD::from_shape_vec(IxDyn(&[data.len()]), data).unwrap()
+    }
+
+    #[test]
+    fn tensor_tensor_ops_ok() {
+        let v1 = var(vec![1., 2., 3.]);
+        let v2 = var(vec![2., 4., 6.]);
+        assert_eq!((&v1 + &v2).borrow().data, arr(vec![3., 6., 9.]));
+        assert_eq!((&v1 - &v2).borrow().data, arr(vec![-1., -2., -3.]));
+        assert_eq!((&v1 * &v2).borrow().data, arr(vec![2., 8., 18.]));
+        assert_eq!((&v2 / &v1).borrow().data, arr(vec![2., 2., 2.]));
+        assert_eq!((-&v1).borrow().data, arr(vec![-1., -2., -3.]));
+        assert_eq!((v1 + v2).borrow().data, arr(vec![3., 6., 9.]));
+    }
+
+    #[test]
+    fn tensor_scalar_ops_ok() {
+        let v = var(vec![1., 2., 4.]);
+        assert_eq!((&v + 1.).borrow().data, arr(vec![2., 3., 5.]));
+        assert_eq!((1. + &v).borrow().data, arr(vec![2., 3., 5.]));
+        assert_eq!((&v - 1.).borrow().data, arr(vec![0., 1., 3.]));
+        assert_eq!((1. - &v).borrow().data, arr(vec![0., -1., -3.]));
+        assert_eq!((&v * 2.).borrow().data, arr(vec![2., 4., 8.]));
+        assert_eq!((2. * &v).borrow().data, arr(vec![2., 4., 8.]));
+        assert_eq!((&v / 2.).borrow().data, arr(vec![0.5, 1., 2.]));
+        assert_eq!((2. / &v).borrow().data, arr(vec![2., 1., 0.5]));
+    }
+
+    #[test]
+    fn ops_backward_ok() {
+        let x = var(vec![1., 2., 3.]);
+        let y = var(vec![2., 2., 2.]);
+        // z = x * y - x / 2 + 3
+        let z = &x * &y - &x / 2. + 3.;
+        assert_eq!(z.borrow().data, arr(vec![4.5, 6., 7.5]));
+
+        let ones = Array::ones(z.borrow().data.borrow().raw_dim());
+        z.borrow_mut().backward_with(ones);
+        assert_eq!(x.borrow().grad, arr(vec![1.5, 1.5, 1.5]));
+        assert_eq!(y.borrow().grad, arr(vec![1., 2., 3.]));
+    }
+
+    #[test]
+    fn assign_ops_backward_ok() {
+        let x = var(vec![1., 2., 3.]);
+        let mut acc = x.clone();
+        acc *= &x;
+        acc += 1.;
+        acc -= &x;
+        acc /= 2.;
+        // acc = (x^2 + 1 - x) / 2
+        assert_eq!(acc.borrow().data, arr(vec![0.5, 1.5, 3.5]));
+        // The leaf data is not modified in place
+        assert_eq!(x.borrow().data, arr(vec![1., 2., 3.]));
+
+        let ones = Array::ones(acc.borrow().data.borrow().raw_dim());
+        acc.borrow_mut().backward_with(ones);
+        assert_eq!(x.borrow().grad, arr(vec![0.5, 1.5, 2.5]));
+    }
+
+    #[test]
+    fn conversions_ok() {
+        let t = Tensor::new_ref(&arr(vec![1., 2.]));
+        let v = Var::from(t.clone());
+        let sum: RTensor = (&v + &v).into();
+        assert_eq!(sum.borrow().data, arr(vec![2., 4.]));
+        assert!(crate::backend::shared::Shared::ptr_eq(&v.into_inner(), &t));
+    }
+}
@@ -1,41 +1,43 @@
-use ndarray::{ArrayD, IxDyn};
-use rusty_grad::backend::ops::add;
-use rusty_grad::backend::tensor::{RTensor, Tensor};
-use rusty_grad::nn::{components::Module, losses::squared_error, models::MLP};
-use rusty_grad::rtensor;
+use rusty_grad::backend::grad_mode::no_grad;
+use rusty_grad::backend::ops::sum;
+use rusty_grad::backend::random::manual_seed;
+use rusty_grad::backend::tensor::RTensor;
+use rusty_grad::nn::{
+    activations::Activation, components::Module, losses::squared_error, models::MLP,
+};
+use rusty_grad::tensor;
 
 const LEARNING_RATE: f32 = 0.001;
 const EPOCHS: usize = 500;
+const SEED: u64 = 42;
 
 fn main() {
-    // Prepare the dataset
-    let dataset_x = vec![
-        rtensor![&[1, 3], &[2., 3., -1.]],
-        rtensor![&[1, 3], &[3., -1., 0.5]],
-        rtensor![&[1, 3], &[0.5, 1., 1.]],
-        rtensor![&[1, 3], &[1., 1., -1.]],
-    ];
-    let dataset_y = vec![
-        rtensor![&[1, 1], &[1.]],
-        rtensor![&[1, 1], &[-1.]],
-        rtensor![&[1, 1], &[-1.]],
-        rtensor![&[1, 1], &[-1.]],
-    ];
-
-    // Create the model
-    let model = MLP::new(3, vec![4, 4, 1]);
+    // Seed the generator so that every run trains the same model
+    manual_seed(SEED);
+
+    // Prepare the dataset (one sample per row). It doesn't need gradients.
+    let dataset_x = tensor![
+        &[4, 3],
+        &[2., 3., -1., 3., -1., 0.5, 0.5, 1., 1., 1., 1., -1.]
+    ]
+    .with_requires_grad(false)
+    .to_ref();
+    let dataset_y = tensor![&[4, 1], &[1., -1., -1., -1.]]
+        .with_requires_grad(false)
+        .to_ref();
+
+    // Create the model, the targets are in [-1, 1] like the output activation
+    let model = MLP::builder(3, vec![4, 4, 1])
+        .hidden_activation(Activation::Tanh)
+        .output_activation(Activation::Tanh)
+        .build();
 
     for epoch in 0..EPOCHS {
         // Forward pass
-        let pred: Vec<RTensor> = dataset_x.iter().map(|x| model.forward(x)).collect();
+        let pred: RTensor = model.forward(&dataset_x);
 
-        // Compute loss
-        let loss: RTensor = dataset_y
-            .iter()
-            .zip(&pred)
-            .map(|(y_true, y_pred)| squared_error(y_true, y_pred))
-            .reduce(|v1, v2| add(&v1, &v2))
-            .unwrap();
+        // Compute loss (sum of the squared errors of all the samples)
+        let loss: RTensor = sum(&squared_error(&dataset_y, &pred), None, false);
 
         // Reset the gradients to zero
         model.zero_grad();
@@ -45,7 +47,7 @@ fn main() {
         // Update parameters
         for param in model.parameters() {
             let param_delta = &param.borrow().grad * LEARNING_RATE;
-            param.borrow_mut().data -= &param_delta;
+            *param.borrow().data.borrow_mut() -= &param_delta;
         }
 
         // Show current loss
@@ -56,4 +58,10 @@ fn main() {
             loss.borrow_mut().data
         );
     }
+
+    // Inference, without recording the graph
+    model.set_training(false);
+    let _guard = no_grad();
+    let pred = model.forward(&dataset_x);
+    println!("Predictions: {}", pred.borrow().data);
 }
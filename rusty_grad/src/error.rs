@@ -0,0 +1,140 @@
+use core::fmt;
+
+/// Errors returned by the fallible (`try_*`) ops and constructors
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RustyGradError {
+    /// The shapes of the operands are not compatible
+    ShapeMismatch {
+        op: &'static str,
+        lhs: Vec<usize>,
+        rhs: Vec<usize>,
+    },
+    /// A tensor doesn't have enough dimensions for the op
+    RankMismatch {
+        op: &'static str,
+        expected: usize,
+        got: usize,
+    },
+    /// The axis doesn't exist in the tensor
+    InvalidAxis {
+        op: &'static str,
+        axis: usize,
+        ndim: usize,
+    },
+    /// The index is out of the bounds of the axis
+    IndexOutOfBounds {
+        op: &'static str,
+        index: isize,
+        size: usize,
+    },
+    /// The value of an argument is not valid for the op
+    InvalidArgument { op: &'static str, reason: String },
+    /// A node of the graph doesn't have the number of children of its op
+    InvalidChildren {
+        op: &'static str,
+        expected: usize,
+        got: usize,
+    },
+    /// The graph was freed by a previous backpropagation
+    GraphFreed,
+    /// The backpropagation without an upstream gradient was started from a
+    /// tensor with more than one element
+    NonScalarBackward { shape: Vec<usize> },
+}
+
+pub type Result<T> = std::result::Result<T, RustyGradError>;
+
+impl fmt::Display for RustyGradError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ShapeMismatch { op, lhs, rhs } => {
+                write!(
+                    f,
+                    "Incompatible shapes {:?} and {:?} in {} op",
+                    lhs, rhs, op
+                )
+            }
+            Self::RankMismatch { op, expected, got } => write!(
+                f,
+                "{} op requires tensors with at least {} dimensions, but got {}",
+                op, expected, got
+            ),
+            Self::InvalidAxis { op, axis, ndim } => write!(
+                f,
+                "Invalid axis {} in {} op for a tensor with {} dimensions",
+                axis, op, ndim
+            ),
+            Self::IndexOutOfBounds { op, index, size } => write!(
+                f,
+                "Index {} is out of bounds for an axis of size {} in {} op",
+                index, size, op
+            ),
+            Self::InvalidArgument { op, reason } => {
+                write!(f, "Invalid argument in {} op: {}", op, reason)
+            }
+            Self::InvalidChildren { op, expected, got } => write!(
+                f,
+                "The number of children in {} op must be {}, but is {}",
+                op, expected, got
+            ),
+            Self::GraphFreed => write!(
+                f,
+                "Trying to backpropagate through a graph that was already freed, use `retain_graph` to backpropagate more than once"
+            ),
+            Self::NonScalarBackward { shape } => write!(
+                f,
+                "Backward can only be started from a tensor with a single element, but got shape {:?} (use `backward_with` to pass its gradient)",
+                shape
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RustyGradError {}
+
+/// Returns the value of a fallible op, panicking with the error message if it
+/// failed. Used to implement the panicking versions of the `try_*` functions.
+#[track_caller]
+pub(crate) fn unwrap_or_panic<T>(res: Result<T>) -> T {
+    res.unwrap_or_else(|e| panic!("[Error] {}!", e))
+}
+
+/// Returns an `InvalidArgument` error for `op`
+pub(crate) fn invalid_argument<T>(op: &'static str, reason: String) -> Result<T> {
+    Err(RustyGradError::InvalidArgument { op, reason })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_ok() {
+        let e = RustyGradError::ShapeMismatch {
+            op: "Add",
+            lhs: vec![2, 3],
+            rhs: vec![2],
+        };
+        assert_eq!(
+            e.to_string(),
+            "Incompatible shapes [2, 3] and [2] in Add op"
+        );
+
+        let e = RustyGradError::InvalidAxis {
+            op: "Sum",
+            axis: 2,
+            ndim: 2,
+        };
+        assert_eq!(
+            e.to_string(),
+            "Invalid axis 2 in Sum op for a tensor with 2 dimensions"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "[Error] Invalid argument in Clamp op: min > max!")]
+    fn unwrap_or_panic_panics() {
+        unwrap_or_panic::<()>(invalid_argument("Clamp", "min > max".to_string()));
+    }
+}
@@ -0,0 +1,329 @@
+use crate::backend::float::Float;
+use crate::backend::grad_mode::no_grad;
+use crate::backend::random::{fork_rng, get_rng_state, set_rng_state};
+use crate::backend::tensor::RTensor;
+use crate::error::RustyGradError;
+use crate::nn::components::Module;
+use core::fmt;
+use ndarray::prelude::*;
+use rand::{distributions::Uniform, prelude::Distribution, rngs::StdRng, SeedableRng};
+
+/// Parameters of the gradient check
+#[derive(Debug, Clone, Copy)]
+pub struct GradCheckOptions {
+    /// Perturbation added to and subtracted from each input element
+    pub eps: f64,
+    /// Absolute tolerance of the comparison
+    pub atol: f64,
+    /// Relative tolerance of the comparison (relative to the numerical gradient)
+    pub rtol: f64,
+}
+
+impl Default for GradCheckOptions {
+    fn default() -> Self {
+        GradCheckOptions {
+            eps: 1e-2,
+            atol: 1e-3,
+            rtol: 1e-2,
+        }
+    }
+}
+
+/// Errors returned by the gradient check
+#[derive(Debug, Clone, PartialEq)]
+pub enum GradCheckError {
+    /// The function or its backpropagation failed
+    Op(RustyGradError),
+    /// The analytical and numerical gradients don't match. Reports the element
+    /// with the largest mismatch relative to the tolerance.
+    Mismatch {
+        /// Position of the tensor in the inputs
+        input: usize,
+        /// Index of the element in the tensor
+        index: Vec<usize>,
+        analytical: f64,
+        numerical: f64,
+    },
+}
+
+impl From<RustyGradError> for GradCheckError {
+    fn from(e: RustyGradError) -> Self {
+        GradCheckError::Op(e)
+    }
+}
+
+impl fmt::Display for GradCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Op(e) => write!(f, "{}", e),
+            Self::Mismatch {
+                input,
+                index,
+                analytical,
+                numerical,
+            } => write!(
+                f,
+                "Gradient mismatch for input {} at index {:?}: analytical {} vs numerical {}",
+                input, index, analytical, numerical
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GradCheckError {}
+
+/// Returns the sum of the elements of `out` weighted by `weights`, in f64
+fn weighted_sum<T: Float>(out: &ArrayD<T>, weights: &ArrayD<T>) -> f64 {
+    out.iter()
+        .zip(weights.iter())
+        .map(|(y, w)| y.to_f64().unwrap() * w.to_f64().unwrap())
+        .sum()
+}
+
+/// Compares the gradients computed by the backpropagation of `f` with respect
+/// to `inputs` with the ones estimated with central finite differences.
+///
+/// The output of `f` is reduced to a scalar with a weighted sum, using fixed
+/// pseudo-random weights so that errors that cancel out in a plain sum (e.g. a
+/// permuted gradient) are detected. Each input element is perturbed in place
+/// by `±eps`, and the difference quotient is computed in f64 using the actual
+/// (rounded to the element type) perturbation. All the inputs must require
+/// grad. Their data and gradients are restored before returning.
+///
+/// Returns the element with the worst mismatch if any gradient is outside the
+/// tolerances, i.e. `|analytical - numerical| > atol + rtol * |numerical|`.
+pub fn gradcheck<T: Float>(
+    f: impl Fn(&[RTensor<T>]) -> RTensor<T>,
+    inputs: &[RTensor<T>],
+    options: GradCheckOptions,
+) -> Result<(), GradCheckError> {
+    if let Some(i) = inputs.iter().position(|t| !t.borrow().requires_grad) {
+        return Err(RustyGradError::InvalidArgument {
+            op: "GradCheck",
+            reason: format!("input {} doesn't require grad", i),
+        }
+        .into());
+    }
+    let saved_grads: Vec<ArrayD<T>> = inputs.iter().map(|t| t.borrow().grad.clone()).collect();
+    for t in inputs {
+        t.borrow_mut().grad.fill(T::zero());
+    }
+
+    // Analytical gradients
+    let out = f(inputs);
+    let weights = {
+        let mut rng = StdRng::seed_from_u64(0);
+        let uniform = Uniform::new_inclusive(-T::one(), T::one());
+        let out_dim = out.borrow().data.borrow().raw_dim();
+        Array::from_shape_simple_fn(out_dim, || uniform.sample(&mut rng))
+    };
+    let res = out.borrow_mut().try_backward_with(weights.clone());
+    drop(out);
+    let analytical: Vec<ArrayD<T>> = inputs
+        .iter()
+        .zip(saved_grads)
+        .map(|(t, saved)| std::mem::replace(&mut t.borrow_mut().grad, saved))
+        .collect();
+    res?;
+
+    // Numerical gradients
+    let _guard = no_grad();
+    let eval = || weighted_sum(&f(inputs).borrow().data.borrow(), &weights);
+    let mut worst: Option<(f64, GradCheckError)> = None;
+    for (i, (t, grads)) in inputs.iter().zip(&analytical).enumerate() {
+        for (index, &grad) in grads.indexed_iter() {
+            let orig = t.borrow().data.borrow()[&index];
+            let plus = T::cast(orig.to_f64().unwrap() + options.eps);
+            let minus = T::cast(orig.to_f64().unwrap() - options.eps);
+            t.borrow().data.borrow_mut()[&index] = plus;
+            let f_plus = eval();
+            t.borrow().data.borrow_mut()[&index] = minus;
+            let f_minus = eval();
+            t.borrow().data.borrow_mut()[&index] = orig;
+
+            let numerical = (f_plus - f_minus) / (plus - minus).to_f64().unwrap();
+            let grad = grad.to_f64().unwrap();
+            let error = (grad - numerical).abs();
+            let ratio = error / (options.atol + options.rtol * numerical.abs());
+            // Keep the mismatch with the largest ratio above 1
+            if ratio > worst.as_ref().map_or(1.0, |(w, _)| *w) {
+                let mismatch = GradCheckError::Mismatch {
+                    input: i,
+                    index: index.slice().to_vec(),
+                    analytical: grad,
+                    numerical,
+                };
+                worst = Some((ratio, mismatch));
+            }
+        }
+    }
+    match worst {
+        Some((_, mismatch)) => Err(mismatch),
+        None => Ok(()),
+    }
+}
+
+/// Checks the gradients of the parameters of `module` for the input `x`. See
+/// [`gradcheck`].
+///
+/// Every evaluation of the module starts from the same state of the generator
+/// of the library, so that random layers (e.g. `Dropout` in training mode)
+/// sample the same values in all of them. The state of the generator is
+/// restored before returning.
+pub fn gradcheck_module<T: Float>(
+    module: &dyn Module<T>,
+    x: &RTensor<T>,
+    options: GradCheckOptions,
+) -> Result<(), GradCheckError> {
+    let _guard = fork_rng();
+    let state = get_rng_state();
+    let forward = |_: &[RTensor<T>]| {
+        set_rng_state(state.clone());
+        module.forward(x)
+    };
+    gradcheck(forward, &module.parameters(), options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ops::*;
+    use crate::backend::random::permutation;
+    use crate::backend::tensor::Tensor;
+    use crate::nn::{
+        activations::{gelu, sigmoid, tanh, Activation},
+        models::MLP,
+    };
+
+    fn rand_tensor<T: Float>(shape: &[usize], seed: u64) -> RTensor<T> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let uniform = Uniform::new_inclusive(T::cast(0.5), T::cast(2.0));
+        Tensor::new_ref(&Array::from_shape_simple_fn(IxDyn(shape), || {
+            uniform.sample(&mut rng)
+        }))
+    }
+
+    fn check(f: impl Fn(&[RTensor]) -> RTensor, shapes: &[&[usize]]) {
+        let inputs: Vec<RTensor> = shapes
+            .iter()
+            .enumerate()
+            .map(|(i, s)| rand_tensor(s, i as u64))
+            .collect();
+        gradcheck(f, &inputs, GradCheckOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn gradcheck_binary_ops_ok() {
+        check(|x| add(&x[0], &x[1]), &[&[2, 3], &[3]]);
+        check(|x| diff(&x[0], &x[1]), &[&[2, 1], &[2, 3]]);
+        check(|x| mul(&x[0], &x[1]), &[&[2, 3], &[2, 3]]);
+        check(|x| div(&x[0], &x[1]), &[&[3], &[2, 3]]);
+        check(|x| pow(&x[0], 3.0), &[&[2, 2]]);
+    }
+
+    #[test]
+    fn gradcheck_math_ops_ok() {
+        check(|x| exp(&x[0]), &[&[4]]);
+        check(|x| log(&x[0]), &[&[4]]);
+        check(|x| sqrt(&x[0]), &[&[4]]);
+        check(|x| rsqrt(&x[0]), &[&[4]]);
+        check(|x| sin(&cos(&x[0])), &[&[4]]);
+        check(|x| tanh(x[0].clone()), &[&[4]]);
+        check(|x| sigmoid(x[0].clone()), &[&[4]]);
+        check(|x| gelu(x[0].clone()), &[&[4]]);
+    }
+
+    #[test]
+    fn gradcheck_shape_and_reduce_ops_ok() {
+        check(|x| matmul(&x[0], &x[1]), &[&[2, 2, 3], &[3, 4]]);
+        check(|x| permute(&x[0], &[2, 0, 1]), &[&[2, 3, 4]]);
+        check(|x| sum(&x[0], Some(&[1]), false), &[&[2, 3]]);
+        check(|x| mean(&x[0], Some(&[0]), true), &[&[2, 3]]);
+        check(|x| prod(&x[0], None, false), &[&[2, 3]]);
+        check(
+            |x| concat(&[x[0].clone(), x[1].clone()], 1),
+            &[&[2, 1], &[2, 3]],
+        );
+    }
+
+    #[test]
+    fn gradcheck_module_ok() {
+        let model: MLP = MLP::new(3, vec![4, 2]);
+        let x = rand_tensor(&[5, 3], 0);
+        gradcheck_module(&model, &x, GradCheckOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn gradcheck_module_dropout_ok() {
+        let model: MLP = MLP::builder(3, vec![8, 8, 2])
+            .hidden_activation(Activation::Tanh)
+            .dropout(0.5)
+            .build();
+        let x = rand_tensor(&[5, 3], 0);
+        let state = get_rng_state();
+        let p = permutation(16);
+        set_rng_state(state);
+        gradcheck_module(&model, &x, GradCheckOptions::default()).unwrap();
+        // The generator is left as it was
+        assert_eq!(permutation(16), p);
+    }
+
+    #[test]
+    fn gradcheck_f64_ok() {
+        // f64 allows a much smaller perturbation and tighter tolerances
+        let options = GradCheckOptions {
+            eps: 1e-6,
+            atol: 1e-8,
+            rtol: 1e-6,
+        };
+        let inputs: Vec<RTensor<f64>> = vec![rand_tensor(&[2, 3], 0), rand_tensor(&[3], 1)];
+        gradcheck(|x| div(&exp(&x[0]), &x[1]), &inputs, options).unwrap();
+
+        let model = MLP::<f64>::new(3, vec![4, 2]);
+        gradcheck_module(&model, &rand_tensor(&[5, 3], 0), options).unwrap();
+    }
+
+    #[test]
+    fn gradcheck_restores_inputs() {
+        let x = rand_tensor(&[3], 0);
+        x.borrow_mut().grad.fill(7.0);
+        let data = x.borrow().data.borrow().clone();
+        gradcheck(
+            |x| exp(&x[0]),
+            std::slice::from_ref(&x),
+            GradCheckOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(x.borrow().data, data);
+        assert_eq!(x.borrow().grad, Array::from_elem(IxDyn(&[3]), 7.0));
+    }
+
+    #[test]
+    fn gradcheck_mismatch_err() {
+        // An op with a wrong gradient: forward of `x^2`, backward of `x`
+        let wrong_square = |x: &[RTensor]| {
+            let data = x[0].borrow().data.borrow().mapv(|v| v * v);
+            Tensor::from_op(data, vec![x[0].clone()], |_, grad| Ok(vec![grad.clone()]))
+        };
+        let x = Tensor::new_ref(&array![1., 3., 2.].into_dyn());
+        match gradcheck(wrong_square, &[x], GradCheckOptions::default()) {
+            Err(GradCheckError::Mismatch { input, index, .. }) => {
+                assert_eq!(input, 0);
+                // The largest error is at the largest element
+                assert_eq!(index, vec![1]);
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn gradcheck_no_grad_input_err() {
+        let x = Tensor::new(&array![1.].into_dyn())
+            .with_requires_grad(false)
+            .to_ref();
+        assert!(matches!(
+            gradcheck(|x| exp(&x[0]), &[x], GradCheckOptions::default()),
+            Err(GradCheckError::Op(RustyGradError::InvalidArgument { .. }))
+        ));
+    }
+}
@@ -1,2 +1,4 @@
 pub mod backend;
+pub mod error;
+pub mod gradcheck;
 pub mod nn;
@@ -1,59 +1,113 @@
-use crate::backend::tensor::{RTensor, Tensor};
-use ndarray::Array;
-
-pub fn relu(t: RTensor) -> RTensor {
-    let t_data = &t.borrow().data;
-    Tensor {
-        data: t_data.mapv(|x| if x > 0. { x } else { 0. }),
-        grad: Array::zeros(t_data.raw_dim()),
-        prev: vec![t.clone()],
-        backward_fn: Box::new(relu_backward),
-    }
-    .to_ref()
+use crate::backend::{
+    float::{indicator, Float},
+    ops::{add, check_children, constant, diff, mul, pow, scale},
+    tensor::{RTensor, Tensor},
+};
+use crate::error::Result;
+use crate::nn::components::Module;
+use ndarray::prelude::*;
+
+pub fn relu<T: Float>(t: RTensor<T>) -> RTensor<T> {
+    let data = t.borrow().data.borrow().mapv(|x| x.max(T::zero()));
+    Tensor::from_op(data, vec![t], relu_backward)
 }
 
-fn relu_backward(t: &Tensor) {
-    match &t.prev[..] {
-        [t_prev] => {
-            let mut t_prev = t_prev.borrow_mut();
-            t_prev.grad += &(&t.grad * &t.data.mapv(|x| (x > 0.) as u8 as f32));
-        }
-        _ => panic!(
-            "[Error] The number of children in ReLU op must be 1, but is {}!",
-            t.prev.len()
-        ),
-    }
+fn relu_backward<T: Float>(t: &Tensor<T>, grad: &RTensor<T>) -> Result<Vec<RTensor<T>>> {
+    check_children("ReLU", t, 1)?;
+    let mask = t.data.borrow().mapv(|x| indicator(x > T::zero()));
+    Ok(vec![mul(grad, &constant(mask))])
+}
+
+pub fn tanh<T: Float>(t: RTensor<T>) -> RTensor<T> {
+    let data = t.borrow().data.borrow().mapv(T::tanh);
+    Tensor::from_op(data, vec![t], tanh_backward)
+}
+
+fn tanh_backward<T: Float>(t: &Tensor<T>, grad: &RTensor<T>) -> Result<Vec<RTensor<T>>> {
+    check_children("Tanh", t, 1)?;
+    // d(tanh(x))/dx = 1 - tanh(x)^2, recomputed from the input so that it can
+    // be differentiated
+    let one = constant(arr0(T::one()).into_dyn());
+    let dprev = diff(&one, &pow(&tanh(t.prev[0].clone()), T::cast(2.)));
+    Ok(vec![mul(grad, &dprev)])
 }
 
-pub fn tanh(t: RTensor) -> RTensor {
-    let aux_exp = t.borrow().data.mapv(|x| f32::exp(2.0 * x));
-    let res = (&aux_exp - 1.) / (&aux_exp + 1.);
-    Tensor {
-        data: res,
-        grad: Array::zeros(t.borrow().data.raw_dim()),
-        prev: vec![t.clone()],
-        backward_fn: Box::new(tanh_backward),
+/// Logistic function of `x`, computed without overflowing `exp`
+pub(crate) fn sigmoid_value<T: Float>(x: T) -> T {
+    if x >= T::zero() {
+        T::one() / (T::one() + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (T::one() + e)
     }
-    .to_ref()
 }
 
-fn tanh_backward(t: &Tensor) {
-    match &t.prev[..] {
-        [t_prev] => {
-            let mut t_prev = t_prev.borrow_mut();
-            t_prev.grad += &(&t.grad * &t.data.mapv(|x| 1. - f32::powi(x, 2)));
+pub fn sigmoid<T: Float>(t: RTensor<T>) -> RTensor<T> {
+    let data = t.borrow().data.borrow().mapv(sigmoid_value);
+    Tensor::from_op(data, vec![t], sigmoid_backward)
+}
+
+fn sigmoid_backward<T: Float>(t: &Tensor<T>, grad: &RTensor<T>) -> Result<Vec<RTensor<T>>> {
+    check_children("Sigmoid", t, 1)?;
+    // d(sigmoid(x))/dx = sigmoid(x) * (1 - sigmoid(x)), recomputed from the
+    // input so that it can be differentiated
+    let one = constant(arr0(T::one()).into_dyn());
+    let s = sigmoid(t.prev[0].clone());
+    Ok(vec![mul(grad, &mul(&s, &diff(&one, &s)))])
+}
+
+/// Gaussian error linear unit, with the tanh approximation
+/// `0.5 * x * (1 + tanh(sqrt(2 / pi) * (x + 0.044715 * x^3)))`. It is composed
+/// of autograd ops, so it is differentiable.
+pub fn gelu<T: Float>(t: RTensor<T>) -> RTensor<T> {
+    let one = constant(arr0(T::one()).into_dyn());
+    let cubic = add(&t, &scale(&pow(&t, T::cast(3.)), T::cast(GELU_COEF)));
+    let inner = scale(&cubic, T::cast(GELU_SCALE));
+    mul(&scale(&t, T::cast(0.5)), &add(&one, &tanh(inner)))
+}
+
+/// `sqrt(2 / pi)`, the scale of the tanh approximation of GELU
+pub(crate) const GELU_SCALE: f64 = 0.7978845608028654;
+
+/// Coefficient of the cubic term of the tanh approximation of GELU
+pub(crate) const GELU_COEF: f64 = 0.044715;
+
+/// Activation function, to select the activations of a model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activation {
+    Relu,
+    Tanh,
+    Sigmoid,
+    Gelu,
+}
+
+impl Activation {
+    /// Applies the activation function to `t`
+    pub fn apply<T: Float>(&self, t: RTensor<T>) -> RTensor<T> {
+        match self {
+            Activation::Relu => relu(t),
+            Activation::Tanh => tanh(t),
+            Activation::Sigmoid => sigmoid(t),
+            Activation::Gelu => gelu(t),
         }
-        _ => panic!(
-            "[Error] The number of children in Tanh op must be 1, but is {}!",
-            t.prev.len()
-        ),
+    }
+}
+
+/// Activations are modules without parameters, so that they can be stacked in
+/// a [`Sequential`](crate::nn::Sequential)
+impl<T: Float> Module<T> for Activation {
+    fn parameters(&self) -> Vec<RTensor<T>> {
+        vec![]
+    }
+    fn forward(&self, x: &RTensor<T>) -> RTensor<T> {
+        self.apply(x.clone())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ndarray::prelude::*;
+    use crate::backend::dual::{self, DualTensor};
 
     #[test]
     fn relu_ok() {
@@ -75,7 +129,8 @@ mod tests {
             res.borrow().data,
             ArrayD::from_shape_vec(IxDyn(&[2, 2]), vec![2., 1.2, 0., 0.]).unwrap()
         );
-        res.borrow_mut().backward();
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
         assert_eq!(
             t.borrow().grad,
             ArrayD::from_shape_vec(IxDyn(&[2, 2]), vec![1., 1., 0., 0.]).unwrap()
@@ -85,27 +140,114 @@ mod tests {
     #[test]
     fn tanh_ok() {
         let arr = ArrayD::from_shape_vec(IxDyn(&[2, 2]), vec![0., 0.7, 0., 0.7]).unwrap();
-        let t = Tensor::new_ref(&arr);
+        let t = Tensor::<f32>::new_ref(&arr);
         let res = tanh(t);
         assert_eq!(
             res.borrow().data,
-            ArrayD::from_shape_vec(IxDyn(&[2, 2]), vec![0., 0.6043678, 0., 0.6043678]).unwrap()
+            ArrayD::from_shape_vec(IxDyn(&[2, 2]), vec![0., 0.60436773, 0., 0.60436773]).unwrap()
         );
     }
 
     #[test]
     fn tanh_backward_ok() {
         let arr = ArrayD::from_shape_vec(IxDyn(&[2, 2]), vec![0., 0.8814, 0., 0.8814]).unwrap();
-        let t = Tensor::new_ref(&arr);
+        let t = Tensor::<f32>::new_ref(&arr);
+        let res = tanh(t.clone());
+        assert_eq!(
+            res.borrow().data,
+            ArrayD::from_shape_vec(IxDyn(&[2, 2]), vec![0., 0.70711994, 0., 0.70711994]).unwrap()
+        );
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(
+            t.borrow().grad,
+            ArrayD::from_shape_vec(IxDyn(&[2, 2]), vec![1., 0.4999814, 1., 0.4999814]).unwrap()
+        );
+    }
+
+    #[test]
+    fn tanh_large_inputs_ok() {
+        let arr = ArrayD::from_shape_vec(IxDyn(&[4]), vec![50., 1000., -50., -1000.]).unwrap();
+        let t = Tensor::<f32>::new_ref(&arr);
         let res = tanh(t.clone());
         assert_eq!(
             res.borrow().data,
-            ArrayD::from_shape_vec(IxDyn(&[2, 2]), vec![0., 0.70712, 0., 0.70712]).unwrap()
+            ArrayD::from_shape_vec(IxDyn(&[4]), vec![1., 1., -1., -1.]).unwrap()
+        );
+        // The gradient saturates to 0 instead of becoming NaN
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, Array::zeros(IxDyn(&[4])));
+    }
+
+    #[test]
+    fn sigmoid_backward_ok() {
+        let arr = ArrayD::from_shape_vec(IxDyn(&[4]), vec![0., 2., -2., -100.]).unwrap();
+        let t = Tensor::<f64>::new_ref(&arr);
+        let res = sigmoid(t.clone());
+        let expected = arr.mapv(|x| 1. / (1. + (-x).exp()));
+        assert!((&*res.borrow().data.borrow() - &expected)
+            .iter()
+            .all(|d| d.abs() < 1e-12));
+        // No overflow for large negative inputs
+        assert!(res.borrow().data.borrow()[3] > 0.);
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        let expected = expected.mapv(|s| s * (1. - s));
+        assert!((&t.borrow().grad - &expected)
+            .iter()
+            .all(|d| d.abs() < 1e-12));
+    }
+
+    #[test]
+    fn gelu_ok() {
+        let arr = ArrayD::from_shape_vec(IxDyn(&[3]), vec![-1., 0., 1.]).unwrap();
+        let t = Tensor::<f64>::new_ref(&arr);
+        let res = gelu(t.clone());
+        let expected = ArrayD::from_shape_vec(IxDyn(&[3]), vec![-0.158808, 0., 0.841192]).unwrap();
+        assert!((&*res.borrow().data.borrow() - &expected)
+            .iter()
+            .all(|d| d.abs() < 1e-6));
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert!((t.borrow().grad[1] - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn gelu_large_inputs_ok() {
+        let arr = ArrayD::from_shape_vec(IxDyn(&[4]), vec![11., 50., -11., -50.]).unwrap();
+        let t = Tensor::<f32>::new_ref(&arr);
+        let res = gelu(t.clone());
+        assert_eq!(
+            res.borrow().data,
+            ArrayD::from_shape_vec(IxDyn(&[4]), vec![11., 50., 0., 0.]).unwrap()
         );
-        res.borrow_mut().backward();
+        // Same values and derivatives as the forward mode
+        let dual = dual::gelu(&DualTensor::new(arr.clone(), Array::ones(arr.raw_dim())));
+        assert_eq!(res.borrow().data, dual.primal);
+
+        let ones = Array::ones(res.borrow().data.borrow().raw_dim());
+        res.borrow_mut().backward_with(ones);
+        assert_eq!(t.borrow().grad, dual.tangent);
         assert_eq!(
             t.borrow().grad,
-            ArrayD::from_shape_vec(IxDyn(&[2, 2]), vec![1., 0.49998128, 1., 0.49998128]).unwrap()
+            ArrayD::from_shape_vec(IxDyn(&[4]), vec![1., 1., 0., 0.]).unwrap()
+        );
+    }
+
+    #[test]
+    fn activation_apply_ok() {
+        let arr = ArrayD::from_shape_vec(IxDyn(&[2]), vec![-1., 1.]).unwrap();
+        let t = Tensor::<f32>::new_ref(&arr);
+        assert_eq!(
+            Activation::Relu.apply(t.clone()).borrow().data,
+            relu(t.clone()).borrow().data
+        );
+        assert_eq!(
+            Activation::Gelu.apply(t.clone()).borrow().data,
+            gelu(t).borrow().data
         );
     }
 }
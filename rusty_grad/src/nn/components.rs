@@ -1,11 +1,23 @@
-use crate::backend::tensor::RTensor;
+use crate::backend::{float::Float, shared::MaybeSendSync, tensor::RTensor};
 
-pub trait Module {
+/// Layer or model of a network. Modules are `Send + Sync` with the `sync`
+/// feature, so that `Box<dyn Module>` can be shared between threads.
+pub trait Module<T: Float = f32>: MaybeSendSync {
     fn zero_grad(&self) {
         for param in self.parameters() {
-            param.borrow_mut().grad.fill(0.0);
+            param.borrow_mut().zero_grad();
         }
     }
-    fn parameters(&self) -> Vec<RTensor>;
-    fn forward(&self, x: &RTensor) -> RTensor;
+    fn parameters(&self) -> Vec<RTensor<T>>;
+    /// Calls `f` with each parameter of the module, e.g. to initialize them
+    /// (see [`init_module`](crate::nn::init::init_module))
+    fn visit_parameters(&self, f: &mut dyn FnMut(&RTensor<T>)) {
+        for param in self.parameters() {
+            f(&param);
+        }
+    }
+    fn forward(&self, x: &RTensor<T>) -> RTensor<T>;
+    /// Sets whether the module is in training mode, the default. Some modules,
+    /// like dropout, only change the input while training.
+    fn set_training(&self, _training: bool) {}
 }
@@ -0,0 +1,584 @@
+//! Initialization schemes of the parameters. An [`Init`] can be selected per
+//! layer through its builder (e.g. [`Dense::builder`](crate::nn::layers::Dense::builder))
+//! or applied to all the parameters of any module with [`init_module`].
+//!
+//! The fan-based schemes follow the layout of the weights of `Dense`: the
+//! first axis is the input and the second one the output. The remaining axes,
+//! if any, are a receptive field that multiplies both fans.
+
+use crate::backend::creation::{sample, standard_normal};
+use crate::backend::float::Float;
+use crate::backend::tensor::RTensor;
+use crate::error::{invalid_argument, unwrap_or_panic, Result};
+use crate::nn::components::Module;
+use ndarray::prelude::*;
+use rand::{Rng, RngCore};
+
+/// Fan used to scale the Kaiming initialization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanMode {
+    /// Preserves the variance of the activations in the forward pass
+    FanIn,
+    /// Preserves the variance of the gradients in the backward pass
+    FanOut,
+}
+
+/// Initialization scheme of a parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Init<T: Float = f32> {
+    /// All the elements are 0
+    Zeros,
+    /// All the elements are the given value
+    Constant(T),
+    /// Uniform distribution over `[low, high)`
+    Uniform { low: T, high: T },
+    /// Normal distribution
+    Normal { mean: T, std: T },
+    /// Normal distribution restricted to `[low, high]`, sampled by inverting
+    /// its CDF, so the bounds can be far in the tails
+    TruncatedNormal { mean: T, std: T, low: T, high: T },
+    /// Xavier/Glorot uniform, over `±gain * sqrt(6 / (fan_in + fan_out))`
+    XavierUniform { gain: T },
+    /// Xavier/Glorot normal, with deviation `gain * sqrt(2 / (fan_in + fan_out))`
+    XavierNormal { gain: T },
+    /// Kaiming/He uniform, over `±gain * sqrt(3 / fan)`. The gain is
+    /// `sqrt(2)` for ReLU.
+    KaimingUniform { gain: T, mode: FanMode },
+    /// Kaiming/He normal, with deviation `gain / sqrt(fan)`. The gain is
+    /// `sqrt(2)` for ReLU.
+    KaimingNormal { gain: T, mode: FanMode },
+    /// (Semi-)orthogonal matrix scaled by `gain`, flattening the axes after
+    /// the first one
+    Orthogonal { gain: T },
+}
+
+/// Returns the fan in and fan out of a parameter with the given `shape`
+fn fans(op: &'static str, shape: &[usize]) -> Result<(f64, f64)> {
+    if shape.len() < 2 {
+        return invalid_argument(
+            op,
+            format!(
+                "the fans can't be computed for a tensor with {} dimensions",
+                shape.len()
+            ),
+        );
+    }
+    let receptive: usize = shape[2..].iter().product();
+    Ok(((shape[0] * receptive) as f64, (shape[1] * receptive) as f64))
+}
+
+/// Samples an array with the given `shape` from the uniform distribution over
+/// `[low, high)`
+fn uniform(shape: &[usize], low: f64, high: f64, rng: Option<&mut dyn RngCore>) -> ArrayD<f64> {
+    sample(shape, rng, |rng| low + (high - low) * rng.gen::<f64>())
+}
+
+/// Samples an array with the given `shape` from a normal distribution
+fn normal(shape: &[usize], mean: f64, std: f64, rng: Option<&mut dyn RngCore>) -> ArrayD<f64> {
+    sample(shape, rng, |rng| mean + std * standard_normal(rng))
+}
+
+/// Evaluates the polynomial with the coefficients `coefs`, from the highest
+/// degree to the constant term, at `x`
+fn horner(coefs: &[f64], x: f64) -> f64 {
+    coefs.iter().fold(0., |acc, c| acc * x + c)
+}
+
+/// Complementary error function, with a fractional error below `1.2e-7`
+/// everywhere (Chebyshev fit from Numerical Recipes)
+fn erfc(x: f64) -> f64 {
+    const COEFS: [f64; 10] = [
+        0.17087277,
+        -0.82215223,
+        1.48851587,
+        -1.13520398,
+        0.27886807,
+        -0.18628806,
+        0.09678418,
+        0.37409196,
+        1.00002368,
+        -1.26551223,
+    ];
+    let z = x.abs();
+    let t = 1. / (1. + 0.5 * z);
+    let res = t * (-z * z + horner(&COEFS, t)).exp();
+    if x >= 0. {
+        res
+    } else {
+        2. - res
+    }
+}
+
+/// CDF of the standard normal distribution
+fn normal_cdf(x: f64) -> f64 {
+    0.5 * erfc(-x / std::f64::consts::SQRT_2)
+}
+
+/// Inverse of the CDF of the standard normal distribution for `p` in `(0, 1)`,
+/// with a relative error below `1.2e-9` (Acklam's rational approximation)
+fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e+01,
+        2.209460984245205e+02,
+        -2.759285104469687e+02,
+        1.38357751867269e+02,
+        -3.066479806614716e+01,
+        2.506628277459239e+00,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e+01,
+        1.615858368580409e+02,
+        -1.556989798598866e+02,
+        6.680131188771972e+01,
+        -1.328068155288572e+01,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-03,
+        -3.223964580411365e-01,
+        -2.400758277161838e+00,
+        -2.549732539343734e+00,
+        4.374664141464968e+00,
+        2.938163982698783e+00,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-03,
+        3.224671290700398e-01,
+        2.445134137142996e+00,
+        3.754408661907416e+00,
+    ];
+    let tail = |p: f64| {
+        let q = (-2. * p.ln()).sqrt();
+        horner(&C, q) / (horner(&D, q) * q + 1.)
+    };
+    if p < 0.02425 {
+        tail(p)
+    } else if p > 1. - 0.02425 {
+        -tail(1. - p)
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        horner(&A, r) * q / (horner(&B, r) * r + 1.)
+    }
+}
+
+/// Samples an array with the given `shape` from a normal distribution
+/// restricted to `[low, high]`, by mapping uniform samples through the inverse
+/// of its CDF. Returns `None` if the probability of the range underflows.
+fn truncated_normal(
+    shape: &[usize],
+    mean: f64,
+    std: f64,
+    low: f64,
+    high: f64,
+    rng: Option<&mut dyn RngCore>,
+) -> Option<ArrayD<f64>> {
+    let (mut alpha, mut beta) = ((low - mean) / std, (high - mean) / std);
+    // The range is moved to the lower tail, where the CDF is small and so
+    // keeps its relative precision
+    let sign = if alpha + beta > 0. {
+        (alpha, beta) = (-beta, -alpha);
+        -1.
+    } else {
+        1.
+    };
+    let (p_low, p_high) = (normal_cdf(alpha), normal_cdf(beta));
+    if p_high <= p_low {
+        return None;
+    }
+    Some(sample(shape, rng, |rng| {
+        // `u` is in `(p_low, p_high]`, so it is never 0
+        let u = p_low + (p_high - p_low) * (1. - rng.gen::<f64>());
+        let x = mean + std * sign * normal_quantile(u);
+        // The approximations can land slightly outside of the range
+        x.clamp(low, high)
+    }))
+}
+
+/// Orthonormalizes the columns of `a` in place with the modified Gram-Schmidt
+/// process
+fn orthonormalize_columns(a: &mut Array2<f64>) {
+    for j in 0..a.ncols() {
+        for k in 0..j {
+            let prev = a.column(k).to_owned();
+            let proj = a.column(j).dot(&prev);
+            a.column_mut(j).scaled_add(-proj, &prev);
+        }
+        let norm = a.column(j).dot(&a.column(j)).sqrt();
+        a.column_mut(j).mapv_inplace(|x| x / norm);
+    }
+}
+
+impl<T: Float> Init<T> {
+    /// Fallible version of [`Init::sample`], returns an error if the
+    /// parameters of the scheme are not valid or a fan-based scheme receives
+    /// less than 2 dimensions
+    pub fn try_sample(&self, shape: &[usize], rng: Option<&mut dyn RngCore>) -> Result<ArrayD<T>> {
+        let f = |x: T| x.to_f64().unwrap();
+        let data = match *self {
+            Init::Zeros => return Ok(Array::zeros(IxDyn(shape))),
+            Init::Constant(value) => return Ok(Array::from_elem(IxDyn(shape), value)),
+            Init::Uniform { low, high } => {
+                // The width is not finite if either bound is not
+                if !(high - low).is_finite() || low >= high {
+                    return invalid_argument(
+                        "Uniform",
+                        format!("the range from {} to {} is not valid", low, high),
+                    );
+                }
+                uniform(shape, f(low), f(high), rng)
+            }
+            Init::Normal { mean, std } => {
+                if !mean.is_finite() || !std.is_finite() || std < T::zero() {
+                    return invalid_argument(
+                        "Normal",
+                        format!(
+                            "the normal with mean {} and deviation {} is not valid",
+                            mean, std
+                        ),
+                    );
+                }
+                normal(shape, f(mean), f(std), rng)
+            }
+            Init::TruncatedNormal {
+                mean,
+                std,
+                low,
+                high,
+            } => {
+                let finite = [mean, std, low, high].iter().all(|x| x.is_finite());
+                if !finite || std <= T::zero() || low >= high {
+                    return invalid_argument(
+                        "TruncatedNormal",
+                        format!(
+                            "the normal with mean {} and deviation {} from {} to {} is not valid",
+                            mean, std, low, high
+                        ),
+                    );
+                }
+                let (mean, std, low, high) = (f(mean), f(std), f(low), f(high));
+                match truncated_normal(shape, mean, std, low, high, rng) {
+                    Some(data) => data,
+                    None => {
+                        return invalid_argument(
+                            "TruncatedNormal",
+                            format!(
+                                "the range from {} to {} is too far in the tail to be sampled",
+                                low, high
+                            ),
+                        )
+                    }
+                }
+            }
+            Init::XavierUniform { gain } => {
+                let (fan_in, fan_out) = fans("XavierUniform", shape)?;
+                let bound = f(gain) * (6. / (fan_in + fan_out)).sqrt();
+                uniform(shape, -bound, bound, rng)
+            }
+            Init::XavierNormal { gain } => {
+                let (fan_in, fan_out) = fans("XavierNormal", shape)?;
+                normal(shape, 0., f(gain) * (2. / (fan_in + fan_out)).sqrt(), rng)
+            }
+            Init::KaimingUniform { gain, mode } => {
+                let fan = kaiming_fan("KaimingUniform", shape, mode)?;
+                let bound = f(gain) * (3. / fan).sqrt();
+                uniform(shape, -bound, bound, rng)
+            }
+            Init::KaimingNormal { gain, mode } => {
+                let fan = kaiming_fan("KaimingNormal", shape, mode)?;
+                normal(shape, 0., f(gain) / fan.sqrt(), rng)
+            }
+            Init::Orthogonal { gain } => {
+                if shape.len() < 2 {
+                    return invalid_argument(
+                        "Orthogonal",
+                        format!(
+                            "the tensor must have at least 2 dimensions, but has {}",
+                            shape.len()
+                        ),
+                    );
+                }
+                let (rows, cols) = (shape[0], shape[1..].iter().product::<usize>());
+                // Orthonormal columns if there are enough rows, or rows otherwise
+                let (n, m) = (rows.max(cols), rows.min(cols));
+                let mut a = normal(&[n, m], 0., 1., rng)
+                    .into_dimensionality::<Ix2>()
+                    .unwrap();
+                orthonormalize_columns(&mut a);
+                if rows < cols {
+                    a = a.reversed_axes();
+                }
+                (a.as_standard_layout().into_owned() * f(gain))
+                    .into_shape(IxDyn(shape))
+                    .unwrap()
+            }
+        };
+        Ok(data.mapv(T::cast))
+    }
+
+    /// Samples an array with the given `shape` from the scheme, using `rng` or
+    /// the generator of the library if it is `None`
+    pub fn sample(&self, shape: &[usize], rng: Option<&mut dyn RngCore>) -> ArrayD<T> {
+        unwrap_or_panic(self.try_sample(shape, rng))
+    }
+
+    /// Fallible version of [`Init::fill`], returns an error if the scheme
+    /// can't be sampled with the shape of `t`
+    pub fn try_fill(&self, t: &RTensor<T>) -> Result<()> {
+        let shape = t.borrow().data.borrow().shape().to_vec();
+        *t.borrow().data.borrow_mut() = self.try_sample(&shape, None)?;
+        Ok(())
+    }
+
+    /// Replaces the data of `t` with a sample of the scheme
+    pub fn fill(&self, t: &RTensor<T>) {
+        unwrap_or_panic(self.try_fill(t))
+    }
+}
+
+/// Returns the fan selected by `mode` for a parameter with the given `shape`
+fn kaiming_fan(op: &'static str, shape: &[usize], mode: FanMode) -> Result<f64> {
+    let (fan_in, fan_out) = fans(op, shape)?;
+    Ok(match mode {
+        FanMode::FanIn => fan_in,
+        FanMode::FanOut => fan_out,
+    })
+}
+
+/// Fallible version of [`init_module`], returns an error if a scheme can't be
+/// sampled with the shape of a parameter. The parameters visited before the
+/// error keep their new values.
+pub fn try_init_module<T: Float>(
+    module: &dyn Module<T>,
+    weight_init: &Init<T>,
+    bias_init: &Init<T>,
+) -> Result<()> {
+    let mut res = Ok(());
+    module.visit_parameters(&mut |param| {
+        if res.is_ok() {
+            let init = if param.borrow().data.borrow().ndim() >= 2 {
+                weight_init
+            } else {
+                bias_init
+            };
+            res = init.try_fill(param);
+        }
+    });
+    res
+}
+
+/// Initializes the parameters of `module` with 2 or more dimensions (the
+/// weights) with `weight_init` and the rest (the biases) with `bias_init`
+pub fn init_module<T: Float>(module: &dyn Module<T>, weight_init: &Init<T>, bias_init: &Init<T>) {
+    unwrap_or_panic(try_init_module(module, weight_init, bias_init))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::tensor::Tensor;
+    use crate::error::RustyGradError;
+    use crate::nn::models::MLP;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn std_of(data: &ArrayD<f64>) -> f64 {
+        data.std(0.)
+    }
+
+    #[test]
+    fn constant_inits_ok() {
+        assert_eq!(
+            Init::<f32>::Zeros.sample(&[2], None),
+            array![0., 0.].into_dyn()
+        );
+        assert_eq!(
+            Init::Constant(0.1f32).sample(&[1, 2], None),
+            array![[0.1, 0.1]].into_dyn()
+        );
+    }
+
+    #[test]
+    fn fan_based_inits_ok() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let shape = &[200, 300];
+        // bound = sqrt(6 / 500)
+        let w = Init::XavierUniform { gain: 1f64 }.sample(shape, Some(&mut rng));
+        let bound = (6f64 / 500.).sqrt();
+        assert!(w.iter().all(|x| x.abs() <= bound));
+        assert!((std_of(&w) - bound / 3f64.sqrt()).abs() < 2e-3);
+
+        let w = Init::XavierNormal { gain: 2f64 }.sample(shape, Some(&mut rng));
+        assert!((std_of(&w) - 2. * (2f64 / 500.).sqrt()).abs() < 2e-3);
+
+        let relu_gain = 2f64.sqrt();
+        let w = Init::KaimingNormal {
+            gain: relu_gain,
+            mode: FanMode::FanIn,
+        }
+        .sample(shape, Some(&mut rng));
+        assert!((std_of(&w) - (2f64 / 200.).sqrt()).abs() < 2e-3);
+
+        let w = Init::KaimingUniform {
+            gain: relu_gain,
+            mode: FanMode::FanOut,
+        }
+        .sample(shape, Some(&mut rng));
+        assert!((std_of(&w) - (2f64 / 300.).sqrt()).abs() < 2e-3);
+    }
+
+    #[test]
+    fn fan_based_init_err() {
+        let init = Init::XavierUniform { gain: 1f32 };
+        assert!(matches!(
+            init.try_sample(&[3], None),
+            Err(RustyGradError::InvalidArgument {
+                op: "XavierUniform",
+                ..
+            })
+        ));
+        assert!(Init::Uniform {
+            low: 1f32,
+            high: 0.
+        }
+        .try_sample(&[3], None)
+        .is_err());
+        assert!(Init::Uniform {
+            low: -f64::MAX,
+            high: f64::MAX
+        }
+        .try_sample(&[3], None)
+        .is_err());
+    }
+
+    #[test]
+    fn orthogonal_ok() {
+        for shape in [[5, 3], [3, 5]] {
+            let w = Init::Orthogonal { gain: 2f64 }.sample(&shape, None);
+            let w = w.into_dimensionality::<Ix2>().unwrap();
+            // The smaller gram matrix is `gain^2 * I`
+            let gram = if shape[0] >= shape[1] {
+                w.t().dot(&w)
+            } else {
+                w.dot(&w.t())
+            };
+            let expected = Array2::<f64>::eye(3) * 4.;
+            assert!((gram - expected).iter().all(|d| d.abs() < 1e-10));
+        }
+    }
+
+    #[test]
+    fn truncated_normal_ok() {
+        let init = Init::TruncatedNormal {
+            mean: 1f32,
+            std: 1.,
+            low: 0.,
+            high: 1.5,
+        };
+        let w = init.sample(&[1000], None);
+        assert!(w.iter().all(|x| (0. ..=1.5).contains(x)));
+
+        // Bounds far in the tails are sampled without rejection. Beyond 10
+        // deviations almost all the mass is next to the closest bound.
+        for (low, high) in [(10f64, 11.), (-12., -10.)] {
+            let init = Init::TruncatedNormal {
+                mean: 0.,
+                std: 1.,
+                low,
+                high,
+            };
+            let w = init.sample(&[1000], None);
+            assert!(w.iter().all(|x| (low..=high).contains(x)));
+            let closest = if low > 0. { low } else { high };
+            let mean = w.mean().unwrap();
+            assert!((mean - closest).abs() < 0.2, "mean {}", mean);
+        }
+
+        // The standard normal restricted to `[-1, 1]` has deviation 0.5396
+        let mut rng = StdRng::seed_from_u64(0);
+        let init = Init::TruncatedNormal {
+            mean: 0f64,
+            std: 1.,
+            low: -1.,
+            high: 1.,
+        };
+        let w = init.sample(&[20_000], Some(&mut rng));
+        assert!(w.mean().unwrap().abs() < 0.02);
+        assert!((std_of(&w) - 0.5396).abs() < 0.01);
+    }
+
+    #[test]
+    fn normal_inits_err() {
+        let invalid = [
+            Init::Normal {
+                mean: f32::NAN,
+                std: 1.,
+            },
+            Init::Normal {
+                mean: 0.,
+                std: f32::INFINITY,
+            },
+            Init::TruncatedNormal {
+                mean: 0.,
+                std: f32::NAN,
+                low: -1.,
+                high: 1.,
+            },
+            Init::TruncatedNormal {
+                mean: f32::NAN,
+                std: 1.,
+                low: -1.,
+                high: 1.,
+            },
+            Init::TruncatedNormal {
+                mean: 0.,
+                std: 0.,
+                low: -1.,
+                high: 1.,
+            },
+            // The probability of the range underflows
+            Init::TruncatedNormal {
+                mean: 0.,
+                std: 1.,
+                low: 50.,
+                high: 60.,
+            },
+        ];
+        for init in invalid {
+            assert!(
+                matches!(
+                    init.try_sample(&[3], None),
+                    Err(RustyGradError::InvalidArgument { .. })
+                ),
+                "{:?}",
+                init
+            );
+        }
+    }
+
+    #[test]
+    fn init_module_ok() {
+        let model: MLP = MLP::new(3, vec![4, 2]);
+        init_module(&model, &Init::Constant(0.5), &Init::Zeros);
+        for param in model.parameters() {
+            let param = param.borrow();
+            let expected = if param.data.borrow().ndim() == 2 {
+                0.5
+            } else {
+                0.
+            };
+            assert!(param.data.borrow().iter().all(|&x| x == expected));
+        }
+        assert!(try_init_module(&model, &Init::Zeros, &Init::XavierNormal { gain: 1. }).is_err());
+    }
+
+    #[test]
+    fn fill_keeps_shape() {
+        let t = Tensor::new_ref(&Array::zeros(IxDyn(&[2, 3, 4])));
+        Init::KaimingUniform {
+            gain: 1f32,
+            mode: FanMode::FanIn,
+        }
+        .fill(&t);
+        assert_eq!(t.borrow().data.borrow().shape(), &[2, 3, 4]);
+        assert!(t.borrow().data.borrow().iter().any(|&x| x != 0.));
+    }
+}
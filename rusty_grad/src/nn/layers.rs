@@ -1,46 +1,322 @@
 use crate::backend::{
-    ops::dot,
+    creation::{ones, sample, zeros},
+    float::Float,
+    ops::{add, constant, diff, dot, mean, mul, rsqrt},
     tensor::{RTensor, Tensor},
 };
-use crate::nn::components::Module;
+use crate::error::{invalid_argument, unwrap_or_panic, Result};
+use crate::nn::{components::Module, init::Init};
 use ndarray::prelude::*;
-use rand::{distributions::Uniform, prelude::Distribution};
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-pub struct Dense {
-    w: RTensor,
-    b: RTensor,
+/// Fully connected layer computing `x·W + b`, with the bias broadcasted over
+/// the batch dimension
+pub struct Dense<T: Float = f32> {
+    w: RTensor<T>,
+    b: Option<RTensor<T>>,
 }
 
-impl Dense {
+impl<T: Float> Dense<T> {
+    /// Creates a layer with the weights and bias sampled from `Uniform(-1, 1)`.
+    /// Use [`Dense::builder`] to select other initializations.
     pub fn new(n_in: usize, n_out: usize) -> Self {
-        let uniform = Uniform::new_inclusive(-1.0, 1.0);
-        let mut rng = rand::thread_rng();
-        Dense {
-            w: Tensor::new_ref(
-                &ArrayD::from_shape_vec(
-                    IxDyn(&[n_in, n_out]),
-                    (0..n_in * n_out)
-                        .map(|_| uniform.sample(&mut rng))
-                        .collect(),
-                )
-                .unwrap(),
-            ),
-            b: Tensor::new_ref(
-                &Array::from_shape_vec(
-                    IxDyn(&[n_out]),
-                    (0..n_out).map(|_| uniform.sample(&mut rng)).collect(),
-                )
-                .unwrap(),
-            ),
+        Self::builder(n_in, n_out).build()
+    }
+
+    /// Returns a builder of a layer with `n_in` inputs and `n_out` outputs
+    pub fn builder(n_in: usize, n_out: usize) -> DenseBuilder<T> {
+        let uniform = Init::Uniform {
+            low: -T::one(),
+            high: T::one(),
+        };
+        DenseBuilder {
+            n_in,
+            n_out,
+            weight_init: uniform,
+            bias_init: uniform,
+            bias: true,
+        }
+    }
+}
+
+/// Builder of a [`Dense`] layer
+#[derive(Debug, Clone, Copy)]
+pub struct DenseBuilder<T: Float = f32> {
+    n_in: usize,
+    n_out: usize,
+    weight_init: Init<T>,
+    bias_init: Init<T>,
+    bias: bool,
+}
+
+impl<T: Float> DenseBuilder<T> {
+    /// Sets the initialization of the weights, of shape `[n_in, n_out]`
+    pub fn weight_init(mut self, init: Init<T>) -> Self {
+        self.weight_init = init;
+        self
+    }
+
+    /// Sets the initialization of the bias, of shape `[n_out]`
+    pub fn bias_init(mut self, init: Init<T>) -> Self {
+        self.bias_init = init;
+        self
+    }
+
+    /// Sets whether the layer has a bias, it has one by default
+    pub fn bias(mut self, bias: bool) -> Self {
+        self.bias = bias;
+        self
+    }
+
+    /// Fallible version of [`DenseBuilder::build`], returns an error if an
+    /// initialization can't be sampled (e.g. a fan-based one for the bias)
+    pub fn try_build(self) -> Result<Dense<T>> {
+        let w = self
+            .weight_init
+            .try_sample(&[self.n_in, self.n_out], None)?;
+        let b = if self.bias {
+            Some(Tensor::new_ref(
+                &self.bias_init.try_sample(&[self.n_out], None)?,
+            ))
+        } else {
+            None
+        };
+        Ok(Dense {
+            w: Tensor::new_ref(&w),
+            b,
+        })
+    }
+
+    /// Creates the layer, sampling its parameters
+    pub fn build(self) -> Dense<T> {
+        unwrap_or_panic(self.try_build())
+    }
+}
+
+impl<T: Float> Module<T> for Dense<T> {
+    fn parameters(&self) -> Vec<RTensor<T>> {
+        let mut params = vec![self.w.clone()];
+        params.extend(self.b.clone());
+        params
+    }
+    fn forward(&self, x: &RTensor<T>) -> RTensor<T> {
+        let out = dot(x, &self.w);
+        match &self.b {
+            Some(b) => add(&out, b),
+            None => out,
+        }
+    }
+}
+
+/// Dropout layer. While training, it zeroes each element of the input with
+/// probability `p` and scales the rest by `1 / (1 - p)`, so that the expected
+/// value of each element doesn't change. The mask is sampled from the
+/// generator of the library. Outside of training it returns the input.
+pub struct Dropout {
+    p: f64,
+    training: AtomicBool,
+}
+
+impl Dropout {
+    /// Fallible version of [`Dropout::new`], returns an error if `p` is not in
+    /// `[0, 1)`
+    pub fn try_new(p: f64) -> Result<Self> {
+        if !(0. ..1.).contains(&p) {
+            return invalid_argument("Dropout", format!("the probability {} is not in [0, 1)", p));
+        }
+        Ok(Dropout {
+            p,
+            training: AtomicBool::new(true),
+        })
+    }
+
+    /// Creates a dropout layer that zeroes the elements with probability `p`
+    pub fn new(p: f64) -> Self {
+        unwrap_or_panic(Self::try_new(p))
+    }
+}
+
+impl<T: Float> Module<T> for Dropout {
+    fn parameters(&self) -> Vec<RTensor<T>> {
+        vec![]
+    }
+    fn forward(&self, x: &RTensor<T>) -> RTensor<T> {
+        if !self.training.load(Ordering::Relaxed) || self.p == 0. {
+            return x.clone();
+        }
+        let keep = 1. - self.p;
+        let scale = T::cast(1. / keep);
+        let shape = x.borrow().data.borrow().shape().to_vec();
+        let mask = sample(&shape, None, |rng| {
+            if rng.gen::<f64>() < keep {
+                scale
+            } else {
+                T::zero()
+            }
+        });
+        mul(x, &constant(mask))
+    }
+    fn set_training(&self, training: bool) {
+        self.training.store(training, Ordering::Relaxed);
+    }
+}
+
+/// Layer normalization over the last axis, with a learned scale (initialized
+/// to 1) and shift (initialized to 0) for each feature
+pub struct LayerNorm<T: Float = f32> {
+    gamma: RTensor<T>,
+    beta: RTensor<T>,
+    eps: T,
+}
+
+impl<T: Float> LayerNorm<T> {
+    /// Creates a layer that normalizes `n_features` features, adding `1e-5` to
+    /// the variance
+    pub fn new(n_features: usize) -> Self {
+        Self::with_eps(n_features, T::cast(1e-5))
+    }
+
+    /// Creates a layer that normalizes `n_features` features, adding `eps` to
+    /// the variance to avoid dividing by 0
+    pub fn with_eps(n_features: usize, eps: T) -> Self {
+        LayerNorm {
+            gamma: ones(&[n_features], true),
+            beta: zeros(&[n_features], true),
+            eps,
         }
     }
 }
 
-impl Module for Dense {
-    fn parameters(&self) -> Vec<RTensor> {
-        vec![self.w.clone(), self.b.clone()]
+impl<T: Float> Module<T> for LayerNorm<T> {
+    fn parameters(&self) -> Vec<RTensor<T>> {
+        vec![self.gamma.clone(), self.beta.clone()]
+    }
+    fn forward(&self, x: &RTensor<T>) -> RTensor<T> {
+        let axis = [x.borrow().data.borrow().ndim().saturating_sub(1)];
+        let centered = diff(x, &mean(x, Some(&axis), true));
+        let var = mean(&mul(&centered, &centered), Some(&axis), true);
+        let eps = constant(arr0(self.eps).into_dyn());
+        let normalized = mul(&centered, &rsqrt(&add(&var, &eps)));
+        add(&mul(&normalized, &self.gamma), &self.beta)
     }
-    fn forward(&self, x: &RTensor) -> RTensor {
-        dot(x, &self.w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::random::manual_seed;
+    use crate::gradcheck::{gradcheck_module, GradCheckOptions};
+    use crate::nn::init::FanMode;
+
+    #[test]
+    fn builder_inits_ok() {
+        let layer = Dense::builder(3, 2)
+            .weight_init(Init::KaimingNormal {
+                gain: 2f32.sqrt(),
+                mode: FanMode::FanIn,
+            })
+            .bias_init(Init::Zeros)
+            .build();
+        let params = layer.parameters();
+        assert_eq!(params[0].borrow().data.borrow().shape(), &[3, 2]);
+        assert!(params[1].borrow().data.borrow().iter().all(|&x| x == 0.));
+
+        let layer: Dense = Dense::new(3, 2);
+        let params = layer.parameters();
+        assert!(params[0]
+            .borrow()
+            .data
+            .borrow()
+            .iter()
+            .all(|x| x.abs() <= 1.));
+    }
+
+    #[test]
+    fn forward_applies_bias() {
+        let layer = Dense::builder(2, 3)
+            .weight_init(Init::Constant(1f32))
+            .bias_init(Init::Constant(0.5))
+            .build();
+        let x = Tensor::new_ref(&array![[1., 2.], [3., 4.]].into_dyn());
+        let out = layer.forward(&x);
+        assert_eq!(
+            out.borrow().data,
+            array![[3.5, 3.5, 3.5], [7.5, 7.5, 7.5]].into_dyn()
+        );
+
+        // The gradient of the bias is the sum of the upstream gradient over
+        // the batch
+        let upstream = array![[1., 2., 3.], [-4., 0.5, 1.]].into_dyn();
+        out.borrow_mut().backward_with(upstream.clone());
+        let b = &layer.parameters()[1];
+        assert_eq!(b.borrow().grad, upstream.sum_axis(Axis(0)));
+    }
+
+    #[test]
+    fn no_bias_ok() {
+        let layer = Dense::builder(2, 3)
+            .weight_init(Init::Constant(1f32))
+            .bias(false)
+            .build();
+        assert_eq!(layer.parameters().len(), 1);
+        let x = Tensor::new_ref(&array![[1., 2.]].into_dyn());
+        assert_eq!(
+            layer.forward(&x).borrow().data,
+            array![[3., 3., 3.]].into_dyn()
+        );
+    }
+
+    #[test]
+    fn builder_init_err() {
+        let res = Dense::<f32>::builder(3, 2)
+            .bias_init(Init::XavierUniform { gain: 1. })
+            .try_build();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn dropout_ok() {
+        manual_seed(0);
+        let dropout = Dropout::new(0.25);
+        let x = Tensor::new_ref(&Array::ones(IxDyn(&[100, 40])));
+        let out = Module::<f32>::forward(&dropout, &x);
+        let data = out.borrow().data.borrow().clone();
+        let zeros = data.iter().filter(|&&v| v == 0.).count();
+        assert!((900..1100).contains(&zeros), "{} zeros", zeros);
+        assert!(data
+            .iter()
+            .all(|&v| v == 0. || (v - 1. / 0.75).abs() < 1e-6));
+
+        // The gradient only flows through the kept elements
+        out.borrow_mut()
+            .backward_with(Array::ones(IxDyn(&[100, 40])));
+        assert_eq!(x.borrow().grad, data);
+
+        Module::<f32>::set_training(&dropout, false);
+        let out = Module::<f32>::forward(&dropout, &x);
+        assert_eq!(out.borrow().data, x.borrow().data);
+        assert!(Dropout::try_new(1.).is_err());
+    }
+
+    #[test]
+    fn layer_norm_ok() {
+        let layer = LayerNorm::<f64>::new(3);
+        let x = Tensor::new_ref(&array![[1., 2., 3.], [-2., 0., 8.]].into_dyn());
+        let out = layer.forward(&x);
+        for row in out.borrow().data.borrow().rows() {
+            assert!(row.mean().unwrap().abs() < 1e-9);
+            assert!((row.var(0.) - 1.).abs() < 1e-4);
+        }
+
+        // Perturb the affine parameters so that they are checked too
+        *layer.parameters()[0].borrow().data.borrow_mut() += 0.5;
+        *layer.parameters()[1].borrow().data.borrow_mut() -= 0.25;
+        let options = GradCheckOptions {
+            eps: 1e-6,
+            atol: 1e-6,
+            rtol: 1e-5,
+        };
+        gradcheck_module(&layer, &x, options).unwrap();
     }
 }
@@ -1,8 +1,9 @@
 use crate::backend::{
+    float::Float,
     ops::{diff, pow},
     tensor::RTensor,
 };
 
-pub fn squared_error(y_true: &RTensor, y_pred: &RTensor) -> RTensor {
-    pow(&diff(y_true, y_pred), 2.0)
+pub fn squared_error<T: Float>(y_true: &RTensor<T>, y_pred: &RTensor<T>) -> RTensor<T> {
+    pow(&diff(y_true, y_pred), T::cast(2.0))
 }
@@ -1,5 +1,8 @@
 pub mod activations;
 pub mod components;
+pub mod init;
 pub mod layers;
 pub mod losses;
 pub mod models;
+
+pub use models::Sequential;
@@ -1,34 +1,430 @@
-use crate::backend::tensor::RTensor;
-use crate::nn::{components::Module, layers::Dense};
+use crate::backend::{float::Float, tensor::RTensor};
+use crate::error::{invalid_argument, unwrap_or_panic, Result};
+use crate::nn::{
+    activations::Activation,
+    components::Module,
+    init::Init,
+    layers::{Dense, Dropout, LayerNorm},
+};
+use std::ops::Index;
 
-pub struct MLP {
-    layers: Vec<Dense>,
+/// Multilayer perceptron. Each hidden layer is a `Dense` layer followed by the
+/// optional layer norm, the hidden activation and the optional dropout, in
+/// this order. The output layer is a `Dense` layer followed by the optional
+/// output activation.
+pub struct MLP<T: Float = f32> {
+    layers: Vec<Dense<T>>,
+    norms: Vec<Option<LayerNorm<T>>>,
+    dropouts: Vec<Option<Dropout>>,
+    hidden_activation: Option<Activation>,
+    output_activation: Option<Activation>,
 }
 
-impl MLP {
+impl<T: Float> MLP<T> {
+    /// Creates a stack of `Dense` layers with `n_units[i]` outputs each,
+    /// without activations. Use [`MLP::builder`] to add them.
     pub fn new(n_in: usize, n_units: Vec<usize>) -> Self {
-        let mut layers: Vec<Dense> = vec![];
-        let mut aux_in = n_in;
-        for aux_out in n_units.iter() {
-            layers.push(Dense::new(aux_in, *aux_out));
-            aux_in = *aux_out;
+        Self::builder(n_in, n_units).build()
+    }
+
+    /// Returns a builder of a model with `n_in` inputs and a layer with
+    /// `n_units[i]` outputs for each element of `n_units`
+    pub fn builder(n_in: usize, n_units: Vec<usize>) -> MLPBuilder<T> {
+        MLPBuilder {
+            n_in,
+            n_units,
+            hidden_activation: None,
+            output_activation: None,
+            dropouts: None,
+            layer_norm: false,
+            weight_init: None,
+            bias_init: None,
+        }
+    }
+
+    fn n_hidden(&self) -> usize {
+        self.layers.len().saturating_sub(1)
+    }
+}
+
+/// Builder of an [`MLP`]
+#[derive(Debug, Clone)]
+pub struct MLPBuilder<T: Float = f32> {
+    n_in: usize,
+    n_units: Vec<usize>,
+    hidden_activation: Option<Activation>,
+    output_activation: Option<Activation>,
+    dropouts: Option<Vec<f64>>,
+    layer_norm: bool,
+    weight_init: Option<Init<T>>,
+    bias_init: Option<Init<T>>,
+}
+
+impl<T: Float> MLPBuilder<T> {
+    /// Sets the activation applied after each hidden layer
+    pub fn hidden_activation(mut self, activation: Activation) -> Self {
+        self.hidden_activation = Some(activation);
+        self
+    }
+
+    /// Sets the activation applied after the output layer
+    pub fn output_activation(mut self, activation: Activation) -> Self {
+        self.output_activation = Some(activation);
+        self
+    }
+
+    /// Applies dropout with probability `p` after every hidden layer
+    pub fn dropout(mut self, p: f64) -> Self {
+        self.dropouts = Some(vec![p; self.n_units.len().saturating_sub(1)]);
+        self
+    }
+
+    /// Applies dropout with probability `ps[i]` after the hidden layer `i`,
+    /// there must be one probability for each hidden layer (0 disables it)
+    pub fn dropouts(mut self, ps: Vec<f64>) -> Self {
+        self.dropouts = Some(ps);
+        self
+    }
+
+    /// Sets whether the output of each hidden layer is normalized with
+    /// [`LayerNorm`]
+    pub fn layer_norm(mut self, layer_norm: bool) -> Self {
+        self.layer_norm = layer_norm;
+        self
+    }
+
+    /// Sets the initialization of the weights of all the layers
+    pub fn weight_init(mut self, init: Init<T>) -> Self {
+        self.weight_init = Some(init);
+        self
+    }
+
+    /// Sets the initialization of the biases of all the layers
+    pub fn bias_init(mut self, init: Init<T>) -> Self {
+        self.bias_init = Some(init);
+        self
+    }
+
+    /// Fallible version of [`MLPBuilder::build`], returns an error if the
+    /// number of dropout probabilities doesn't match the number of hidden
+    /// layers, a probability is not in `[0, 1)` or an initialization can't be
+    /// sampled
+    pub fn try_build(self) -> Result<MLP<T>> {
+        let n_hidden = self.n_units.len().saturating_sub(1);
+        let dropouts = self.dropouts.unwrap_or_else(|| vec![0.; n_hidden]);
+        if dropouts.len() != n_hidden {
+            return invalid_argument(
+                "MLP",
+                format!(
+                    "got {} dropout probabilities for {} hidden layers",
+                    dropouts.len(),
+                    n_hidden
+                ),
+            );
+        }
+        let mut layers = vec![];
+        let mut n_in = self.n_in;
+        for &n_out in self.n_units.iter() {
+            let mut builder = Dense::builder(n_in, n_out);
+            if let Some(init) = self.weight_init {
+                builder = builder.weight_init(init);
+            }
+            if let Some(init) = self.bias_init {
+                builder = builder.bias_init(init);
+            }
+            layers.push(builder.try_build()?);
+            n_in = n_out;
         }
-        MLP { layers }
+        let norms = self.n_units[..n_hidden]
+            .iter()
+            .map(|&n| self.layer_norm.then(|| LayerNorm::new(n)))
+            .collect();
+        let dropouts = dropouts
+            .into_iter()
+            .map(|p| (p != 0.).then(|| Dropout::try_new(p)).transpose())
+            .collect::<Result<_>>()?;
+        Ok(MLP {
+            layers,
+            norms,
+            dropouts,
+            hidden_activation: self.hidden_activation,
+            output_activation: self.output_activation,
+        })
+    }
+
+    /// Creates the model, sampling its parameters
+    pub fn build(self) -> MLP<T> {
+        unwrap_or_panic(self.try_build())
     }
 }
 
-impl Module for MLP {
-    fn parameters(&self) -> Vec<RTensor> {
+impl<T: Float> Module<T> for MLP<T> {
+    fn parameters(&self) -> Vec<RTensor<T>> {
+        let norms = self.norms.iter().flatten().map(|n| n.parameters());
         self.layers
             .iter()
             .map(|l| l.parameters())
-            .collect::<Vec<Vec<RTensor>>>() // Vector of parameters' vectors
+            .chain(norms)
+            .collect::<Vec<Vec<RTensor<T>>>>() // Vector of parameters' vectors
             .concat()
     }
 
-    fn forward(&self, x: &RTensor) -> RTensor {
-        self.layers
+    fn forward(&self, x: &RTensor<T>) -> RTensor<T> {
+        let mut out = x.clone();
+        for (i, layer) in self.layers.iter().enumerate() {
+            out = layer.forward(&out);
+            let activation = if i < self.n_hidden() {
+                if let Some(norm) = &self.norms[i] {
+                    out = norm.forward(&out);
+                }
+                self.hidden_activation
+            } else {
+                self.output_activation
+            };
+            if let Some(activation) = activation {
+                out = activation.apply(out);
+            }
+            if let Some(Some(dropout)) = self.dropouts.get(i) {
+                out = dropout.forward(&out);
+            }
+        }
+        out
+    }
+
+    fn set_training(&self, training: bool) {
+        for dropout in self.dropouts.iter().flatten() {
+            Module::<T>::set_training(dropout, training);
+        }
+    }
+}
+
+/// Container that applies its modules one after the other, so that any
+/// architecture can be assembled without writing a new struct:
+///
+/// ```
+/// use rusty_grad::backend::creation::randn;
+/// use rusty_grad::nn::{activations::Activation, components::Module, layers::Dense, Sequential};
+///
+/// let model: Sequential = Sequential::new()
+///     .with(Dense::new(3, 8))
+///     .with(Activation::Relu)
+///     .with(Dense::new(8, 1));
+/// assert_eq!(model.parameters().len(), 4);
+/// let out = model.forward(&randn(&[5, 3], None, false));
+/// assert_eq!(out.borrow().data.borrow().shape(), &[5, 1]);
+/// ```
+#[derive(Default)]
+pub struct Sequential<T: Float = f32> {
+    modules: Vec<Box<dyn Module<T>>>,
+}
+
+impl<T: Float> Sequential<T> {
+    /// Creates an empty container, that returns its input
+    pub fn new() -> Self {
+        Sequential { modules: vec![] }
+    }
+
+    /// Appends `module` to the container
+    pub fn push(&mut self, module: impl Module<T> + 'static) {
+        self.modules.push(Box::new(module));
+    }
+
+    /// Returns the container with `module` appended
+    pub fn with(mut self, module: impl Module<T> + 'static) -> Self {
+        self.push(module);
+        self
+    }
+
+    /// Returns the number of modules
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    /// Returns true if there are no modules
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    /// Returns an iterator over the modules, in the order they are applied
+    pub fn iter(&self) -> std::slice::Iter<'_, Box<dyn Module<T>>> {
+        self.modules.iter()
+    }
+}
+
+impl<T: Float> From<Vec<Box<dyn Module<T>>>> for Sequential<T> {
+    fn from(modules: Vec<Box<dyn Module<T>>>) -> Self {
+        Sequential { modules }
+    }
+}
+
+impl<T: Float> Index<usize> for Sequential<T> {
+    type Output = dyn Module<T>;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        self.modules[index].as_ref()
+    }
+}
+
+impl<'a, T: Float> IntoIterator for &'a Sequential<T> {
+    type Item = &'a Box<dyn Module<T>>;
+    type IntoIter = std::slice::Iter<'a, Box<dyn Module<T>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: Float> Module<T> for Sequential<T> {
+    fn parameters(&self) -> Vec<RTensor<T>> {
+        self.modules
+            .iter()
+            .map(|m| m.parameters())
+            .collect::<Vec<Vec<RTensor<T>>>>()
+            .concat()
+    }
+
+    fn forward(&self, x: &RTensor<T>) -> RTensor<T> {
+        self.modules
             .iter()
-            .fold(x.clone(), |input, l| l.forward(&input))
+            .fold(x.clone(), |input, m| m.forward(&input))
+    }
+
+    fn set_training(&self, training: bool) {
+        for module in self.modules.iter() {
+            module.set_training(training);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::creation::randn;
+    use crate::backend::random::manual_seed;
+    use crate::backend::tensor::Tensor;
+    use crate::error::RustyGradError;
+    use crate::gradcheck::{gradcheck_module, GradCheckOptions};
+    use ndarray::prelude::*;
+
+    #[test]
+    fn new_is_linear() {
+        let model: MLP = MLP::new(3, vec![4, 2]);
+        assert_eq!(model.parameters().len(), 4);
+        // Without activations the model is affine: f(2x) - f(x) = f(x) - f(0)
+        let x = randn::<f32>(&[5, 3], None, false);
+        let f = |x: ArrayD<f32>| {
+            model
+                .forward(&Tensor::new_ref(&x))
+                .borrow()
+                .data
+                .borrow()
+                .clone()
+        };
+        let data = x.borrow().data.borrow().clone();
+        let lhs = f(&data * 2.) - f(data.clone());
+        let rhs = f(data.clone()) - f(data.mapv(|_| 0.));
+        assert!((lhs - rhs).iter().all(|d| d.abs() < 1e-4));
+    }
+
+    #[test]
+    fn builder_ok() {
+        let model: MLP<f64> = MLP::builder(3, vec![8, 8, 1])
+            .hidden_activation(Activation::Gelu)
+            .output_activation(Activation::Sigmoid)
+            .layer_norm(true)
+            .weight_init(Init::XavierUniform { gain: 1. })
+            .bias_init(Init::Zeros)
+            .build();
+        // 3 dense layers and 2 layer norms
+        assert_eq!(model.parameters().len(), 3 * 2 + 2 * 2);
+        let x = randn(&[4, 3], None, false);
+        let out = model.forward(&x);
+        assert_eq!(out.borrow().data.borrow().shape(), &[4, 1]);
+        assert!(out.borrow().data.borrow().iter().all(|&y| 0. < y && y < 1.));
+
+        let options = GradCheckOptions {
+            eps: 1e-6,
+            atol: 1e-6,
+            rtol: 1e-5,
+        };
+        gradcheck_module(&model, &x, options).unwrap();
+    }
+
+    #[test]
+    fn dropout_only_while_training() {
+        manual_seed(0);
+        let model: MLP = MLP::builder(3, vec![16, 16, 1])
+            .hidden_activation(Activation::Relu)
+            .dropouts(vec![0.5, 0.])
+            .build();
+        let x = randn(&[8, 3], None, false);
+        let train1 = model.forward(&x).borrow().data.borrow().clone();
+        let train2 = model.forward(&x).borrow().data.borrow().clone();
+        assert_ne!(train1, train2);
+
+        model.set_training(false);
+        let eval1 = model.forward(&x).borrow().data.borrow().clone();
+        assert_eq!(model.forward(&x).borrow().data, eval1);
+    }
+
+    #[test]
+    fn builder_err() {
+        let res = MLP::<f32>::builder(3, vec![4, 4, 1])
+            .dropouts(vec![0.1])
+            .try_build();
+        assert!(res.is_err());
+        let res = MLP::<f32>::builder(3, vec![4, 1]).dropout(1.5).try_build();
+        assert!(res.is_err());
+        // Only a probability of exactly 0 disables the dropout
+        for p in [-0.5, f64::NAN] {
+            let res = MLP::<f32>::builder(3, vec![4, 1]).dropout(p).try_build();
+            assert!(matches!(
+                res,
+                Err(RustyGradError::InvalidArgument { op: "Dropout", .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn sequential_ok() {
+        let mut model: Sequential<f64> = Sequential::new()
+            .with(Dense::new(3, 4))
+            .with(Activation::Tanh)
+            .with(Dropout::new(0.5));
+        model.push(Dense::builder(4, 2).bias(false).build());
+        assert_eq!(model.len(), 4);
+        assert_eq!(model.parameters().len(), 3);
+        assert_eq!(model[1].parameters().len(), 0);
+        let n_params: Vec<usize> = model.iter().map(|m| m.parameters().len()).collect();
+        assert_eq!(n_params, vec![2, 0, 0, 1]);
+
+        // Same as applying the modules one by one
+        model.set_training(false);
+        let x = randn(&[5, 3], None, false);
+        let manual = (&model)
+            .into_iter()
+            .fold(x.clone(), |input, m| m.forward(&input));
+        assert_eq!(model.forward(&x).borrow().data, manual.borrow().data);
+
+        let options = GradCheckOptions {
+            eps: 1e-6,
+            atol: 1e-6,
+            rtol: 1e-5,
+        };
+        gradcheck_module(&model, &x, options).unwrap();
+    }
+
+    #[test]
+    fn sequential_nested_ok() {
+        let inner: Vec<Box<dyn Module>> =
+            vec![Box::new(MLP::new(2, vec![3])), Box::new(Activation::Relu)];
+        let model: Sequential = Sequential::new()
+            .with(Sequential::from(inner))
+            .with(LayerNorm::new(3));
+        assert_eq!(model.len(), 2);
+        assert_eq!(model.parameters().len(), 4);
+        let out = model.forward(&randn(&[4, 2], None, false));
+        assert_eq!(out.borrow().data.borrow().shape(), &[4, 3]);
+        assert!(Sequential::<f32>::default().is_empty());
     }
 }